@@ -0,0 +1,715 @@
+use std::collections::HashMap;
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes128;
+
+use crate::drm::{format_uuid, parse_uuid};
+use crate::isobmff::{self, FourCC, Mp4Node, TrackInit};
+use crate::layout;
+
+const PIFF_SENC_UUID: [u8; 16] = [
+    0xa2, 0x39, 0x4f, 0x52, 0x5a, 0x9b, 0x4f, 0x14, 0xa2, 0x44, 0x6c, 0x42, 0x7c, 0x64, 0x8d, 0xf4,
+];
+
+pub type KeyPair = ([u8; 16], [u8; 16]);
+pub type KeyMap = HashMap<[u8; 16], [u8; 16]>;
+
+#[derive(Clone, Debug, Default)]
+pub struct TrackEncryption {
+    pub track_id: u32,
+    pub scheme_type: FourCC,
+    pub original_format: FourCC,
+    pub default_is_protected: bool,
+    pub default_per_sample_iv_size: u8,
+    pub default_kid: [u8; 16],
+    pub default_constant_iv: Option<Vec<u8>>,
+    pub crypt_byte_block: u8,
+    pub skip_byte_block: u8,
+}
+
+fn parse_hex_key(text: &str) -> Option<[u8; 16]> {
+    parse_uuid(text.trim())
+}
+
+/// Parses a `KID:key` pair, both given as 32 hex digits; the KID may use UUID dashes.
+pub fn parse_key(text: &str) -> Option<KeyPair> {
+    let (kid, key) = text.trim().split_once(':')?;
+    Some((parse_hex_key(kid)?, parse_hex_key(key)?))
+}
+
+/// Reads `KID:key` pairs, one per line. Empty lines and lines starting with `#` are ignored.
+pub fn read_key_file(path: &str) -> Result<Vec<KeyPair>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{} : {}", path, e))?;
+    let mut keys = Vec::new();
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_key(line) {
+            Some(key) => keys.push(key),
+            None => return Err(format!("{} : invalid key on line {}", path, line_idx + 1)),
+        }
+    }
+    Ok(keys)
+}
+
+fn parse_tenc(payload: &[u8], encryption: &mut TrackEncryption) -> Option<()> {
+    let version = *payload.first()?;
+    if version > 0 {
+        let pattern = *payload.get(5)?;
+        encryption.crypt_byte_block = pattern >> 4;
+        encryption.skip_byte_block = pattern & 0xf;
+    }
+    encryption.default_is_protected = *payload.get(6)? != 0;
+    encryption.default_per_sample_iv_size = *payload.get(7)?;
+    encryption.default_kid = payload.get(8..24)?.try_into().ok()?;
+    if encryption.default_is_protected && encryption.default_per_sample_iv_size == 0 {
+        let constant_iv_size = *payload.get(24)? as usize;
+        encryption.default_constant_iv = Some(payload.get(25..25 + constant_iv_size)?.to_vec());
+    }
+    Some(())
+}
+
+fn parse_sinf(sinf: &Mp4Node) -> Option<TrackEncryption> {
+    let mut encryption = TrackEncryption {
+        original_format: sinf.child(b"frma")?.data.get(..4)?.try_into().ok()?,
+        ..Default::default()
+    };
+    if let Some(schm) = sinf.child(b"schm") {
+        encryption.scheme_type = schm.data.get(4..8)?.try_into().ok()?;
+    }
+    let tenc = sinf.child(b"schi")?.child(b"tenc")?;
+    parse_tenc(&tenc.data, &mut encryption)?;
+    Some(encryption)
+}
+
+fn tkhd_track_id(trak: &Mp4Node) -> Option<u32> {
+    let tkhd = trak.child(b"tkhd")?;
+    match tkhd.data.first()? {
+        1 => isobmff::read_u32(&tkhd.data, 20),
+        _ => isobmff::read_u32(&tkhd.data, 12),
+    }
+}
+
+/// Rewrites protected sample entries of a track back to their original format.
+fn clear_trak(trak: &mut Mp4Node, tracks: &mut Vec<TrackEncryption>) {
+    let track_id = tkhd_track_id(trak).unwrap_or_default();
+    let stsd = trak
+        .child_mut(b"mdia")
+        .and_then(|mdia| mdia.child_mut(b"minf"))
+        .and_then(|minf| minf.child_mut(b"stbl"))
+        .and_then(|stbl| stbl.child_mut(b"stsd"));
+    let Some(stsd) = stsd else {
+        return;
+    };
+    for entry in stsd.children.iter_mut() {
+        if &entry.box_type != b"encv" && &entry.box_type != b"enca" {
+            continue;
+        }
+        match entry.child(b"sinf").and_then(parse_sinf) {
+            Some(mut encryption) => {
+                encryption.track_id = track_id;
+                entry.box_type = encryption.original_format;
+                entry.children.retain(|child| &child.box_type != b"sinf");
+                tracks.push(encryption);
+            }
+            None => eprintln!("Could not parse protection scheme of track {}", track_id),
+        }
+    }
+}
+
+/// Returns the clear init segment together with the encryption parameters of its tracks.
+pub fn decrypt_init(data: &[u8]) -> Result<(Vec<u8>, Vec<TrackEncryption>), String> {
+    let mut tree = isobmff::parse_tree(data);
+    let mut tracks = Vec::new();
+    let moov = tree
+        .iter_mut()
+        .find(|node| &node.box_type == b"moov")
+        .ok_or("moov box not found")?;
+    moov.children.retain(|child| &child.box_type != b"pssh");
+    for trak in moov.children.iter_mut() {
+        if &trak.box_type == b"trak" {
+            clear_trak(trak, &mut tracks);
+        }
+    }
+    Ok((isobmff::write_tree(&tree), tracks))
+}
+
+struct SampleEncryption {
+    iv: Vec<u8>,
+    subsamples: Vec<(usize, usize)>,
+}
+
+fn parse_senc(payload: &[u8], iv_size: usize) -> Option<Vec<SampleEncryption>> {
+    let flags = isobmff::read_u32(payload, 0)? & 0xffffff;
+    let sample_count = isobmff::read_u32(payload, 4)?;
+    let mut pos = 8;
+    let mut samples = Vec::new();
+    for _ in 0..sample_count {
+        let iv = payload.get(pos..pos + iv_size)?.to_vec();
+        pos += iv_size;
+        let mut subsamples = Vec::new();
+        if flags & 0x2 != 0 {
+            let subsample_count = isobmff::read_u16(payload, pos)?;
+            pos += 2;
+            for _ in 0..subsample_count {
+                let clear = isobmff::read_u16(payload, pos)? as usize;
+                let protected = isobmff::read_u32(payload, pos + 2)? as usize;
+                subsamples.push((clear, protected));
+                pos += 6;
+            }
+        }
+        samples.push(SampleEncryption { iv, subsamples });
+    }
+    Some(samples)
+}
+
+struct Pattern {
+    crypt: usize,
+    skip: usize,
+}
+
+impl Pattern {
+    /// Whether the block at `block_idx` of a protected range is encrypted.
+    fn is_encrypted(&self, block_idx: usize) -> bool {
+        match self.crypt + self.skip {
+            0 => true,
+            period => block_idx % period < self.crypt,
+        }
+    }
+}
+
+struct CtrState {
+    counter: [u8; 16],
+    keystream: [u8; 16],
+    used: usize,
+}
+
+impl CtrState {
+    fn new(iv: &[u8]) -> CtrState {
+        let mut counter = [0u8; 16];
+        counter[..iv.len().min(16)].copy_from_slice(&iv[..iv.len().min(16)]);
+        CtrState {
+            counter,
+            keystream: [0; 16],
+            used: 16,
+        }
+    }
+
+    fn apply(&mut self, cipher: &Aes128, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            if self.used == 16 {
+                let mut block = GenericArray::from(self.counter);
+                cipher.encrypt_block(&mut block);
+                self.keystream.copy_from_slice(&block);
+                self.counter = u128::from_be_bytes(self.counter)
+                    .wrapping_add(1)
+                    .to_be_bytes();
+                self.used = 0;
+            }
+            *byte ^= self.keystream[self.used];
+            self.used += 1;
+        }
+    }
+}
+
+fn cbc_decrypt_block(cipher: &Aes128, chain: &mut [u8; 16], block: &mut [u8]) {
+    let encrypted: [u8; 16] = block.try_into().unwrap_or_default();
+    let mut decrypted = GenericArray::from(encrypted);
+    cipher.decrypt_block(&mut decrypted);
+    for (idx, byte) in block.iter_mut().enumerate() {
+        *byte = decrypted[idx] ^ chain[idx];
+    }
+    *chain = encrypted;
+}
+
+fn decrypt_sample(
+    cipher: &Aes128,
+    encryption: &TrackEncryption,
+    iv: &[u8],
+    subsamples: &[(usize, usize)],
+    sample: &mut [u8],
+) -> Result<(), String> {
+    let mut ranges = Vec::new();
+    match subsamples.is_empty() {
+        true => ranges.push((0, sample.len())),
+        false => {
+            let mut pos = 0;
+            for (clear, protected) in subsamples {
+                pos += clear;
+                ranges.push((pos, *protected));
+                pos += protected;
+            }
+            if pos > sample.len() {
+                return Err(format!(
+                    "subsamples cover {} bytes, sample has {}",
+                    pos,
+                    sample.len()
+                ));
+            }
+        }
+    }
+    let pattern = Pattern {
+        crypt: encryption.crypt_byte_block as usize,
+        skip: encryption.skip_byte_block as usize,
+    };
+    let mut ctr = CtrState::new(iv);
+    let mut chain: [u8; 16] = [0; 16];
+    chain[..iv.len().min(16)].copy_from_slice(&iv[..iv.len().min(16)]);
+    for (start, length) in ranges {
+        let range = &mut sample[start..start + length];
+        match &encryption.scheme_type {
+            b"cenc" => ctr.apply(cipher, range),
+            b"cens" => {
+                for (block_idx, block) in range.chunks_mut(16).enumerate() {
+                    if block.len() == 16 && pattern.is_encrypted(block_idx) {
+                        ctr.apply(cipher, block);
+                    }
+                }
+            }
+            b"cbc1" | b"cbcs" => {
+                if &encryption.scheme_type == b"cbcs" {
+                    chain = [0; 16];
+                    chain[..iv.len().min(16)].copy_from_slice(&iv[..iv.len().min(16)]);
+                }
+                for (block_idx, block) in range.chunks_mut(16).enumerate() {
+                    if block.len() == 16 && pattern.is_encrypted(block_idx) {
+                        cbc_decrypt_block(cipher, &mut chain, block);
+                    }
+                }
+            }
+            scheme => {
+                return Err(format!(
+                    "protection scheme {} not supported",
+                    String::from_utf8_lossy(scheme)
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn find_senc(data: &[u8], traf: &isobmff::BoxHeader) -> Option<(usize, usize)> {
+    for child in isobmff::children(data, traf) {
+        if &child.box_type == b"senc" {
+            return Some((child.payload_offset(), child.end()));
+        }
+        if &child.box_type == b"uuid" && child.payload(data).get(..16) == Some(&PIFF_SENC_UUID) {
+            return Some((child.payload_offset() + 16, child.end()));
+        }
+    }
+    None
+}
+
+fn is_sample_group_of_seig(data: &[u8], header: &isobmff::BoxHeader) -> bool {
+    header.payload(data).get(4..8) == Some(b"seig")
+}
+
+fn decrypt_traf(
+    data: &mut [u8],
+    moof: &isobmff::BoxHeader,
+    traf: &isobmff::BoxHeader,
+    tracks: &[TrackEncryption],
+    defaults: &[TrackInit],
+    keys: &KeyMap,
+) -> Result<usize, String> {
+    let fragment = isobmff::parse_traf(data, moof, traf, defaults).ok_or("tfhd box not found")?;
+    let Some(encryption) = tracks
+        .iter()
+        .find(|track| track.track_id == fragment.track_id)
+    else {
+        return Ok(0);
+    };
+    if !encryption.default_is_protected {
+        return Ok(0);
+    }
+    let key = keys.get(&encryption.default_kid).ok_or(format!(
+        "no key for KID {}",
+        format_uuid(&encryption.default_kid)
+    ))?;
+    let cipher = Aes128::new(&GenericArray::from(*key));
+    let (senc_start, senc_end) = find_senc(data, traf).ok_or("senc box not found")?;
+    let iv_size = encryption.default_per_sample_iv_size as usize;
+    let sample_encryptions =
+        parse_senc(&data[senc_start..senc_end], iv_size).ok_or("Could not parse senc box")?;
+
+    let mut sample_idx = 0;
+    for run in &fragment.runs {
+        let mut sample_offset = run.data_offset;
+        for sample in &run.samples {
+            let size = sample.size as usize;
+            let sample_encryption = sample_encryptions
+                .get(sample_idx)
+                .ok_or("senc has fewer entries than samples")?;
+            let iv = match iv_size {
+                0 => encryption.default_constant_iv.clone().unwrap_or_default(),
+                _ => sample_encryption.iv.clone(),
+            };
+            let sample_data = data
+                .get_mut(sample_offset..sample_offset + size)
+                .ok_or("sample data out of range")?;
+            decrypt_sample(
+                &cipher,
+                encryption,
+                &iv,
+                &sample_encryption.subsamples,
+                sample_data,
+            )?;
+            sample_offset += size;
+            sample_idx += 1;
+        }
+    }
+
+    for child in isobmff::children(data, traf) {
+        let remove = match &child.box_type {
+            b"senc" | b"saiz" | b"saio" => true,
+            b"uuid" => child.payload(data).get(..16) == Some(&PIFF_SENC_UUID),
+            b"sbgp" | b"sgpd" => is_sample_group_of_seig(data, &child),
+            _ => false,
+        };
+        if remove {
+            isobmff::replace_with_free(data, &child);
+        }
+    }
+    Ok(sample_idx)
+}
+
+/// Decrypts the samples of a media segment in place. Encryption boxes are turned
+/// into `free` boxes so that no data offset changes. Returns the number of samples.
+pub fn decrypt_segment(
+    data: &mut [u8],
+    tracks: &[TrackEncryption],
+    defaults: &[TrackInit],
+    keys: &KeyMap,
+) -> Result<usize, String> {
+    let mut samples = 0;
+    for moof in isobmff::top_level_boxes(data) {
+        if &moof.box_type != b"moof" {
+            continue;
+        }
+        for child in isobmff::children(data, &moof) {
+            match &child.box_type {
+                b"traf" => samples += decrypt_traf(data, &moof, &child, tracks, defaults, keys)?,
+                b"pssh" => isobmff::replace_with_free(data, &child),
+                _ => {}
+            }
+        }
+    }
+    Ok(samples)
+}
+
+/// Removes `ContentProtection` elements so that players do not request licenses
+/// for the decrypted mirror.
+pub fn strip_content_protection(manifest_text: &str) -> String {
+    let mut ret = String::with_capacity(manifest_text.len());
+    let mut rest = manifest_text;
+    while let Some(start) = rest.find("<ContentProtection") {
+        ret.push_str(rest[..start].trim_end_matches([' ', '\t']));
+        let element = &rest[start..];
+        let tag_end = element
+            .find('>')
+            .map(|pos| pos + 1)
+            .unwrap_or(element.len());
+        let end = match element[..tag_end].ends_with("/>") {
+            true => tag_end,
+            false => element
+                .find("</ContentProtection>")
+                .map(|pos| pos + "</ContentProtection>".len())
+                .unwrap_or(element.len()),
+        };
+        rest = &element[end..];
+        if let Some(stripped) = rest.strip_prefix('\n') {
+            rest = stripped;
+        } else if let Some(stripped) = rest.strip_prefix("\r\n") {
+            rest = stripped;
+        }
+    }
+    ret.push_str(rest);
+    ret
+}
+
+fn write_file(path: &std::path::Path, data: &[u8]) -> Result<(), String> {
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)
+            .map_err(|e| format!("{} : {}", directory.display(), e))?;
+    }
+    std::fs::write(path, data).map_err(|e| format!("{} : {}", path.display(), e))
+}
+
+fn decrypt_file(
+    input_path: &std::path::Path,
+    output_path: &std::path::Path,
+    tracks: &[TrackEncryption],
+    defaults: &[TrackInit],
+    keys: &KeyMap,
+) -> Result<usize, String> {
+    let mut data =
+        std::fs::read(input_path).map_err(|e| format!("{} : {}", input_path.display(), e))?;
+    let samples = decrypt_segment(&mut data, tracks, defaults, keys)?;
+    write_file(output_path, &data)?;
+    Ok(samples)
+}
+
+/// Writes a clear copy of the mirror in `input_directory` to `output_directory`.
+/// Returns the number of files that could not be decrypted.
+pub fn decrypt_mirror(input_directory: &str, output_directory: &str, keys: &KeyMap) -> usize {
+    let Some((manifest_text, url_info)) = layout::read_mirror(input_directory) else {
+        return 1;
+    };
+    let mut failures = 0;
+    let manifest_path = layout::manifest_path(output_directory);
+    if let Err(e) = write_file(
+        &manifest_path,
+        strip_content_protection(&manifest_text).as_bytes(),
+    ) {
+        eprintln!("Could not write manifest {}", e);
+        failures += 1;
+    }
+    for representation in &url_info.representations {
+        let Some(initialization) = &representation.initialization else {
+            eprintln!(
+                "rep {} has no initialization segment, skip",
+                representation.id
+            );
+            continue;
+        };
+        let (Some(init_in), Some(init_out)) = (
+            layout::local_path(input_directory, &url_info.base_url, initialization),
+            layout::local_path(output_directory, &url_info.base_url, initialization),
+        ) else {
+            eprintln!(
+                "rep {} init {} is not below base url",
+                representation.id, initialization
+            );
+            failures += 1;
+            continue;
+        };
+        let init = match std::fs::read(&init_in) {
+            Ok(init) => init,
+            Err(e) => {
+                eprintln!("Could not read init {} error {}", init_in.display(), e);
+                failures += 1;
+                continue;
+            }
+        };
+        let defaults = isobmff::parse_init_tracks(&init);
+        let tracks = match decrypt_init(&init) {
+            Ok((clear_init, tracks)) => {
+                if let Err(e) = write_file(&init_out, &clear_init) {
+                    eprintln!("Could not write init {}", e);
+                    failures += 1;
+                }
+                tracks
+            }
+            Err(e) => {
+                eprintln!("Could not decrypt init {} error {}", init_in.display(), e);
+                failures += 1;
+                continue;
+            }
+        };
+        for track in &tracks {
+            println!(
+                "rep {} track {} scheme {} KID {}",
+                representation.id,
+                track.track_id,
+                String::from_utf8_lossy(&track.scheme_type),
+                format_uuid(&track.default_kid)
+            );
+            if !keys.contains_key(&track.default_kid) {
+                eprintln!(
+                    "rep {} : no key supplied for KID {}",
+                    representation.id,
+                    format_uuid(&track.default_kid)
+                );
+            }
+        }
+        for segment in &representation.segments {
+            let (Some(segment_in), Some(segment_out)) = (
+                layout::local_path(input_directory, &url_info.base_url, &segment.url),
+                layout::local_path(output_directory, &url_info.base_url, &segment.url),
+            ) else {
+                failures += 1;
+                continue;
+            };
+            match decrypt_file(&segment_in, &segment_out, &tracks, &defaults, keys) {
+                Ok(samples) => println!("decrypted {} samples {}", segment_in.display(), samples),
+                Err(e) => {
+                    eprintln!("Could not decrypt {} error {}", segment_in.display(), e);
+                    failures += 1;
+                }
+            }
+        }
+    }
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::cipher::generic_array::GenericArray;
+    use aes::cipher::{BlockEncrypt, KeyInit};
+    use aes::Aes128;
+
+    use super::{
+        decrypt_init, decrypt_segment, parse_key, strip_content_protection, CtrState, KeyMap,
+    };
+    use crate::isobmff::tests::{full_box, make_box, media_segment_with};
+    use crate::isobmff::{self, TrackInit};
+
+    const KID: [u8; 16] = [1; 16];
+    const KEY: [u8; 16] = [2; 16];
+
+    fn encrypted_init(scheme: &[u8; 4], pattern: u8, iv_size: u8, constant_iv: &[u8]) -> Vec<u8> {
+        let mut tenc_payload = vec![0, pattern, 1, iv_size];
+        tenc_payload.extend(KID);
+        if iv_size == 0 {
+            tenc_payload.push(constant_iv.len() as u8);
+            tenc_payload.extend(constant_iv);
+        }
+        let tenc = full_box(b"tenc", 1, 0, &tenc_payload);
+        let mut schm_payload = scheme.to_vec();
+        schm_payload.extend(0x10000u32.to_be_bytes());
+        let mut sinf_payload = make_box(b"frma", b"avc1");
+        sinf_payload.extend(full_box(b"schm", 0, 0, &schm_payload));
+        sinf_payload.extend(make_box(b"schi", &tenc));
+        let mut encv_payload = vec![0; 78];
+        encv_payload.extend(make_box(b"avcC", &[1, 2, 3]));
+        encv_payload.extend(make_box(b"sinf", &sinf_payload));
+        let mut stsd_payload = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        stsd_payload.extend(make_box(b"encv", &encv_payload));
+        let stbl = make_box(b"stbl", &make_box(b"stsd", &stsd_payload));
+        let mdia = make_box(b"mdia", &make_box(b"minf", &stbl));
+        let mut tkhd_payload = [0; 20];
+        tkhd_payload[12..16].copy_from_slice(&1u32.to_be_bytes());
+        let mut trak_payload = full_box(b"tkhd", 0, 0, &tkhd_payload[4..]);
+        trak_payload.extend(mdia);
+        let mut moov_payload = make_box(b"trak", &trak_payload);
+        moov_payload.extend(full_box(b"pssh", 0, 0, &[0; 20]));
+        make_box(b"moov", &moov_payload)
+    }
+
+    fn media_segment(samples: &[Vec<u8>], senc_payload: &[u8], senc_flags: u32) -> Vec<u8> {
+        let samples: Vec<&[u8]> = samples.iter().map(Vec::as_slice).collect();
+        let senc = full_box(b"senc", 0, senc_flags, senc_payload);
+        media_segment_with(1, 0, 1000, &samples, &senc)
+    }
+
+    fn mdat_payload(segment: &[u8]) -> Vec<u8> {
+        isobmff::find_path(segment, &[b"mdat"])
+            .unwrap()
+            .payload(segment)
+            .to_vec()
+    }
+
+    #[test]
+    fn parse_key_test() {
+        let (kid, key) =
+            parse_key("01010101-0101-0101-0101-010101010101:02020202020202020202020202020202")
+                .unwrap();
+        assert_eq!(kid, KID);
+        assert_eq!(key, KEY);
+        assert!(parse_key("0101").is_none());
+    }
+
+    #[test]
+    fn decrypt_init_rewrites_sample_entry() {
+        let init = encrypted_init(b"cenc", 0, 8, &[]);
+        let (clear, tracks) = decrypt_init(&init).unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].track_id, 1);
+        assert_eq!(&tracks[0].scheme_type, b"cenc");
+        assert_eq!(tracks[0].default_kid, KID);
+        let stsd = isobmff::find_path(
+            &clear,
+            &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"],
+        )
+        .unwrap();
+        let entries = isobmff::boxes_in(&clear, stsd.payload_offset() + 8, stsd.end());
+        assert_eq!(&entries[0].box_type, b"avc1");
+        assert!(!clear
+            .windows(4)
+            .any(|window| window == b"sinf" || window == b"pssh"));
+    }
+
+    #[test]
+    fn decrypt_cenc_segment() {
+        let cipher = Aes128::new(&GenericArray::from(KEY));
+        let clear_samples = vec![
+            (0..40).collect::<Vec<u8>>(),
+            (100..130).collect::<Vec<u8>>(),
+        ];
+        let iv = [9u8, 8, 7, 6, 5, 4, 3, 2];
+        // first sample: 5 clear bytes then 35 protected bytes, second sample fully protected
+        let mut encrypted_samples = clear_samples.clone();
+        CtrState::new(&iv).apply(&cipher, &mut encrypted_samples[0][5..]);
+        CtrState::new(&iv).apply(&cipher, &mut encrypted_samples[1]);
+        let mut senc_payload = 2u32.to_be_bytes().to_vec();
+        senc_payload.extend(iv);
+        senc_payload.extend([0, 1, 0, 5, 0, 0, 0, 35]);
+        senc_payload.extend(iv);
+        senc_payload.extend([0, 1, 0, 0, 0, 0, 0, 30]);
+        let mut segment = media_segment(&encrypted_samples, &senc_payload, 0x2);
+
+        let (_, tracks) = decrypt_init(&encrypted_init(b"cenc", 0, 8, &[])).unwrap();
+        let defaults = vec![TrackInit {
+            track_id: 1,
+            ..Default::default()
+        }];
+        let keys = KeyMap::from([(KID, KEY)]);
+        let length = segment.len();
+        assert_eq!(
+            decrypt_segment(&mut segment, &tracks, &defaults, &keys),
+            Ok(2)
+        );
+        assert_eq!(segment.len(), length);
+        assert_eq!(mdat_payload(&segment), clear_samples.concat());
+        assert!(!segment.windows(4).any(|window| window == b"senc"));
+    }
+
+    #[test]
+    fn decrypt_cbcs_segment() {
+        let cipher = Aes128::new(&GenericArray::from(KEY));
+        let constant_iv = [3u8; 16];
+        let clear_sample: Vec<u8> = (0..=255).collect();
+        let mut encrypted_sample = clear_sample.clone();
+        // 16 clear bytes, then 1:9 pattern over 240 protected bytes
+        let mut chain = constant_iv;
+        for (block_idx, block) in encrypted_sample[16..].chunks_mut(16).enumerate() {
+            if block_idx % 10 == 0 {
+                for (idx, byte) in block.iter_mut().enumerate() {
+                    *byte ^= chain[idx];
+                }
+                let mut encrypted = GenericArray::clone_from_slice(block);
+                cipher.encrypt_block(&mut encrypted);
+                block.copy_from_slice(&encrypted);
+                chain.copy_from_slice(block);
+            }
+        }
+        let mut senc_payload = 1u32.to_be_bytes().to_vec();
+        senc_payload.extend([0, 1, 0, 16, 0, 0, 0, 240]);
+        let mut segment = media_segment(&[encrypted_sample], &senc_payload, 0x2);
+
+        let init = encrypted_init(b"cbcs", 0x19, 0, &constant_iv);
+        let (_, tracks) = decrypt_init(&init).unwrap();
+        let keys = KeyMap::from([(KID, KEY)]);
+        assert_eq!(decrypt_segment(&mut segment, &tracks, &[], &keys), Ok(1));
+        assert_eq!(mdat_payload(&segment), clear_sample);
+    }
+
+    #[test]
+    fn decrypt_segment_without_key() {
+        let mut segment = media_segment(&[vec![0; 16]], &[0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0], 0);
+        let (_, tracks) = decrypt_init(&encrypted_init(b"cenc", 0, 8, &[])).unwrap();
+        assert!(decrypt_segment(&mut segment, &tracks, &[], &KeyMap::new()).is_err());
+    }
+
+    #[test]
+    fn strip_content_protection_test() {
+        let manifest = "<AdaptationSet>\n  <ContentProtection schemeIdUri=\"a\"/>\n  <ContentProtection schemeIdUri=\"b\">\n    <cenc:pssh>AAAA</cenc:pssh>\n  </ContentProtection>\n  <Representation/>\n</AdaptationSet>";
+        assert_eq!(
+            strip_content_protection(manifest),
+            "<AdaptationSet>\n  <Representation/>\n</AdaptationSet>"
+        );
+    }
+}
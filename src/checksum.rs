@@ -0,0 +1,114 @@
+use crate::journal;
+use crate::storage::Storage;
+
+pub const CHECKSUM_FILE_NAME: &str = "checksums.sha256";
+
+/// One line of the checksum file, `<sha256> <size> <path>` with the path relative to the
+/// mirror directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checksum {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl Checksum {
+    pub fn new(path: &str, data: &[u8]) -> Checksum {
+        Checksum {
+            path: path.to_string(),
+            size: data.len() as u64,
+            sha256: journal::sha256_hex(data),
+        }
+    }
+}
+
+pub fn checksum_path(output_directory: &str) -> std::path::PathBuf {
+    std::path::Path::new(output_directory).join(CHECKSUM_FILE_NAME)
+}
+
+/// Writes `checksums` sorted by path.
+pub fn write_checksums(storage: &dyn Storage, checksums: &[Checksum]) -> Result<(), String> {
+    let mut checksums = checksums.to_vec();
+    checksums.sort_by(|a, b| a.path.cmp(&b.path));
+    checksums.dedup_by(|a, b| a.path == b.path);
+    let text: String = checksums
+        .iter()
+        .map(|checksum| format!("{} {} {}\n", checksum.sha256, checksum.size, checksum.path))
+        .collect();
+    storage.write(CHECKSUM_FILE_NAME, text.as_bytes())
+}
+
+pub fn read_checksums(output_directory: &str) -> Result<Vec<Checksum>, String> {
+    let path = checksum_path(output_directory);
+    let text = std::fs::read_to_string(&path).map_err(|e| format!("{} : {}", path.display(), e))?;
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(sha256), Some(size), Some(path)) if sha256.len() == 64 => Ok(Checksum {
+                    path: path.to_string(),
+                    size: size
+                        .parse()
+                        .map_err(|_| format!("invalid checksum line {}", line))?,
+                    sha256: sha256.to_ascii_lowercase(),
+                }),
+                _ => Err(format!("invalid checksum line {}", line)),
+            }
+        })
+        .collect()
+}
+
+/// Relative paths of all files below `output_directory`, with `/` separators.
+pub fn list_files(output_directory: &str) -> Result<Vec<String>, String> {
+    let mut files = Vec::new();
+    let mut directories = vec![(std::path::PathBuf::from(output_directory), String::new())];
+    while let Some((directory, prefix)) = directories.pop() {
+        let entries = std::fs::read_dir(&directory)
+            .map_err(|e| format!("{} : {}", directory.display(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("{} : {}", directory.display(), e))?;
+            let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            if entry.path().is_dir() {
+                directories.push((entry.path(), format!("{}/", name)));
+            } else {
+                files.push(name);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{list_files, read_checksums, write_checksums, Checksum};
+    use crate::storage::FileStorage;
+
+    #[test]
+    fn checksum_file_round_trip() {
+        let directory =
+            std::env::temp_dir().join(format!("dash-mirror-checksum-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("video")).unwrap();
+        std::fs::write(directory.join("video/a b.m4s"), b"a").unwrap();
+        let output_directory = directory.to_str().unwrap();
+        let checksums = vec![
+            Checksum::new("video/a b.m4s", b"a"),
+            Checksum::new("manifest.mpd", b""),
+        ];
+        write_checksums(&FileStorage::new(output_directory), &checksums).unwrap();
+        let read = read_checksums(output_directory).unwrap();
+        assert_eq!(read, vec![checksums[1].clone(), checksums[0].clone()]);
+        assert_eq!(
+            read[0].sha256,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            list_files(output_directory).unwrap(),
+            vec!["checksums.sha256", "video/a b.m4s"]
+        );
+        std::fs::write(directory.join("checksums.sha256"), "abc 1 x\n").unwrap();
+        assert!(read_checksums(output_directory).is_err());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
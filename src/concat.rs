@@ -0,0 +1,211 @@
+use std::io::Write;
+
+use crate::isobmff::{self, SidxReference};
+use crate::layout;
+use crate::mpd::{RepresentationUrls, UrlInfo};
+
+pub const CONCAT_DIRECTORY_NAME: &str = "concat";
+
+/// Media segment bytes without the top level boxes which must not be repeated
+/// inside a single file.
+fn media_segment_boxes(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for header in isobmff::top_level_boxes(data) {
+        if &header.box_type != b"sidx" && &header.box_type != b"ssix" {
+            out.extend(&data[header.offset..header.end()]);
+        }
+    }
+    out
+}
+
+/// Output path of the single file of a representation.
+pub fn concat_path(
+    output_directory: &str,
+    url_info: &UrlInfo,
+    representation: &RepresentationUrls,
+) -> std::path::PathBuf {
+    let mut path = std::path::Path::new(output_directory).join(CONCAT_DIRECTORY_NAME);
+    let multi_period = url_info
+        .representations
+        .iter()
+        .any(|rep| rep.period_idx != representation.period_idx);
+    if multi_period {
+        path.push(format!("period{}", representation.period_idx));
+    }
+    path.join(format!("{}.mp4", layout::file_name(&representation.id)))
+}
+
+fn build_index(
+    output_directory: &str,
+    base_url: &str,
+    representation: &RepresentationUrls,
+    init: &[u8],
+) -> Result<Vec<u8>, String> {
+    let tracks = isobmff::parse_init_tracks(init);
+    let track = tracks.first().ok_or("no track in init segment")?;
+    let timescale = match track.timescale {
+        0 => representation.timescale as u32,
+        timescale => timescale,
+    };
+    let mut earliest_presentation_time = None;
+    let mut references = Vec::new();
+    for segment in &representation.segments {
+        let data = media_segment_boxes(&layout::read_segment(
+            output_directory,
+            base_url,
+            &segment.url,
+        )?);
+        let fragments = isobmff::parse_track_fragments(&data, &tracks);
+        let fragment = fragments
+            .iter()
+            .find(|fragment| fragment.track_id == track.track_id);
+        if earliest_presentation_time.is_none() {
+            earliest_presentation_time = Some(
+                fragment
+                    .and_then(|fragment| fragment.base_media_decode_time)
+                    .unwrap_or(segment.time),
+            );
+        }
+        let duration = match fragment {
+            Some(fragment) if fragment.duration > 0 => fragment.duration,
+            _ => segment.duration.unwrap_or_default(),
+        };
+        references.push(SidxReference {
+            referenced_size: data.len() as u32,
+            subsegment_duration: duration as u32,
+            starts_with_sap: fragment.is_none_or(|fragment| fragment.starts_with_sync_sample),
+        });
+    }
+    Ok(isobmff::build_sidx(
+        track.track_id,
+        timescale,
+        earliest_presentation_time.unwrap_or_default(),
+        &references,
+    ))
+}
+
+/// Joins the init segment and the media segments of a representation, in timeline
+/// order, into one fragmented MP4 file, optionally indexed by a `sidx` box.
+pub fn concat_representation(
+    output_directory: &str,
+    url_info: &UrlInfo,
+    representation: &RepresentationUrls,
+    with_sidx: bool,
+) -> Result<std::path::PathBuf, String> {
+    let initialization = representation
+        .initialization
+        .as_ref()
+        .ok_or("initialization segment not present")?;
+    let init = layout::read_segment(output_directory, &url_info.base_url, initialization)?;
+    let path = concat_path(output_directory, url_info, representation);
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)
+            .map_err(|e| format!("{} : {}", directory.display(), e))?;
+    }
+    let file = std::fs::File::create(&path).map_err(|e| format!("{} : {}", path.display(), e))?;
+    let mut writer = std::io::BufWriter::new(file);
+    let write_error = |e: std::io::Error| format!("{} : {}", path.display(), e);
+    writer.write_all(&init).map_err(write_error)?;
+    if with_sidx {
+        let sidx = build_index(output_directory, &url_info.base_url, representation, &init)?;
+        writer.write_all(&sidx).map_err(write_error)?;
+    }
+    for segment in &representation.segments {
+        let data = layout::read_segment(output_directory, &url_info.base_url, &segment.url)?;
+        writer
+            .write_all(&media_segment_boxes(&data))
+            .map_err(write_error)?;
+    }
+    writer.flush().map_err(write_error)?;
+    Ok(path)
+}
+
+/// Writes one file per representation of the mirror in `output_directory`.
+/// Returns the number of representations which could not be joined.
+pub fn concat_mirror(output_directory: &str, with_sidx: bool) -> usize {
+    let Some((_, url_info)) = layout::read_mirror(output_directory) else {
+        return 1;
+    };
+    let mut failures = 0;
+    for representation in &url_info.representations {
+        match concat_representation(output_directory, &url_info, representation, with_sidx) {
+            Ok(path) => println!(
+                "rep {} : {} segments joined into {}",
+                representation.id,
+                representation.segments.len(),
+                path.display()
+            ),
+            Err(e) => {
+                eprintln!("rep {} : could not join segments {}", representation.id, e);
+                failures += 1;
+            }
+        }
+    }
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::concat_mirror;
+    use crate::isobmff;
+    use crate::isobmff::tests::{self, init_segment, make_box};
+
+    /// Media segment of track 1 with two samples, and a sidx box of its own.
+    fn media_segment(decode_time: u64, duration: u32) -> Vec<u8> {
+        let mut segment = tests::media_segment(1, decode_time, duration, &[&[0; 5], &[0; 5]]);
+        let styp_size = isobmff::read_box_header(&segment, 0).unwrap().size;
+        segment.splice(styp_size..styp_size, make_box(b"sidx", &[0; 32]));
+        segment
+    }
+
+    #[test]
+    fn concat_with_sidx() {
+        let directory =
+            std::env::temp_dir().join(format!("dash-mirror-concat-{}", std::process::id()));
+        let output_directory = directory.to_str().unwrap();
+        std::fs::create_dir_all(directory.join("video")).unwrap();
+        std::fs::write(
+            directory.join("manifest.mpd"),
+            r#"<MPD type="static" mediaPresentationDuration="PT4S">
+            <Period duration="PT4S">
+            <AdaptationSet mimeType="video/mp4">
+            <SegmentTemplate timescale="1000" duration="2000" media="video/$Number$.m4s" initialization="video/init.mp4"/>
+            <Representation id="v1" bandwidth="1000"/>
+            </AdaptationSet>
+            </Period>
+            </MPD>"#,
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("video/init.mp4"),
+            init_segment(1, 1000, b"vide"),
+        )
+        .unwrap();
+        std::fs::write(directory.join("video/1.m4s"), media_segment(0, 1000)).unwrap();
+        std::fs::write(directory.join("video/2.m4s"), media_segment(2000, 950)).unwrap();
+
+        assert_eq!(concat_mirror(output_directory, true), 0);
+        let data = std::fs::read(directory.join("concat/v1.mp4")).unwrap();
+        let types: Vec<[u8; 4]> = isobmff::top_level_boxes(&data)
+            .iter()
+            .map(|header| header.box_type)
+            .collect();
+        assert_eq!(
+            types,
+            [
+                *b"ftyp", *b"moov", *b"sidx", *b"styp", *b"moof", *b"mdat", *b"styp", *b"moof",
+                *b"mdat"
+            ]
+        );
+        let sidx = isobmff::find_path(&data, &[b"sidx"]).unwrap();
+        let payload = sidx.payload(&data);
+        assert_eq!(isobmff::read_u32(payload, 8), Some(1000));
+        assert_eq!(isobmff::read_u16(payload, 30), Some(2));
+        let first_size = isobmff::read_u32(payload, 32).unwrap() as usize;
+        assert_eq!(isobmff::read_u32(payload, 36), Some(2000));
+        assert_eq!(isobmff::read_u32(payload, 48), Some(1900));
+        let second_styp = isobmff::read_box_header(&data, sidx.end() + first_size).unwrap();
+        assert_eq!(&second_styp.box_type, b"styp");
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,518 @@
+use base64::Engine;
+use serde::Serialize;
+
+use crate::mpd::{ContentProtection, TrackProtection};
+
+const WIDEVINE_SYSTEM_ID: &str = "edef8ba9-79d6-4ace-a3c8-27dcd51d21ed";
+const PLAYREADY_SYSTEM_ID: &str = "9a04f079-9840-4286-ab92-e65be0885f95";
+
+const KNOWN_SYSTEM_IDS: [(&str, &str); 6] = [
+    (WIDEVINE_SYSTEM_ID, "Widevine"),
+    (PLAYREADY_SYSTEM_ID, "PlayReady"),
+    ("94ce86fb-07ff-4f43-adb8-93d2fa968ca2", "FairPlay"),
+    ("e2719d58-a985-b3c9-781a-b030af78d30e", "ClearKey"),
+    ("1077efec-c0b2-4d02-ace3-3c1e52e2fb4b", "W3C Common"),
+    ("f239e769-efa3-4850-9c16-a903c6932efb", "PrimeTime"),
+];
+
+pub struct PsshBox {
+    pub version: u8,
+    pub system_id: [u8; 16],
+    pub key_ids: Vec<[u8; 16]>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct PsshInfo {
+    system_id: String,
+    system_name: Option<&'static str>,
+    version: u8,
+    key_ids: Vec<String>,
+    data: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    playready_header: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ContentProtectionInfo {
+    level: &'static str,
+    scheme_id_uri: String,
+    system_name: Option<&'static str>,
+    value: Option<String>,
+    default_kid: Option<String>,
+    laurl: Option<String>,
+    pssh: Vec<PsshInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    playready_header: Option<String>,
+    /// Key IDs of the PlayReady header in mspr:pro.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    playready_key_ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct TrackInfo {
+    period: usize,
+    adaptation_set: usize,
+    mime_type: String,
+    representation_id: String,
+    bandwidth: u64,
+    key_ids: Vec<String>,
+    content_protection: Vec<ContentProtectionInfo>,
+}
+
+#[derive(Serialize)]
+struct InspectReport {
+    tracks: Vec<TrackInfo>,
+}
+
+pub fn format_uuid(bytes: &[u8; 16]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+pub fn parse_uuid(text: &str) -> Option<[u8; 16]> {
+    let hex: String = text.chars().filter(|c| *c != '-').collect();
+    if hex.len() != 32 {
+        return None;
+    }
+    let mut bytes = [0u8; 16];
+    for (idx, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(idx * 2..idx * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn system_name(system_id: &str) -> Option<&'static str> {
+    KNOWN_SYSTEM_IDS
+        .iter()
+        .find(|(id, _)| *id == system_id)
+        .map(|(_, name)| *name)
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn read_uuid(data: &[u8], pos: usize) -> Option<[u8; 16]> {
+    data.get(pos..pos + 16)?.try_into().ok()
+}
+
+/// Parses all `pssh` boxes found back to back in `data`.
+pub fn parse_pssh_boxes(data: &[u8]) -> Vec<PsshBox> {
+    let mut boxes = Vec::new();
+    let mut pos = 0;
+    while let Some(size) = read_u32(data, pos) {
+        let size = size as usize;
+        if size < 8 || pos + size > data.len() {
+            eprintln!("pssh box size {} is invalid", size);
+            break;
+        }
+        if &data[pos + 4..pos + 8] == b"pssh" {
+            match parse_pssh_payload(&data[pos + 8..pos + size]) {
+                Some(pssh) => boxes.push(pssh),
+                None => eprintln!("Could not parse pssh box"),
+            }
+        }
+        pos += size;
+    }
+    boxes
+}
+
+/// Parses the payload of a `pssh` full box, starting at version and flags.
+pub fn parse_pssh_payload(payload: &[u8]) -> Option<PsshBox> {
+    let version = *payload.first()?;
+    let system_id = read_uuid(payload, 4)?;
+    let mut pos = 20;
+    let mut key_ids = Vec::new();
+    if version > 0 {
+        let kid_count = read_u32(payload, pos)?;
+        pos += 4;
+        for _ in 0..kid_count {
+            key_ids.push(read_uuid(payload, pos)?);
+            pos += 16;
+        }
+    }
+    let data_size = read_u32(payload, pos)? as usize;
+    pos += 4;
+    let data = payload.get(pos..pos + data_size)?.to_vec();
+    Some(PsshBox {
+        version,
+        system_id,
+        key_ids,
+        data,
+    })
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Extracts the `key_id` fields (field 2) of a Widevine PSSH data protobuf.
+fn parse_widevine_key_ids(data: &[u8]) -> Vec<[u8; 16]> {
+    let mut key_ids = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let Some(key) = read_varint(data, &mut pos) else {
+            break;
+        };
+        let length = match key & 0x7 {
+            0 => {
+                if read_varint(data, &mut pos).is_none() {
+                    break;
+                }
+                continue;
+            }
+            1 => 8,
+            2 => match read_varint(data, &mut pos).map(usize::try_from) {
+                Some(Ok(length)) => length,
+                _ => break,
+            },
+            5 => 4,
+            _ => break,
+        };
+        if key >> 3 == 2 && length == 16 {
+            if let Some(key_id) = read_uuid(data, pos) {
+                key_ids.push(key_id);
+            }
+        }
+        match pos.checked_add(length) {
+            Some(end) if end <= data.len() => pos = end,
+            _ => break,
+        }
+    }
+    key_ids
+}
+
+/// Returns the WRMHEADER XML of a PlayReady Object.
+pub fn parse_playready_header(pro: &[u8]) -> Option<String> {
+    let record_count = u16::from_le_bytes(pro.get(4..6)?.try_into().ok()?);
+    let mut pos = 6;
+    for _ in 0..record_count {
+        let record_type = u16::from_le_bytes(pro.get(pos..pos + 2)?.try_into().ok()?);
+        let record_length =
+            u16::from_le_bytes(pro.get(pos + 2..pos + 4)?.try_into().ok()?) as usize;
+        pos += 4;
+        let record = pro.get(pos..pos + record_length)?;
+        if record_type == 1 {
+            let utf16: Vec<u16> = record
+                .chunks_exact(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .collect();
+            return String::from_utf16(&utf16).ok();
+        }
+        pos += record_length;
+    }
+    None
+}
+
+/// Extracts the key IDs of a WRMHEADER, converting them from the little endian
+/// GUID layout used by PlayReady to the big endian layout used by CENC.
+fn parse_playready_key_ids(header: &str) -> Vec<[u8; 16]> {
+    let mut key_ids = Vec::new();
+    let doc = match roxmltree::Document::parse(header) {
+        Ok(doc) => doc,
+        Err(e) => {
+            eprintln!("PlayReady header parse error: {}", e);
+            return key_ids;
+        }
+    };
+    for node in doc.root_element().descendants() {
+        if !node.has_tag_name("KID") {
+            continue;
+        }
+        let encoded = match node.attribute("VALUE") {
+            Some(value) => value,
+            None => node.text().unwrap_or_default(),
+        };
+        let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim());
+        if let Ok(guid) = decoded {
+            if let Ok(mut key_id) = <[u8; 16]>::try_from(guid.as_slice()) {
+                key_id[0..4].reverse();
+                key_id[4..6].reverse();
+                key_id[6..8].reverse();
+                key_ids.push(key_id);
+            }
+        }
+    }
+    key_ids
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let stripped: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    match base64::engine::general_purpose::STANDARD.decode(stripped) {
+        Ok(bytes) => Some(bytes),
+        Err(e) => {
+            eprintln!("base64 decode failure : {}", e);
+            None
+        }
+    }
+}
+
+/// Removes repeated key IDs, keeping the first occurrence of each.
+fn dedup_key_ids(key_ids: &mut Vec<[u8; 16]>) {
+    let mut seen = std::collections::HashSet::new();
+    key_ids.retain(|key_id| seen.insert(*key_id));
+}
+
+fn pssh_info(pssh: &PsshBox) -> PsshInfo {
+    let system_id = format_uuid(&pssh.system_id);
+    let mut key_ids: Vec<[u8; 16]> = pssh.key_ids.clone();
+    let mut playready_header = None;
+    if system_id == PLAYREADY_SYSTEM_ID {
+        playready_header = parse_playready_header(&pssh.data);
+        if let Some(header) = &playready_header {
+            key_ids.extend(parse_playready_key_ids(header));
+        }
+    } else if system_id == WIDEVINE_SYSTEM_ID {
+        key_ids.extend(parse_widevine_key_ids(&pssh.data));
+    }
+    dedup_key_ids(&mut key_ids);
+    PsshInfo {
+        system_name: system_name(&system_id),
+        system_id,
+        version: pssh.version,
+        key_ids: key_ids.iter().map(format_uuid).collect(),
+        data: base64::engine::general_purpose::STANDARD.encode(&pssh.data),
+        playready_header,
+    }
+}
+
+fn content_protection_info(
+    level: &'static str,
+    content_protection: &ContentProtection,
+) -> ContentProtectionInfo {
+    let mut pssh = Vec::new();
+    for encoded in &content_protection.pssh {
+        if let Some(bytes) = decode_base64(encoded) {
+            pssh.extend(parse_pssh_boxes(&bytes).iter().map(pssh_info));
+        }
+    }
+    let playready_header = content_protection
+        .pro
+        .as_deref()
+        .and_then(decode_base64)
+        .and_then(|pro| parse_playready_header(&pro));
+    let mut playready_key_ids = playready_header
+        .as_deref()
+        .map(parse_playready_key_ids)
+        .unwrap_or_default();
+    dedup_key_ids(&mut playready_key_ids);
+    let system_id = content_protection
+        .scheme_id_uri
+        .strip_prefix("urn:uuid:")
+        .unwrap_or_default();
+    ContentProtectionInfo {
+        level,
+        scheme_id_uri: content_protection.scheme_id_uri.clone(),
+        system_name: system_name(system_id),
+        value: content_protection.value.clone(),
+        default_kid: content_protection
+            .default_kid
+            .as_deref()
+            .map(|kid| kid.to_lowercase()),
+        laurl: content_protection.laurl.clone(),
+        pssh,
+        playready_header,
+        playready_key_ids: playready_key_ids.iter().map(format_uuid).collect(),
+    }
+}
+
+fn track_info(track: &TrackProtection) -> TrackInfo {
+    let mut content_protection: Vec<ContentProtectionInfo> = Vec::new();
+    for cp in &track.adaptation_set_protections {
+        content_protection.push(content_protection_info("AdaptationSet", cp));
+    }
+    for cp in &track.representation_protections {
+        content_protection.push(content_protection_info("Representation", cp));
+    }
+    let mut key_ids: Vec<String> = Vec::new();
+    for cp in &content_protection {
+        let pssh_key_ids = cp.pssh.iter().flat_map(|pssh| pssh.key_ids.iter());
+        let key_id_iter = cp
+            .default_kid
+            .iter()
+            .chain(pssh_key_ids)
+            .chain(&cp.playready_key_ids);
+        for key_id in key_id_iter {
+            if !key_ids.contains(key_id) {
+                key_ids.push(key_id.clone());
+            }
+        }
+    }
+    TrackInfo {
+        period: track.period_idx,
+        adaptation_set: track.adaptation_set_idx,
+        mime_type: track.mime_type.clone(),
+        representation_id: track.representation_id.clone(),
+        bandwidth: track.bandwidth,
+        key_ids,
+        content_protection,
+    }
+}
+
+/// Builds the JSON report of ContentProtection, PSSH boxes and key IDs per track.
+pub fn inspect(xml_text: String) -> serde_json::Value {
+    let tracks = crate::mpd::get_track_protections(xml_text);
+    let report = InspectReport {
+        tracks: tracks.iter().map(track_info).collect(),
+    };
+    serde_json::to_value(report).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+
+    use super::{format_uuid, inspect, parse_pssh_boxes, parse_uuid, parse_widevine_key_ids};
+
+    fn build_pssh(system_id: &str, key_ids: &[&str], data: &[u8]) -> Vec<u8> {
+        let mut payload: Vec<u8> = vec![if key_ids.is_empty() { 0 } else { 1 }, 0, 0, 0];
+        payload.extend(parse_uuid(system_id).unwrap());
+        if !key_ids.is_empty() {
+            payload.extend((key_ids.len() as u32).to_be_bytes());
+            for key_id in key_ids {
+                payload.extend(parse_uuid(key_id).unwrap());
+            }
+        }
+        payload.extend((data.len() as u32).to_be_bytes());
+        payload.extend(data);
+        let mut pssh = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        pssh.extend(b"pssh");
+        pssh.extend(payload);
+        pssh
+    }
+
+    #[test]
+    fn uuid_round_trip() {
+        let uuid = "9a04f079-9840-4286-ab92-e65be0885f95";
+        assert_eq!(format_uuid(&parse_uuid(uuid).unwrap()), uuid);
+        assert!(parse_uuid("1234").is_none());
+    }
+
+    #[test]
+    fn parse_pssh_v1() {
+        let pssh = build_pssh(
+            "1077efec-c0b2-4d02-ace3-3c1e52e2fb4b",
+            &["10000000-1000-1000-1000-100000000001"],
+            &[],
+        );
+        let boxes = parse_pssh_boxes(&pssh);
+        assert_eq!(boxes.len(), 1);
+        assert_eq!(boxes[0].version, 1);
+        assert_eq!(
+            format_uuid(&boxes[0].key_ids[0]),
+            "10000000-1000-1000-1000-100000000001"
+        );
+    }
+
+    /// PlayReady Object with a WRMHEADER listing `key_ids`.
+    fn build_pro(key_ids: &[&str]) -> Vec<u8> {
+        let kids: String = key_ids
+            .iter()
+            .map(|key_id| {
+                let mut guid = parse_uuid(key_id).unwrap();
+                guid[0..4].reverse();
+                guid[4..6].reverse();
+                guid[6..8].reverse();
+                let encoded = base64::engine::general_purpose::STANDARD.encode(guid);
+                format!(r#"<KID ALGID="AESCTR" VALUE="{}"/>"#, encoded)
+            })
+            .collect();
+        let header = format!(
+            r#"<WRMHEADER xmlns="http://schemas.microsoft.com/DRM/2007/03/PlayReadyHeader" version="4.3.0.0"><DATA><PROTECTINFO><KIDS>{}</KIDS></PROTECTINFO></DATA></WRMHEADER>"#,
+            kids
+        );
+        let record: Vec<u8> = header.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let mut pro = ((record.len() + 10) as u32).to_le_bytes().to_vec();
+        pro.extend(1u16.to_le_bytes());
+        pro.extend(1u16.to_le_bytes());
+        pro.extend((record.len() as u16).to_le_bytes());
+        pro.extend(record);
+        pro
+    }
+
+    #[test]
+    fn malformed_widevine_data() {
+        // a key_id field whose length varint is far beyond the data
+        let mut data = vec![0x12];
+        data.extend([0xff; 9]);
+        data.push(0x01);
+        assert!(parse_widevine_key_ids(&data).is_empty());
+        let mut data = vec![0x12, 0x10];
+        data.extend([0x22; 16]);
+        data.extend([0x1a, 0x40, 0x00]);
+        assert_eq!(parse_widevine_key_ids(&data), vec![[0x22; 16]]);
+    }
+
+    #[test]
+    fn inspect_content_protection() {
+        // Widevine PSSH data with a single key_id field (field 2, 16 bytes).
+        let mut widevine_data = vec![0x12, 0x10];
+        widevine_data.extend(parse_uuid("20000000-2000-2000-2000-200000000002").unwrap());
+        let widevine = build_pssh("edef8ba9-79d6-4ace-a3c8-27dcd51d21ed", &[], &widevine_data);
+        let encoded = base64::engine::general_purpose::STANDARD.encode(widevine);
+        let pro = base64::engine::general_purpose::STANDARD.encode(build_pro(&[
+            "30000000-3000-3000-3000-300000000003",
+            "20000000-2000-2000-2000-200000000002",
+            "30000000-3000-3000-3000-300000000003",
+        ]));
+        let xml_text = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <MPD xmlns="urn:mpeg:dash:schema:mpd:2011" xmlns:cenc="urn:mpeg:cenc:2013" xmlns:dashif="https://dashif.org/CPS">
+            <Period>
+            <AdaptationSet mimeType="video/mp4">
+                <ContentProtection schemeIdUri="urn:mpeg:dash:mp4protection:2011" value="cenc" cenc:default_KID="20000000-2000-2000-2000-200000000002"/>
+                <ContentProtection schemeIdUri="urn:uuid:EDEF8BA9-79D6-4ACE-A3C8-27DCD51D21ED">
+                    <cenc:pssh>{}</cenc:pssh>
+                    <dashif:laurl>https://license.test.com/widevine</dashif:laurl>
+                </ContentProtection>
+                <ContentProtection schemeIdUri="urn:uuid:9A04F079-9840-4286-AB92-E65BE0885F95">
+                    <mspr:pro xmlns:mspr="urn:microsoft:playready">{}</mspr:pro>
+                </ContentProtection>
+                <Representation id="video1" bandwidth="1000000"/>
+            </AdaptationSet>
+            </Period>
+            </MPD>"#,
+            encoded, pro
+        );
+        let report = inspect(xml_text);
+        let track = &report["tracks"][0];
+        assert_eq!(track["representation_id"], "video1");
+        assert_eq!(track["key_ids"][0], "20000000-2000-2000-2000-200000000002");
+        assert_eq!(track["key_ids"][1], "30000000-3000-3000-3000-300000000003");
+        assert_eq!(track["key_ids"].as_array().unwrap().len(), 2);
+        let playready = &track["content_protection"][2];
+        assert_eq!(playready["system_name"], "PlayReady");
+        assert_eq!(
+            playready["playready_key_ids"],
+            serde_json::json!([
+                "30000000-3000-3000-3000-300000000003",
+                "20000000-2000-2000-2000-200000000002"
+            ])
+        );
+        let widevine = &track["content_protection"][1];
+        assert_eq!(widevine["system_name"], "Widevine");
+        assert_eq!(widevine["laurl"], "https://license.test.com/widevine");
+        assert_eq!(widevine["pssh"][0]["system_name"], "Widevine");
+        assert_eq!(
+            widevine["pssh"][0]["key_ids"][0],
+            "20000000-2000-2000-2000-200000000002"
+        );
+    }
+}
@@ -0,0 +1,572 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::throttle::RateLimiter;
+
+#[derive(Clone)]
+pub struct FetchRequest {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl FetchRequest {
+    pub fn new(url: &str) -> FetchRequest {
+        FetchRequest {
+            url: url.to_string(),
+            headers: Vec::new(),
+        }
+    }
+}
+
+pub struct FetchResponse {
+    pub status: u16,
+    /// Url the response was received from, the last one of a redirect chain.
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl FetchResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    pub fn is_redirect(&self) -> bool {
+        matches!(self.status, 301 | 302 | 303 | 307 | 308)
+    }
+}
+
+/// Transport used to load manifests and segments. A fetcher performs a single request,
+/// redirects and retries are handled by the caller.
+pub trait Fetcher: Send + Sync {
+    fn fetch(&self, request: &FetchRequest) -> Result<FetchResponse, String>;
+
+    /// Like `fetch`, but the body of a successful response is written to `sink` as it
+    /// arrives instead of being kept in `body`.
+    fn fetch_to(
+        &self,
+        request: &FetchRequest,
+        sink: &mut dyn std::io::Write,
+    ) -> Result<FetchResponse, String> {
+        let mut response = self.fetch(request)?;
+        if response.is_success() {
+            sink.write_all(&response.body).map_err(|e| e.to_string())?;
+            response.body = Vec::new();
+        }
+        Ok(response)
+    }
+}
+
+/// Connection settings of `HttpFetcher`.
+#[derive(Default)]
+pub struct HttpOptions {
+    /// `http://`, `https://`, `socks5://` or `socks5h://` proxy url for all requests.
+    pub proxy: Option<String>,
+    /// PEM file with certificates trusted in addition to the system ones.
+    pub ca_bundle: Option<std::path::PathBuf>,
+    /// Client certificate, a PKCS #12 file, or a PEM file used with `client_key`.
+    pub client_certificate: Option<std::path::PathBuf>,
+    /// PKCS #8 PEM private key of a PEM `client_certificate`.
+    pub client_key: Option<std::path::PathBuf>,
+    pub client_certificate_password: Option<String>,
+    /// Accept invalid certificates and host names.
+    pub insecure: bool,
+    pub connect_timeout: Option<std::time::Duration>,
+    /// Limit for a whole request, including reading the body, 30 seconds if not set.
+    pub timeout: Option<std::time::Duration>,
+    /// Addresses to use for a host instead of resolving it.
+    pub resolve: Vec<(String, Vec<std::net::SocketAddr>)>,
+    /// Limits the rate at which response bodies are read, shared by all requests.
+    pub rate_limiter: Option<std::sync::Arc<RateLimiter>>,
+}
+
+/// Parses a curl style `host:port:addr[,addr]...` override. Addresses take the port,
+/// urls without an explicit port connect to it. IPv6 addresses may be in brackets.
+pub fn parse_resolve(entry: &str) -> Result<(String, Vec<std::net::SocketAddr>), String> {
+    let invalid = || format!("invalid resolve entry {}, expected host:port:addr", entry);
+    let (host, rest) = entry.split_once(':').ok_or_else(invalid)?;
+    let (port, addresses) = rest.split_once(':').ok_or_else(invalid)?;
+    let port: u16 = port.parse().map_err(|_| invalid())?;
+    let addresses = addresses
+        .split(',')
+        .map(|address| {
+            let address = address.trim_start_matches('[').trim_end_matches(']');
+            address
+                .parse::<std::net::IpAddr>()
+                .map(|ip| std::net::SocketAddr::new(ip, port))
+                .map_err(|_| invalid())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host.to_ascii_lowercase(), addresses))
+}
+
+fn read_file(path: &std::path::Path) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("{} : {}", path.display(), e))
+}
+
+pub struct HttpFetcher {
+    client: reqwest::blocking::Client,
+    rate_limiter: Option<std::sync::Arc<RateLimiter>>,
+}
+
+/// Time a whole request may take. Without an explicit timeout the blocking client's
+/// default of 30 seconds applies, unless a rate limit may stretch the transfer.
+fn total_timeout(options: &HttpOptions) -> Option<std::time::Duration> {
+    match (options.timeout, &options.rate_limiter) {
+        (Some(timeout), _) => Some(timeout),
+        (None, Some(_)) => None,
+        (None, None) => Some(std::time::Duration::from_secs(30)),
+    }
+}
+
+impl HttpFetcher {
+    pub fn new() -> Result<HttpFetcher, String> {
+        HttpFetcher::with_options(&HttpOptions::default())
+    }
+
+    pub fn with_options(options: &HttpOptions) -> Result<HttpFetcher, String> {
+        let mut builder =
+            reqwest::blocking::Client::builder().redirect(reqwest::redirect::Policy::none());
+        if let Some(proxy) = &options.proxy {
+            let proxy =
+                reqwest::Proxy::all(proxy).map_err(|e| format!("proxy {} : {}", proxy, e))?;
+            builder = builder.proxy(proxy);
+        }
+        if let Some(path) = &options.ca_bundle {
+            let certificates = reqwest::Certificate::from_pem_bundle(&read_file(path)?)
+                .map_err(|e| format!("{} : {}", path.display(), e))?;
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if let Some(path) = &options.client_certificate {
+            let certificate = read_file(path)?;
+            let identity = match &options.client_key {
+                Some(key) => reqwest::Identity::from_pkcs8_pem(&certificate, &read_file(key)?),
+                None => reqwest::Identity::from_pkcs12_der(
+                    &certificate,
+                    options
+                        .client_certificate_password
+                        .as_deref()
+                        .unwrap_or_default(),
+                ),
+            }
+            .map_err(|e| format!("{} : {}", path.display(), e))?;
+            builder = builder.identity(identity);
+        }
+        if options.insecure {
+            builder = builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+        if let Some(timeout) = options.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        builder = builder.timeout(total_timeout(options));
+        for (host, addresses) in &options.resolve {
+            builder = builder.resolve_to_addrs(host, addresses);
+        }
+        let client = builder.build().map_err(|e| e.to_string())?;
+        Ok(HttpFetcher {
+            client,
+            rate_limiter: options.rate_limiter.clone(),
+        })
+    }
+}
+
+/// Copies a body to `sink` in chunks, paced by `rate_limiter`.
+fn copy_body(
+    reader: &mut impl std::io::Read,
+    rate_limiter: Option<&RateLimiter>,
+    sink: &mut dyn std::io::Write,
+) -> Result<(), String> {
+    let mut chunk = Vec::new();
+    loop {
+        let chunk_size = rate_limiter.map_or(64 * 1024, |rate_limiter| rate_limiter.chunk_size());
+        chunk.resize(chunk_size, 0);
+        let read = reader.read(&mut chunk).map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Ok(());
+        }
+        sink.write_all(&chunk[..read]).map_err(|e| e.to_string())?;
+        if let Some(rate_limiter) = rate_limiter {
+            rate_limiter.consume(read);
+        }
+    }
+}
+
+impl HttpFetcher {
+    /// Performs `request`, writing a successful body to `sink` if there is one.
+    fn send(
+        &self,
+        request: &FetchRequest,
+        sink: Option<&mut dyn std::io::Write>,
+    ) -> Result<FetchResponse, String> {
+        let mut builder = self.client.get(&request.url);
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+        let mut response = builder.send().map_err(|e| e.to_string())?;
+        let status = response.status().as_u16();
+        let url = response.url().to_string();
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).to_string(),
+                )
+            })
+            .collect();
+        let mut body = Vec::new();
+        let rate_limiter = self.rate_limiter.as_deref();
+        match sink {
+            Some(sink) if response.status().is_success() => {
+                copy_body(&mut response, rate_limiter, sink)?
+            }
+            _ => copy_body(&mut response, rate_limiter, &mut body)?,
+        }
+        Ok(FetchResponse {
+            status,
+            url,
+            headers,
+            body,
+        })
+    }
+}
+
+impl Fetcher for HttpFetcher {
+    fn fetch(&self, request: &FetchRequest) -> Result<FetchResponse, String> {
+        self.send(request, None)
+    }
+
+    fn fetch_to(
+        &self,
+        request: &FetchRequest,
+        sink: &mut dyn std::io::Write,
+    ) -> Result<FetchResponse, String> {
+        self.send(request, Some(sink))
+    }
+}
+
+impl FetchResponse {
+    fn from_body(url: &str, body: Vec<u8>) -> FetchResponse {
+        FetchResponse {
+            status: 200,
+            url: url.to_string(),
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    fn not_found(url: &str) -> FetchResponse {
+        FetchResponse {
+            status: 404,
+            url: url.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+}
+
+/// Reads `file://` urls and plain paths from the local file system.
+pub struct FileFetcher;
+
+impl FileFetcher {
+    /// Local path of a `file://` url or plain path, `None` for other schemes.
+    pub fn path(url: &str) -> Option<std::path::PathBuf> {
+        if url.starts_with("file:") {
+            return reqwest::Url::parse(url).ok()?.to_file_path().ok();
+        }
+        if url.contains("://") {
+            return None;
+        }
+        let end = url.find('?').unwrap_or(url.len());
+        Some(std::path::PathBuf::from(&url[..end]))
+    }
+}
+
+impl Fetcher for FileFetcher {
+    fn fetch(&self, request: &FetchRequest) -> Result<FetchResponse, String> {
+        let path =
+            FileFetcher::path(&request.url).ok_or(format!("not a local url {}", request.url))?;
+        match std::fs::read(&path) {
+            Ok(body) => Ok(FetchResponse::from_body(&request.url, body)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(FetchResponse::not_found(&request.url))
+            }
+            Err(e) => Err(format!("{} : {}", path.display(), e)),
+        }
+    }
+
+    fn fetch_to(
+        &self,
+        request: &FetchRequest,
+        sink: &mut dyn std::io::Write,
+    ) -> Result<FetchResponse, String> {
+        let path =
+            FileFetcher::path(&request.url).ok_or(format!("not a local url {}", request.url))?;
+        match std::fs::File::open(&path) {
+            Ok(mut file) => {
+                std::io::copy(&mut file, sink)
+                    .map_err(|e| format!("{} : {}", path.display(), e))?;
+                Ok(FetchResponse::from_body(&request.url, Vec::new()))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(FetchResponse::not_found(&request.url))
+            }
+            Err(e) => Err(format!("{} : {}", path.display(), e)),
+        }
+    }
+}
+
+/// Serves bodies from an in-memory map of urls, unknown urls get a 404.
+#[derive(Default)]
+pub struct MemoryFetcher {
+    bodies: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryFetcher {
+    pub fn new() -> MemoryFetcher {
+        MemoryFetcher::default()
+    }
+
+    pub fn insert(&self, url: &str, body: Vec<u8>) {
+        self.bodies.lock().unwrap().insert(url.to_string(), body);
+    }
+}
+
+impl Fetcher for MemoryFetcher {
+    fn fetch(&self, request: &FetchRequest) -> Result<FetchResponse, String> {
+        Ok(match self.bodies.lock().unwrap().get(&request.url) {
+            Some(body) => FetchResponse::from_body(&request.url, body.clone()),
+            None => FetchResponse::not_found(&request.url),
+        })
+    }
+}
+
+/// Fetcher for the scheme of `url`: HTTP(S) for `http://` and `https://`, the local file
+/// system for `file://` and plain paths.
+pub fn fetcher_for_url(url: &str, options: &HttpOptions) -> Result<Box<dyn Fetcher>, String> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(Box::new(HttpFetcher::with_options(options)?))
+    } else if FileFetcher::path(url).is_some() {
+        Ok(Box::new(FileFetcher))
+    } else {
+        Err(format!("unsupported url scheme {}", url))
+    }
+}
+
+/// Resolves the `Location` of a redirect against the url which was requested.
+pub fn resolve_location(url: &str, location: &str) -> Option<String> {
+    let base = reqwest::Url::parse(url).ok()?;
+    Some(base.join(location).ok()?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        fetcher_for_url, parse_resolve, total_timeout, FetchRequest, Fetcher, FileFetcher,
+        HttpFetcher, HttpOptions, MemoryFetcher,
+    };
+    use crate::mirror::{mirror, MirrorOptions};
+    use crate::mock::MockOrigin;
+
+    #[test]
+    fn timeout_with_rate_limit() {
+        use crate::throttle::{RateLimiter, RateSchedule};
+        use std::time::Duration;
+
+        let mut options = HttpOptions::default();
+        assert_eq!(total_timeout(&options), Some(Duration::from_secs(30)));
+        // a large body at a low rate would never finish within the default
+        options.rate_limiter = Some(std::sync::Arc::new(RateLimiter::new(RateSchedule::new(
+            Some(1024),
+        ))));
+        assert_eq!(total_timeout(&options), None);
+        options.timeout = Some(Duration::from_secs(600));
+        assert_eq!(total_timeout(&options), Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn file_and_memory_fetchers() {
+        let directory =
+            std::env::temp_dir().join(format!("dash-mirror-fetch-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("a b.txt");
+        std::fs::write(&path, b"local").unwrap();
+        let file_url = reqwest::Url::from_file_path(&path).unwrap().to_string();
+        assert!(file_url.ends_with("a%20b.txt"));
+        for url in [file_url.as_str(), path.to_str().unwrap()] {
+            let response = FileFetcher.fetch(&FetchRequest::new(url)).unwrap();
+            assert_eq!((response.status, response.body), (200, b"local".to_vec()));
+        }
+        let missing = directory.join("missing.txt");
+        let response = FileFetcher
+            .fetch(&FetchRequest::new(missing.to_str().unwrap()))
+            .unwrap();
+        assert_eq!(response.status, 404);
+        assert!(FileFetcher
+            .fetch(&FetchRequest::new("http://origin.test/a"))
+            .is_err());
+        assert!(fetcher_for_url("ftp://origin.test/a.mpd", &Default::default()).is_err());
+
+        let memory = MemoryFetcher::new();
+        memory.insert("mem://a", b"memory".to_vec());
+        assert_eq!(
+            memory.fetch(&FetchRequest::new("mem://a")).unwrap().body,
+            b"memory"
+        );
+        assert_eq!(
+            memory.fetch(&FetchRequest::new("mem://b")).unwrap().status,
+            404
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn mirror_from_local_mirror() {
+        let origin = MockOrigin::synthetic_vod("http://origin.test/vod/", 2);
+        let source =
+            std::env::temp_dir().join(format!("dash-mirror-fetch-source-{}", std::process::id()));
+        let copy =
+            std::env::temp_dir().join(format!("dash-mirror-fetch-copy-{}", std::process::id()));
+        let options = MirrorOptions::new(
+            "http://origin.test/vod/manifest.mpd",
+            source.to_str().unwrap(),
+        );
+        mirror(&origin, &options).unwrap();
+
+        let manifest = source.join("manifest.mpd");
+        let url = reqwest::Url::from_file_path(&manifest).unwrap().to_string();
+        let fetcher = fetcher_for_url(&url, &Default::default()).unwrap();
+        let options = MirrorOptions::new(&url, copy.to_str().unwrap());
+        let summary = mirror(fetcher.as_ref(), &options).unwrap();
+        assert_eq!((summary.downloaded, summary.failed), (6, 0));
+        assert_eq!(
+            std::fs::read(copy.join("audio/2.m4s")).unwrap(),
+            origin.body("http://origin.test/vod/audio/2.m4s").unwrap()
+        );
+
+        let fetcher = fetcher_for_url(manifest.to_str().unwrap(), &Default::default()).unwrap();
+        let options = MirrorOptions::new(manifest.to_str().unwrap(), copy.to_str().unwrap());
+        let summary = mirror(fetcher.as_ref(), &options).unwrap();
+        assert_eq!((summary.skipped, summary.failed), (6, 0));
+        std::fs::remove_dir_all(source).unwrap();
+        std::fs::remove_dir_all(copy).unwrap();
+    }
+
+    #[test]
+    fn http_fetcher_resolve() {
+        use std::io::{Read, Write};
+        assert!(parse_resolve("cdn.test:443").is_err());
+        assert!(parse_resolve("cdn.test:x:127.0.0.1").is_err());
+        assert_eq!(
+            parse_resolve("CDN.test:8443:127.0.0.1,[::1]").unwrap(),
+            (
+                "cdn.test".to_string(),
+                vec![
+                    "127.0.0.1:8443".parse().unwrap(),
+                    "[::1]:8443".parse().unwrap()
+                ]
+            )
+        );
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let request = String::from_utf8(request).unwrap();
+            let host = request
+                .lines()
+                .find_map(|line| line.strip_prefix("host: "))
+                .unwrap()
+                .to_string();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                host.len(),
+                host
+            )
+            .unwrap();
+        });
+        let options = HttpOptions {
+            connect_timeout: Some(std::time::Duration::from_secs(5)),
+            resolve: vec![parse_resolve(&format!("origin.invalid:{}:127.0.0.1", port)).unwrap()],
+            ..Default::default()
+        };
+        let fetcher = HttpFetcher::with_options(&options).unwrap();
+        let response = fetcher
+            .fetch(&FetchRequest::new("http://origin.invalid/manifest.mpd"))
+            .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"origin.invalid");
+        server.join().unwrap();
+
+        let options = HttpOptions {
+            proxy: Some("not a url".to_string()),
+            ..Default::default()
+        };
+        assert!(HttpFetcher::with_options(&options).is_err());
+    }
+
+    #[test]
+    fn http_fetcher_streams() {
+        use crate::serve::{read_request, write_response, Response};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let body: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let served = body.clone();
+        let server = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let request = read_request(&mut reader).unwrap();
+                let response = match request.path.as_str() {
+                    "/segment.m4s" => Response::new(200, "video/mp4", served.clone()),
+                    _ => Response::new(404, "text/plain", b"not here".to_vec()),
+                };
+                write_response(&mut stream, &request, &response).unwrap();
+            }
+        });
+        let fetcher = HttpFetcher::new().unwrap();
+        let mut sink = Vec::new();
+        let response = fetcher
+            .fetch_to(
+                &FetchRequest::new(&format!("{}/segment.m4s", base_url)),
+                &mut sink,
+            )
+            .unwrap();
+        assert_eq!(response.status, 200);
+        assert!(response.body.is_empty());
+        assert_eq!(sink, body);
+        // error bodies are not written to the sink
+        let mut sink = Vec::new();
+        let response = fetcher
+            .fetch_to(&FetchRequest::new(&format!("{}/gone", base_url)), &mut sink)
+            .unwrap();
+        assert_eq!(
+            (response.status, response.body.as_slice()),
+            (404, &b"not here"[..])
+        );
+        assert!(sink.is_empty());
+        server.join().unwrap();
+    }
+}
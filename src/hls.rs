@@ -0,0 +1,449 @@
+use std::fmt::Write;
+
+use crate::isobmff;
+use crate::layout;
+use crate::mpd::{RepresentationUrls, UrlInfo};
+
+pub const MULTIVARIANT_PLAYLIST_NAME: &str = "master.m3u8";
+const AUDIO_GROUP_ID: &str = "audio";
+const SUBTITLES_GROUP_ID: &str = "subs";
+
+#[derive(Clone, Copy, PartialEq)]
+enum TrackKind {
+    Video,
+    Audio,
+    Subtitles,
+}
+
+fn track_kind(representation: &RepresentationUrls) -> Option<TrackKind> {
+    let content_type = representation.content_type.as_deref().unwrap_or_default();
+    let mime_type = representation.mime_type.as_str();
+    if content_type == "video" || mime_type.starts_with("video") {
+        Some(TrackKind::Video)
+    } else if content_type == "audio" || mime_type.starts_with("audio") {
+        Some(TrackKind::Audio)
+    } else if content_type == "text"
+        || mime_type.starts_with("text")
+        || mime_type == "application/mp4"
+    {
+        Some(TrackKind::Subtitles)
+    } else {
+        None
+    }
+}
+
+/// Playlist of a representation, next to the multivariant playlist. A leading `_` is
+/// added to ids which would otherwise take its name, and to ids which would take theirs.
+pub fn media_playlist_name(representation: &RepresentationUrls) -> String {
+    let name = layout::file_name(&representation.id);
+    let reserved = MULTIVARIANT_PLAYLIST_NAME.trim_end_matches(".m3u8");
+    match name.trim_start_matches('_').eq_ignore_ascii_case(reserved) {
+        true => format!("_{}.m3u8", name),
+        false => format!("{}.m3u8", name),
+    }
+}
+
+/// Representations of later periods which continue `representation` of the first period.
+fn continuations<'a>(
+    url_info: &'a UrlInfo,
+    representation: &'a RepresentationUrls,
+) -> impl Iterator<Item = &'a RepresentationUrls> {
+    url_info.representations.iter().filter(move |rep| {
+        rep.period_idx > representation.period_idx && rep.id == representation.id
+    })
+}
+
+/// Duration of a segment in seconds, from the manifest or from the mirrored segment
+/// if the manifest does not give one.
+fn segment_duration(
+    output_directory: &str,
+    base_url: &str,
+    representation: &RepresentationUrls,
+    segment_idx: usize,
+) -> f64 {
+    let segment = &representation.segments[segment_idx];
+    let timescale = representation.timescale.max(1) as f64;
+    if let Some(duration) = segment.duration {
+        return duration as f64 / timescale;
+    }
+    let fragment_duration = layout::read_segment(output_directory, base_url, &segment.url)
+        .ok()
+        .and_then(|data| {
+            isobmff::parse_track_fragments(&data, &[])
+                .first()
+                .map(|fragment| fragment.duration)
+        });
+    match fragment_duration {
+        Some(duration) if duration > 0 => duration as f64 / timescale,
+        _ => {
+            eprintln!(
+                "rep {} : duration of {} not available",
+                representation.id, segment.url
+            );
+            0.0
+        }
+    }
+}
+
+fn relative_uri(base_url: &str, url: &str) -> Result<String, String> {
+    layout::relative_path(base_url, url)
+        .ok_or(format!("url {} is not below base url {}", url, base_url))
+}
+
+/// Media playlist of one representation, with a discontinuity at every period boundary.
+pub fn media_playlist(
+    output_directory: &str,
+    url_info: &UrlInfo,
+    representation: &RepresentationUrls,
+) -> Result<String, String> {
+    let base_url = &url_info.base_url;
+    let mut target_duration = 1;
+    let mut body = String::new();
+    let periods = std::iter::once(representation).chain(continuations(url_info, representation));
+    for (idx, period_representation) in periods.enumerate() {
+        let initialization = period_representation
+            .initialization
+            .as_ref()
+            .ok_or("initialization segment not present")?;
+        if idx > 0 {
+            writeln!(body, "#EXT-X-DISCONTINUITY").unwrap();
+        }
+        writeln!(
+            body,
+            "#EXT-X-MAP:URI=\"{}\"",
+            relative_uri(base_url, initialization)?
+        )
+        .unwrap();
+        for (segment_idx, segment) in period_representation.segments.iter().enumerate() {
+            let duration = segment_duration(
+                output_directory,
+                base_url,
+                period_representation,
+                segment_idx,
+            );
+            target_duration = target_duration.max(duration.round() as u64);
+            writeln!(body, "#EXTINF:{:.6},", duration).unwrap();
+            writeln!(body, "{}", relative_uri(base_url, &segment.url)?).unwrap();
+        }
+    }
+    let mut playlist = String::new();
+    writeln!(playlist, "#EXTM3U").unwrap();
+    writeln!(playlist, "#EXT-X-VERSION:7").unwrap();
+    writeln!(playlist, "#EXT-X-TARGETDURATION:{}", target_duration).unwrap();
+    writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:0").unwrap();
+    writeln!(playlist, "#EXT-X-PLAYLIST-TYPE:VOD").unwrap();
+    playlist.push_str(&body);
+    writeln!(playlist, "#EXT-X-ENDLIST").unwrap();
+    Ok(playlist)
+}
+
+/// DASH frame rates may be fractions such as `30000/1001`.
+fn frame_rate(value: &str) -> Option<f64> {
+    match value.split_once('/') {
+        Some((numerator, denominator)) => {
+            let denominator: f64 = denominator.parse().ok()?;
+            (denominator != 0.0).then_some(numerator.parse::<f64>().ok()? / denominator)
+        }
+        None => value.parse().ok(),
+    }
+}
+
+fn rendition(
+    kind: &str,
+    group_id: &str,
+    name: &str,
+    representation: &RepresentationUrls,
+    default: bool,
+) -> String {
+    let mut line = format!(
+        "#EXT-X-MEDIA:TYPE={},GROUP-ID=\"{}\",NAME=\"{}\"",
+        kind, group_id, name
+    );
+    if let Some(lang) = &representation.lang {
+        write!(line, ",LANGUAGE=\"{}\"", lang).unwrap();
+    }
+    let default = if default { "YES" } else { "NO" };
+    write!(
+        line,
+        ",DEFAULT={},AUTOSELECT=YES,URI=\"{}\"",
+        default,
+        media_playlist_name(representation)
+    )
+    .unwrap();
+    line
+}
+
+/// One rendition per adaptation set, the representation with the highest bandwidth.
+fn renditions<'a>(
+    representations: &[&'a RepresentationUrls],
+    kind: TrackKind,
+) -> Vec<&'a RepresentationUrls> {
+    let mut selected: Vec<&RepresentationUrls> = Vec::new();
+    for representation in representations {
+        if track_kind(representation) != Some(kind) {
+            continue;
+        }
+        match selected
+            .iter_mut()
+            .find(|rep| rep.adaptation_set_idx == representation.adaptation_set_idx)
+        {
+            Some(rep) if rep.bandwidth < representation.bandwidth => *rep = representation,
+            Some(_) => {}
+            None => selected.push(representation),
+        }
+    }
+    selected
+}
+
+fn rendition_name(representation: &RepresentationUrls, used: &mut Vec<String>) -> String {
+    let base = representation
+        .lang
+        .clone()
+        .unwrap_or(representation.id.clone());
+    let mut name = base.clone();
+    let mut suffix = 2;
+    while used.contains(&name) {
+        name = format!("{} {}", base, suffix);
+        suffix += 1;
+    }
+    used.push(name.clone());
+    name
+}
+
+fn stream_inf(
+    representation: &RepresentationUrls,
+    bandwidth: u64,
+    codecs: &[&str],
+    audio: bool,
+    subtitles: bool,
+) -> String {
+    let mut line = format!("#EXT-X-STREAM-INF:BANDWIDTH={}", bandwidth);
+    if !codecs.is_empty() {
+        write!(line, ",CODECS=\"{}\"", codecs.join(",")).unwrap();
+    }
+    if let (Some(width), Some(height)) = (representation.width, representation.height) {
+        write!(line, ",RESOLUTION={}x{}", width, height).unwrap();
+    }
+    if let Some(rate) = representation.frame_rate.as_deref().and_then(frame_rate) {
+        write!(line, ",FRAME-RATE={:.3}", rate).unwrap();
+    }
+    if audio {
+        write!(line, ",AUDIO=\"{}\"", AUDIO_GROUP_ID).unwrap();
+    }
+    if subtitles {
+        write!(line, ",SUBTITLES=\"{}\"", SUBTITLES_GROUP_ID).unwrap();
+    }
+    line
+}
+
+/// Multivariant playlist of the first period. Video representations become variants,
+/// audio and subtitle adaptation sets become renditions. Without video every audio
+/// representation is a variant.
+pub fn multivariant_playlist(representations: &[&RepresentationUrls]) -> String {
+    let videos: Vec<&RepresentationUrls> = representations
+        .iter()
+        .copied()
+        .filter(|rep| track_kind(rep) == Some(TrackKind::Video))
+        .collect();
+    let audios = renditions(representations, TrackKind::Audio);
+    let subtitles = renditions(representations, TrackKind::Subtitles);
+
+    let mut playlist = String::new();
+    writeln!(playlist, "#EXTM3U").unwrap();
+    writeln!(playlist, "#EXT-X-VERSION:7").unwrap();
+    writeln!(playlist, "#EXT-X-INDEPENDENT-SEGMENTS").unwrap();
+    let mut names = Vec::new();
+    if !videos.is_empty() {
+        for (idx, audio) in audios.iter().enumerate() {
+            let name = rendition_name(audio, &mut names);
+            let line = rendition("AUDIO", AUDIO_GROUP_ID, &name, audio, idx == 0);
+            writeln!(playlist, "{}", line).unwrap();
+        }
+    }
+    let mut names = Vec::new();
+    for (idx, subtitle) in subtitles.iter().enumerate() {
+        let name = rendition_name(subtitle, &mut names);
+        let line = rendition("SUBTITLES", SUBTITLES_GROUP_ID, &name, subtitle, idx == 0);
+        writeln!(playlist, "{}", line).unwrap();
+    }
+
+    let unique_codecs = |representations: &[&'_ RepresentationUrls]| {
+        let mut unique: Vec<String> = Vec::new();
+        for codecs in representations.iter().filter_map(|rep| rep.codecs.clone()) {
+            if !unique.contains(&codecs) {
+                unique.push(codecs);
+            }
+        }
+        unique
+    };
+    let audio_codecs = unique_codecs(&audios);
+    let subtitle_codecs = unique_codecs(&subtitles);
+    let audio_bandwidth = audios.iter().map(|audio| audio.bandwidth).max();
+    let with_audio = !videos.is_empty() && audio_bandwidth.is_some();
+    let variants = match videos.is_empty() {
+        true => representations
+            .iter()
+            .copied()
+            .filter(|rep| track_kind(rep) == Some(TrackKind::Audio))
+            .collect(),
+        false => videos,
+    };
+    for variant in &variants {
+        let mut codecs: Vec<&str> = variant.codecs.as_deref().into_iter().collect();
+        let mut bandwidth = variant.bandwidth;
+        if with_audio {
+            codecs.extend(audio_codecs.iter().map(String::as_str));
+            bandwidth += audio_bandwidth.unwrap_or_default();
+        }
+        codecs.extend(subtitle_codecs.iter().map(String::as_str));
+        let line = stream_inf(
+            variant,
+            bandwidth,
+            &codecs,
+            with_audio,
+            !subtitles.is_empty(),
+        );
+        writeln!(playlist, "{}", line).unwrap();
+        writeln!(playlist, "{}", media_playlist_name(variant)).unwrap();
+    }
+    playlist
+}
+
+/// Writes the multivariant playlist and one media playlist per representation of the
+/// first period next to the manifest of a mirror. Returns the number of failures.
+pub fn write_playlists(output_directory: &str) -> usize {
+    let Some((_, url_info)) = layout::read_mirror(output_directory) else {
+        return 1;
+    };
+    let mut failures = 0;
+    let mut written = Vec::new();
+    for representation in url_info
+        .representations
+        .iter()
+        .filter(|rep| rep.period_idx == 0)
+    {
+        if track_kind(representation).is_none() {
+            eprintln!(
+                "rep {} : mime type {} not supported in HLS, skip",
+                representation.id, representation.mime_type
+            );
+            continue;
+        }
+        let path = std::path::Path::new(output_directory).join(media_playlist_name(representation));
+        let result =
+            media_playlist(output_directory, &url_info, representation).and_then(|playlist| {
+                std::fs::write(&path, playlist).map_err(|e| format!("{} : {}", path.display(), e))
+            });
+        match result {
+            Ok(_) => {
+                println!(
+                    "rep {} : media playlist written to {}",
+                    representation.id,
+                    path.display()
+                );
+                written.push(representation);
+            }
+            Err(e) => {
+                eprintln!(
+                    "rep {} : could not write media playlist {}",
+                    representation.id, e
+                );
+                failures += 1;
+            }
+        }
+    }
+    if written.is_empty() {
+        eprintln!("no representation could be converted to HLS");
+        return failures + 1;
+    }
+    let path = std::path::Path::new(output_directory).join(MULTIVARIANT_PLAYLIST_NAME);
+    match std::fs::write(&path, multivariant_playlist(&written)) {
+        Ok(_) => println!("multivariant playlist written to {}", path.display()),
+        Err(e) => {
+            eprintln!("Could not write {} error {}", path.display(), e);
+            failures += 1;
+        }
+    }
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{media_playlist_name, write_playlists};
+    use crate::mpd::RepresentationUrls;
+
+    #[test]
+    fn media_playlist_names() {
+        let name = |id: &str| {
+            media_playlist_name(&RepresentationUrls {
+                id: id.to_string(),
+                ..Default::default()
+            })
+        };
+        assert_eq!(name("v1"), "v1.m3u8");
+        assert_eq!(name("audio/en"), "audio_en.m3u8");
+        assert_eq!(name("master"), "_master.m3u8");
+        assert_eq!(name("Master"), "_Master.m3u8");
+        assert_eq!(name("_master"), "__master.m3u8");
+        assert_eq!(name("masters"), "masters.m3u8");
+    }
+
+    #[test]
+    fn write_playlists_test() {
+        let directory =
+            std::env::temp_dir().join(format!("dash-mirror-hls-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join("manifest.mpd"),
+            r#"<MPD type="static" mediaPresentationDuration="PT4S">
+            <Period duration="PT4S">
+            <AdaptationSet mimeType="video/mp4" frameRate="30000/1001">
+            <SegmentTemplate timescale="1000" media="video/$RepresentationID$/$Time$.m4s" initialization="video/$RepresentationID$/init.mp4">
+            <SegmentTimeline><S t="0" d="2000"/><S d="1500"/></SegmentTimeline>
+            </SegmentTemplate>
+            <Representation id="v1" bandwidth="1000000" codecs="avc1.64001f" width="1280" height="720"/>
+            <Representation id="v2" bandwidth="3000000" codecs="avc1.640028" width="1920" height="1080"/>
+            </AdaptationSet>
+            <AdaptationSet mimeType="audio/mp4" lang="en" codecs="mp4a.40.2">
+            <SegmentTemplate timescale="48000" duration="96000" media="audio/en/$Number$.m4s" initialization="audio/en/init.mp4"/>
+            <Representation id="a_en" bandwidth="128000"/>
+            </AdaptationSet>
+            <AdaptationSet mimeType="audio/mp4" lang="fr" codecs="mp4a.40.2">
+            <SegmentTemplate timescale="48000" duration="96000" media="audio/fr/$Number$.m4s" initialization="audio/fr/init.mp4"/>
+            <Representation id="a_fr" bandwidth="96000"/>
+            </AdaptationSet>
+            <AdaptationSet mimeType="application/mp4" lang="de" codecs="stpp">
+            <SegmentTemplate timescale="1000" duration="2000" media="text/$Number$.m4s" initialization="text/init.mp4"/>
+            <Representation id="t_de" bandwidth="1000"/>
+            </AdaptationSet>
+            </Period>
+            </MPD>"#,
+        )
+        .unwrap();
+
+        assert_eq!(write_playlists(directory.to_str().unwrap()), 0);
+        let multivariant = std::fs::read_to_string(directory.join("master.m3u8")).unwrap();
+        assert!(multivariant.contains(
+            "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME=\"en\",LANGUAGE=\"en\",DEFAULT=YES,AUTOSELECT=YES,URI=\"a_en.m3u8\""
+        ));
+        assert!(multivariant.contains("NAME=\"fr\",LANGUAGE=\"fr\",DEFAULT=NO"));
+        assert!(multivariant.contains(
+            "#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",NAME=\"de\",LANGUAGE=\"de\",DEFAULT=YES,AUTOSELECT=YES,URI=\"t_de.m3u8\""
+        ));
+        assert!(multivariant.contains(
+            "#EXT-X-STREAM-INF:BANDWIDTH=3128000,CODECS=\"avc1.640028,mp4a.40.2,stpp\",RESOLUTION=1920x1080,FRAME-RATE=29.970,AUDIO=\"audio\",SUBTITLES=\"subs\"\nv2.m3u8\n"
+        ));
+
+        let media = std::fs::read_to_string(directory.join("v1.m3u8")).unwrap();
+        assert_eq!(
+            media,
+            "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-MAP:URI=\"video/v1/init.mp4\"\n\
+             #EXTINF:2.000000,\nvideo/v1/0.m4s\n#EXTINF:1.500000,\nvideo/v1/2000.m4s\n\
+             #EXT-X-ENDLIST\n"
+        );
+        let audio = std::fs::read_to_string(directory.join("a_en.m3u8")).unwrap();
+        assert!(audio.contains("#EXTINF:2.000000,\naudio/en/2.m4s\n"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::layout;
+use crate::serve::{self, Request, Response};
+use crate::util::Rng;
+
+/// Bandwidth in effect from `at` seconds after the server started.
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+pub struct BandwidthStep {
+    pub at: f64,
+    pub kbps: u64,
+}
+
+/// Impairments applied to the requests a rule matches. A rule without targets matches
+/// every request.
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    /// Request paths starting with this prefix, e.g. `/manifest.mpd` or `/video/`.
+    pub path_prefix: Option<String>,
+    pub representation: Option<String>,
+    pub first_segment: Option<u64>,
+    pub last_segment: Option<u64>,
+    /// Delay before the response is sent.
+    pub latency_ms: u64,
+    pub bandwidth_kbps: Option<u64>,
+    /// Bandwidth over time, overrides `bandwidth_kbps` once the first step is reached.
+    pub bandwidth_schedule: Vec<BandwidthStep>,
+    /// Length of the schedule in seconds after which it starts again.
+    pub schedule_period: Option<f64>,
+    pub error_404_rate: f64,
+    pub error_500_rate: f64,
+    /// Rate of responses which announce the full length but close after part of the body.
+    pub truncate_rate: f64,
+    /// Rate of responses which stop sending halfway and hold the connection.
+    pub stall_rate: f64,
+    /// How long a stalled connection is held before it is closed.
+    pub stall_ms: Option<u64>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImpairmentConfig {
+    pub seed: Option<u64>,
+    /// The first matching rule applies to a request.
+    pub rules: Vec<Rule>,
+}
+
+const DEFAULT_STALL_MS: u64 = 60_000;
+
+/// Representation id and segment number of a requested file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Target {
+    pub representation: Option<String>,
+    pub segment_number: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Fault {
+    NotFound,
+    ServerError,
+    /// Only this fraction of the body is sent.
+    Truncate(f64),
+    Stall,
+}
+
+pub struct Impairments {
+    config: ImpairmentConfig,
+    rng: Mutex<Rng>,
+    start: Instant,
+    /// Mirror paths of media segments, mapped to their representation and number.
+    targets: HashMap<String, Target>,
+}
+
+impl Rule {
+    fn matches(&self, path: &str, target: &Target) -> bool {
+        if let Some(prefix) = &self.path_prefix {
+            if !path.starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+        if self.representation.is_some() && self.representation != target.representation {
+            return false;
+        }
+        if self.first_segment.is_some() || self.last_segment.is_some() {
+            let Some(number) = target.segment_number else {
+                return false;
+            };
+            if self.first_segment.is_some_and(|first| number < first)
+                || self.last_segment.is_some_and(|last| number > last)
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Bandwidth in bytes per second `elapsed` seconds after the server started.
+    pub fn bandwidth(&self, elapsed: f64) -> Option<u64> {
+        let elapsed = match self.schedule_period {
+            Some(period) if period > 0.0 => elapsed % period,
+            _ => elapsed,
+        };
+        let kbps = self
+            .bandwidth_schedule
+            .iter()
+            .filter(|step| step.at <= elapsed)
+            .max_by(|a, b| a.at.total_cmp(&b.at))
+            .map(|step| step.kbps)
+            .or(self.bandwidth_kbps)?;
+        Some(kbps * 1000 / 8)
+    }
+}
+
+impl Impairments {
+    pub fn new(config: ImpairmentConfig, targets: HashMap<String, Target>) -> Impairments {
+        let seed = config.seed.unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64
+        });
+        println!("impairment seed {}", seed);
+        Impairments {
+            config,
+            rng: Mutex::new(Rng(seed)),
+            start: Instant::now(),
+            targets,
+        }
+    }
+
+    /// Reads a JSON rule file and indexes the media segments of the mirror in `root`.
+    pub fn load(path: &str, root: &str) -> Result<Impairments, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{} : {}", path, e))?;
+        let config: ImpairmentConfig =
+            serde_json::from_str(&text).map_err(|e| format!("{} : {}", path, e))?;
+        Ok(Impairments::new(config, mirror_targets(root)))
+    }
+
+    /// Target of a request to the static mirror.
+    pub fn target(&self, path: &str) -> Target {
+        self.targets.get(path).cloned().unwrap_or_default()
+    }
+
+    pub fn rule(&self, path: &str, target: &Target) -> Option<&Rule> {
+        self.config
+            .rules
+            .iter()
+            .find(|rule| rule.matches(path, target))
+    }
+
+    /// Draws the fault, if any, for one request.
+    pub fn fault(&self, rule: &Rule) -> Option<Fault> {
+        let mut rng = self.rng.lock().unwrap();
+        let roll = rng.next_f64();
+        let mut threshold = rule.error_404_rate;
+        if roll < threshold {
+            return Some(Fault::NotFound);
+        }
+        threshold += rule.error_500_rate;
+        if roll < threshold {
+            return Some(Fault::ServerError);
+        }
+        threshold += rule.truncate_rate;
+        if roll < threshold {
+            return Some(Fault::Truncate(rng.next_f64()));
+        }
+        threshold += rule.stall_rate;
+        if roll < threshold {
+            return Some(Fault::Stall);
+        }
+        None
+    }
+
+    /// Sends `response` with the impairments of `rule`. Returns the status which was sent
+    /// and a short description of the fault.
+    pub fn send(
+        &self,
+        writer: &mut impl Write,
+        request: &Request,
+        response: Response,
+        rule: &Rule,
+    ) -> std::io::Result<(u16, Option<String>)> {
+        std::thread::sleep(Duration::from_millis(rule.latency_ms));
+        let fault = self.fault(rule);
+        let (response, body_length) = match fault {
+            Some(Fault::NotFound) => (Response::error(404), None),
+            Some(Fault::ServerError) => (Response::error(500), None),
+            Some(Fault::Truncate(fraction)) => {
+                let length = (response.content_length() as f64 * fraction) as u64;
+                (response, Some(length))
+            }
+            Some(Fault::Stall) => {
+                let length = response.content_length() / 2;
+                (response, Some(length))
+            }
+            None => (response, None),
+        };
+        writer.write_all(serve::response_head(&response).as_bytes())?;
+        if request.method != "HEAD" {
+            let body_length = body_length.unwrap_or(response.content_length());
+            self.write_throttled(writer, &mut response.body_reader().take(body_length), rule)?;
+        }
+        writer.flush()?;
+        if fault == Some(Fault::Stall) {
+            std::thread::sleep(Duration::from_millis(
+                rule.stall_ms.unwrap_or(DEFAULT_STALL_MS),
+            ));
+        }
+        let description = fault.map(|fault| match fault {
+            Fault::NotFound => "injected 404".to_string(),
+            Fault::ServerError => "injected 500".to_string(),
+            Fault::Truncate(fraction) => format!("truncated to {:.0}%", fraction * 100.0),
+            Fault::Stall => "stalled".to_string(),
+        });
+        Ok((response.status, description))
+    }
+
+    /// Writes `body` without exceeding the bandwidth of `rule`, which may change while
+    /// it is sent.
+    fn write_throttled(
+        &self,
+        writer: &mut impl Write,
+        body: &mut impl Read,
+        rule: &Rule,
+    ) -> std::io::Result<()> {
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let Some(rate) = rule.bandwidth(self.start.elapsed().as_secs_f64()) else {
+                return std::io::copy(body, writer).map(|_| ());
+            };
+            let rate = rate.max(1) as f64;
+            let chunk_size = ((rate / 20.0) as usize).clamp(512, buffer.len());
+            let chunk_started = Instant::now();
+            let length = body.read(&mut buffer[..chunk_size])?;
+            if length == 0 {
+                return Ok(());
+            }
+            writer.write_all(&buffer[..length])?;
+            writer.flush()?;
+            let wait = length as f64 / rate - chunk_started.elapsed().as_secs_f64();
+            if wait > 0.0 {
+                std::thread::sleep(Duration::from_secs_f64(wait));
+            }
+        }
+    }
+}
+
+/// Maps the request path of every media segment of a mirror to its representation and
+/// segment number.
+pub fn mirror_targets(root: &str) -> HashMap<String, Target> {
+    let mut targets = HashMap::new();
+    let Some((_, url_info)) = layout::read_mirror(root) else {
+        return targets;
+    };
+    for representation in &url_info.representations {
+        if let Some(path) = representation
+            .initialization
+            .as_ref()
+            .and_then(|url| layout::relative_path(&url_info.base_url, url))
+        {
+            let target = Target {
+                representation: Some(representation.id.clone()),
+                segment_number: None,
+            };
+            targets.insert(format!("/{}", path), target);
+        }
+        for segment in &representation.segments {
+            if let Some(path) = layout::relative_path(&url_info.base_url, &segment.url) {
+                let target = Target {
+                    representation: Some(representation.id.clone()),
+                    segment_number: Some(segment.number),
+                };
+                targets.insert(format!("/{}", path), target);
+            }
+        }
+    }
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BandwidthStep, Fault, ImpairmentConfig, Impairments, Rule, Target};
+    use crate::serve::{Request, Response};
+    use std::collections::HashMap;
+
+    fn target(representation: &str, number: u64) -> Target {
+        Target {
+            representation: Some(representation.to_string()),
+            segment_number: Some(number),
+        }
+    }
+
+    #[test]
+    fn rules_and_faults() {
+        let config: ImpairmentConfig = serde_json::from_str(
+            r#"{"seed": 7, "rules": [
+                {"representation": "v1", "first_segment": 3, "last_segment": 4, "error_404_rate": 1.0},
+                {"path_prefix": "/audio/", "truncate_rate": 1.0},
+                {"bandwidth_kbps": 800, "bandwidth_schedule": [{"at": 10, "kbps": 80}], "schedule_period": 20}
+            ]}"#,
+        )
+        .unwrap();
+        let mut targets = HashMap::new();
+        targets.insert("/video/3.m4s".to_string(), target("v1", 3));
+        let impairments = Impairments::new(config, targets);
+
+        let video = impairments.target("/video/3.m4s");
+        assert_eq!(video, target("v1", 3));
+        let rule = impairments.rule("/video/3.m4s", &video).unwrap();
+        assert_eq!(impairments.fault(rule), Some(Fault::NotFound));
+        let rule = impairments.rule("/video/5.m4s", &target("v1", 5)).unwrap();
+        assert_eq!(rule.bandwidth(5.0), Some(100_000));
+        assert_eq!(rule.bandwidth(15.0), Some(10_000));
+        assert_eq!(rule.bandwidth(25.0), Some(100_000));
+        assert_eq!(impairments.fault(rule), None);
+
+        let rule = impairments
+            .rule("/audio/1.m4s", &Target::default())
+            .unwrap();
+        let request = Request {
+            method: "GET".to_string(),
+            path: "/audio/1.m4s".to_string(),
+            query: None,
+            headers: Vec::new(),
+        };
+        let response = Response::new(200, "video/iso.segment", vec![7; 1000]);
+        let mut out = Vec::new();
+        let (status, fault) = impairments
+            .send(&mut out, &request, response, rule)
+            .unwrap();
+        assert_eq!(status, 200);
+        assert!(fault.unwrap().starts_with("truncated"));
+        let text = String::from_utf8_lossy(&out);
+        let head_end = text.find("\r\n\r\n").unwrap() + 4;
+        assert!(text.contains("Content-Length: 1000\r\n"));
+        assert!(out.len() - head_end < 1000);
+    }
+
+    #[test]
+    fn throttled_write() {
+        let rule = Rule {
+            bandwidth_schedule: vec![BandwidthStep { at: 0.0, kbps: 800 }],
+            ..Default::default()
+        };
+        let impairments = Impairments::new(ImpairmentConfig::default(), HashMap::new());
+        let started = std::time::Instant::now();
+        let mut out = Vec::new();
+        impairments
+            .write_throttled(&mut out, &mut &[1; 20_000][..], &rule)
+            .unwrap();
+        assert_eq!(out.len(), 20_000);
+        // 20 kB at 100 kB/s
+        assert!(started.elapsed().as_secs_f64() >= 0.15);
+    }
+}
@@ -0,0 +1,699 @@
+pub type FourCC = [u8; 4];
+
+#[derive(Clone, Copy, Debug)]
+pub struct BoxHeader {
+    pub box_type: FourCC,
+    pub offset: usize,
+    pub header_size: usize,
+    pub size: usize,
+}
+
+impl BoxHeader {
+    pub fn payload_offset(&self) -> usize {
+        self.offset + self.header_size
+    }
+
+    pub fn end(&self) -> usize {
+        self.offset + self.size
+    }
+
+    pub fn payload<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        &data[self.payload_offset()..self.end()]
+    }
+}
+
+pub fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+pub fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+pub fn read_u64(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+pub fn read_box_header(data: &[u8], offset: usize) -> Option<BoxHeader> {
+    let size = read_u32(data, offset)?;
+    let box_type: FourCC = data.get(offset + 4..offset + 8)?.try_into().ok()?;
+    let (header_size, size) = match size {
+        0 => (8, data.len() - offset),
+        1 => (16, read_u64(data, offset + 8)? as usize),
+        size => (8, size as usize),
+    };
+    if size < header_size || offset.checked_add(size)? > data.len() {
+        return None;
+    }
+    Some(BoxHeader {
+        box_type,
+        offset,
+        header_size,
+        size,
+    })
+}
+
+/// Lists the boxes found back to back in `data[start..end]`.
+pub fn boxes_in(data: &[u8], start: usize, end: usize) -> Vec<BoxHeader> {
+    let mut boxes = Vec::new();
+    let mut pos = start;
+    while pos + 8 <= end {
+        match read_box_header(&data[..end], pos) {
+            Some(header) => {
+                pos = header.end();
+                boxes.push(header);
+            }
+            None => {
+                eprintln!("Invalid box at offset {}", pos);
+                break;
+            }
+        }
+    }
+    boxes
+}
+
+pub fn top_level_boxes(data: &[u8]) -> Vec<BoxHeader> {
+    boxes_in(data, 0, data.len())
+}
+
+pub fn children(data: &[u8], parent: &BoxHeader) -> Vec<BoxHeader> {
+    boxes_in(data, parent.payload_offset(), parent.end())
+}
+
+pub fn find_child(data: &[u8], parent: &BoxHeader, box_type: &FourCC) -> Option<BoxHeader> {
+    children(data, parent)
+        .into_iter()
+        .find(|child| &child.box_type == box_type)
+}
+
+/// Follows `path` from the top level boxes, returning the first match.
+pub fn find_path(data: &[u8], path: &[&FourCC]) -> Option<BoxHeader> {
+    let (first, rest) = path.split_first()?;
+    let mut current = top_level_boxes(data)
+        .into_iter()
+        .find(|header| &header.box_type == *first)?;
+    for box_type in rest {
+        current = find_child(data, &current, box_type)?;
+    }
+    Some(current)
+}
+
+/// Number of bytes that precede the child boxes in the payload of a container box,
+/// `None` for boxes which are not containers.
+fn container_prefix_size(box_type: &FourCC, payload: &[u8]) -> Option<usize> {
+    match box_type {
+        b"moov" | b"trak" | b"mdia" | b"minf" | b"stbl" | b"dinf" | b"edts" | b"mvex" | b"moof"
+        | b"traf" | b"sinf" | b"schi" | b"udta" | b"mfra" => Some(0),
+        b"meta" => Some(4),
+        b"stsd" => Some(8),
+        b"encv" | b"avc1" | b"avc3" | b"hvc1" | b"hev1" | b"vp09" | b"av01" | b"dvh1" | b"dvhe" => {
+            Some(78)
+        }
+        b"enca" | b"mp4a" | b"ac-3" | b"ec-3" | b"ac-4" | b"Opus" | b"fLaC" => {
+            match read_u16(payload, 8) {
+                Some(1) => Some(44),
+                Some(2) => Some(64),
+                _ => Some(28),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Box tree used to rewrite boxes whose size changes.
+#[derive(Clone, Debug)]
+pub struct Mp4Node {
+    pub box_type: FourCC,
+    /// Payload of a leaf box, or the bytes preceding the children of a container box.
+    pub data: Vec<u8>,
+    pub children: Vec<Mp4Node>,
+}
+
+impl Mp4Node {
+    pub fn new(box_type: &FourCC, data: Vec<u8>) -> Mp4Node {
+        Mp4Node {
+            box_type: *box_type,
+            data,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn child(&self, box_type: &FourCC) -> Option<&Mp4Node> {
+        self.children
+            .iter()
+            .find(|child| &child.box_type == box_type)
+    }
+
+    pub fn child_mut(&mut self, box_type: &FourCC) -> Option<&mut Mp4Node> {
+        self.children
+            .iter_mut()
+            .find(|child| &child.box_type == box_type)
+    }
+
+    pub fn size(&self) -> usize {
+        let children_size: usize = self.children.iter().map(|child| child.size()).sum();
+        let payload_size = self.data.len() + children_size;
+        match payload_size + 8 > u32::MAX as usize {
+            true => payload_size + 16,
+            false => payload_size + 8,
+        }
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let size = self.size();
+        if size > u32::MAX as usize {
+            out.extend(1u32.to_be_bytes());
+            out.extend(self.box_type);
+            out.extend((size as u64).to_be_bytes());
+        } else {
+            out.extend((size as u32).to_be_bytes());
+            out.extend(self.box_type);
+        }
+        out.extend(&self.data);
+        for child in &self.children {
+            child.write(out);
+        }
+    }
+}
+
+fn parse_node(data: &[u8], header: &BoxHeader) -> Mp4Node {
+    let payload = header.payload(data);
+    match container_prefix_size(&header.box_type, payload) {
+        Some(prefix_size) if prefix_size <= payload.len() => Mp4Node {
+            box_type: header.box_type,
+            data: payload[..prefix_size].to_vec(),
+            children: boxes_in(data, header.payload_offset() + prefix_size, header.end())
+                .iter()
+                .map(|child| parse_node(data, child))
+                .collect(),
+        },
+        _ => Mp4Node::new(&header.box_type, payload.to_vec()),
+    }
+}
+
+pub fn parse_tree(data: &[u8]) -> Vec<Mp4Node> {
+    top_level_boxes(data)
+        .iter()
+        .map(|header| parse_node(data, header))
+        .collect()
+}
+
+pub fn write_tree(nodes: &[Mp4Node]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for node in nodes {
+        node.write(&mut out);
+    }
+    out
+}
+
+/// Overwrites the type of a box, keeping its size, so readers skip it.
+pub fn replace_with_free(data: &mut [u8], header: &BoxHeader) {
+    data[header.offset + 4..header.offset + 8].copy_from_slice(b"free");
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TrackExtends {
+    pub track_id: u32,
+    pub default_sample_description_index: u32,
+    pub default_sample_duration: u32,
+    pub default_sample_size: u32,
+    pub default_sample_flags: u32,
+}
+
+pub fn parse_trex(payload: &[u8]) -> Option<TrackExtends> {
+    Some(TrackExtends {
+        track_id: read_u32(payload, 4)?,
+        default_sample_description_index: read_u32(payload, 8)?,
+        default_sample_duration: read_u32(payload, 12)?,
+        default_sample_size: read_u32(payload, 16)?,
+        default_sample_flags: read_u32(payload, 20)?,
+    })
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TrackFragmentHeader {
+    pub track_id: u32,
+    pub base_data_offset: Option<u64>,
+    pub sample_description_index: Option<u32>,
+    pub default_sample_duration: Option<u32>,
+    pub default_sample_size: Option<u32>,
+    pub default_sample_flags: Option<u32>,
+}
+
+pub fn parse_tfhd(payload: &[u8]) -> Option<TrackFragmentHeader> {
+    let flags = read_u32(payload, 0)? & 0xffffff;
+    let mut tfhd = TrackFragmentHeader {
+        track_id: read_u32(payload, 4)?,
+        ..Default::default()
+    };
+    let mut pos = 8;
+    if flags & 0x1 != 0 {
+        tfhd.base_data_offset = Some(read_u64(payload, pos)?);
+        pos += 8;
+    }
+    if flags & 0x2 != 0 {
+        tfhd.sample_description_index = Some(read_u32(payload, pos)?);
+        pos += 4;
+    }
+    if flags & 0x8 != 0 {
+        tfhd.default_sample_duration = Some(read_u32(payload, pos)?);
+        pos += 4;
+    }
+    if flags & 0x10 != 0 {
+        tfhd.default_sample_size = Some(read_u32(payload, pos)?);
+        pos += 4;
+    }
+    if flags & 0x20 != 0 {
+        tfhd.default_sample_flags = Some(read_u32(payload, pos)?);
+    }
+    Some(tfhd)
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TrackRunSample {
+    pub duration: Option<u32>,
+    pub size: Option<u32>,
+    pub flags: Option<u32>,
+    pub composition_time_offset: Option<i64>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TrackRun {
+    pub version: u8,
+    pub data_offset: Option<i32>,
+    pub first_sample_flags: Option<u32>,
+    pub samples: Vec<TrackRunSample>,
+}
+
+pub fn parse_trun(payload: &[u8]) -> Option<TrackRun> {
+    let version = *payload.first()?;
+    let flags = read_u32(payload, 0)? & 0xffffff;
+    let sample_count = read_u32(payload, 4)?;
+    let mut trun = TrackRun {
+        version,
+        ..Default::default()
+    };
+    let mut pos = 8;
+    if flags & 0x1 != 0 {
+        trun.data_offset = Some(read_u32(payload, pos)? as i32);
+        pos += 4;
+    }
+    if flags & 0x4 != 0 {
+        trun.first_sample_flags = Some(read_u32(payload, pos)?);
+        pos += 4;
+    }
+    for _ in 0..sample_count {
+        let mut sample = TrackRunSample::default();
+        if flags & 0x100 != 0 {
+            sample.duration = Some(read_u32(payload, pos)?);
+            pos += 4;
+        }
+        if flags & 0x200 != 0 {
+            sample.size = Some(read_u32(payload, pos)?);
+            pos += 4;
+        }
+        if flags & 0x400 != 0 {
+            sample.flags = Some(read_u32(payload, pos)?);
+            pos += 4;
+        }
+        if flags & 0x800 != 0 {
+            let offset = read_u32(payload, pos)?;
+            sample.composition_time_offset = Some(match version {
+                0 => offset as i64,
+                _ => offset as i32 as i64,
+            });
+            pos += 4;
+        }
+        trun.samples.push(sample);
+    }
+    Some(trun)
+}
+
+pub fn parse_tfdt(payload: &[u8]) -> Option<u64> {
+    match payload.first()? {
+        1 => read_u64(payload, 4),
+        _ => read_u32(payload, 4).map(|time| time as u64),
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TrackInit {
+    pub track_id: u32,
+    pub timescale: u32,
+    pub handler_type: FourCC,
+    pub trex: Option<TrackExtends>,
+}
+
+fn parse_mdhd_timescale(payload: &[u8]) -> Option<u32> {
+    match payload.first()? {
+        1 => read_u32(payload, 20),
+        _ => read_u32(payload, 12),
+    }
+}
+
+/// Collects track id, timescale, handler and fragment defaults from an init segment.
+pub fn parse_init_tracks(data: &[u8]) -> Vec<TrackInit> {
+    let mut tracks = Vec::new();
+    let Some(moov) = find_path(data, &[b"moov"]) else {
+        return tracks;
+    };
+    let mut trexs = Vec::new();
+    if let Some(mvex) = find_child(data, &moov, b"mvex") {
+        for child in children(data, &mvex) {
+            if &child.box_type == b"trex" {
+                trexs.extend(parse_trex(child.payload(data)));
+            }
+        }
+    }
+    for trak in children(data, &moov) {
+        if &trak.box_type != b"trak" {
+            continue;
+        }
+        let mut track = TrackInit::default();
+        if let Some(tkhd) = find_child(data, &trak, b"tkhd") {
+            let payload = tkhd.payload(data);
+            let track_id = match payload.first() {
+                Some(1) => read_u32(payload, 20),
+                _ => read_u32(payload, 12),
+            };
+            track.track_id = track_id.unwrap_or_default();
+        }
+        if let Some(mdia) = find_child(data, &trak, b"mdia") {
+            if let Some(mdhd) = find_child(data, &mdia, b"mdhd") {
+                track.timescale = parse_mdhd_timescale(mdhd.payload(data)).unwrap_or_default();
+            }
+            if let Some(hdlr) = find_child(data, &mdia, b"hdlr") {
+                if let Some(handler_type) = hdlr.payload(data).get(8..12) {
+                    track.handler_type = handler_type.try_into().unwrap_or_default();
+                }
+            }
+        }
+        track.trex = trexs
+            .iter()
+            .find(|trex| trex.track_id == track.track_id)
+            .copied();
+        tracks.push(track);
+    }
+    tracks
+}
+
+/// Sample of a track run with the defaults of `tfhd` and `trex` applied.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SampleInfo {
+    pub size: u32,
+    pub duration: u32,
+    pub flags: u32,
+    pub composition_time_offset: i64,
+}
+
+impl SampleInfo {
+    pub fn is_sync(&self) -> bool {
+        // sample_is_non_sync_sample
+        self.flags & 0x10000 == 0
+    }
+}
+
+/// Samples of one `trun`, stored contiguously from `data_offset` of the segment.
+#[derive(Clone, Debug, Default)]
+pub struct SampleRun {
+    pub data_offset: usize,
+    pub samples: Vec<SampleInfo>,
+}
+
+impl SampleRun {
+    pub fn data_size(&self) -> usize {
+        self.samples.iter().map(|sample| sample.size as usize).sum()
+    }
+}
+
+/// One `traf` of a media segment.
+#[derive(Clone, Debug, Default)]
+pub struct TrackFragment {
+    pub track_id: u32,
+    pub base_media_decode_time: Option<u64>,
+    pub sample_count: usize,
+    pub duration: u64,
+    pub starts_with_sync_sample: bool,
+    pub runs: Vec<SampleRun>,
+}
+
+/// Parses a `traf` of `moof`, resolving the absolute offsets of its sample runs.
+pub fn parse_traf(
+    data: &[u8],
+    moof: &BoxHeader,
+    traf: &BoxHeader,
+    defaults: &[TrackInit],
+) -> Option<TrackFragment> {
+    let tfhd = find_child(data, traf, b"tfhd").and_then(|tfhd| parse_tfhd(tfhd.payload(data)))?;
+    let trex = defaults
+        .iter()
+        .find(|track| track.track_id == tfhd.track_id)
+        .and_then(|track| track.trex)
+        .unwrap_or_default();
+    let default_duration = tfhd
+        .default_sample_duration
+        .unwrap_or(trex.default_sample_duration);
+    let default_size = tfhd.default_sample_size.unwrap_or(trex.default_sample_size);
+    let default_flags = tfhd
+        .default_sample_flags
+        .unwrap_or(trex.default_sample_flags);
+    let mut fragment = TrackFragment {
+        track_id: tfhd.track_id,
+        base_media_decode_time: find_child(data, traf, b"tfdt")
+            .and_then(|tfdt| parse_tfdt(tfdt.payload(data))),
+        ..Default::default()
+    };
+    let base = tfhd.base_data_offset.unwrap_or(moof.offset as u64) as i64;
+    let mut data_offset = base as usize;
+    for child in children(data, traf) {
+        if &child.box_type != b"trun" {
+            continue;
+        }
+        let Some(trun) = parse_trun(child.payload(data)) else {
+            eprintln!("Could not parse trun of track {}", tfhd.track_id);
+            continue;
+        };
+        if let Some(offset) = trun.data_offset {
+            data_offset = (base + offset as i64) as usize;
+        }
+        let mut run = SampleRun {
+            data_offset,
+            samples: Vec::with_capacity(trun.samples.len()),
+        };
+        for (sample_idx, sample) in trun.samples.iter().enumerate() {
+            let flags = match (sample_idx, trun.first_sample_flags) {
+                (0, Some(first_sample_flags)) => first_sample_flags,
+                _ => sample.flags.unwrap_or(default_flags),
+            };
+            run.samples.push(SampleInfo {
+                size: sample.size.unwrap_or(default_size),
+                duration: sample.duration.unwrap_or(default_duration),
+                flags,
+                composition_time_offset: sample.composition_time_offset.unwrap_or_default(),
+            });
+        }
+        data_offset += run.data_size();
+        if fragment.sample_count == 0 {
+            fragment.starts_with_sync_sample = run.samples.first().is_none_or(SampleInfo::is_sync);
+        }
+        fragment.sample_count += run.samples.len();
+        fragment.duration += run
+            .samples
+            .iter()
+            .map(|sample| sample.duration as u64)
+            .sum::<u64>();
+        fragment.runs.push(run);
+    }
+    Some(fragment)
+}
+
+/// Lists the track fragments of all `moof` boxes in a media segment. Sample fields
+/// missing in `trun` are taken from `tfhd`, then from the `trex` of the init segment.
+pub fn parse_track_fragments(data: &[u8], defaults: &[TrackInit]) -> Vec<TrackFragment> {
+    let mut fragments = Vec::new();
+    for moof in top_level_boxes(data) {
+        if &moof.box_type != b"moof" {
+            continue;
+        }
+        for traf in children(data, &moof) {
+            if &traf.box_type != b"traf" {
+                continue;
+            }
+            match parse_traf(data, &moof, &traf, defaults) {
+                Some(fragment) => fragments.push(fragment),
+                None => eprintln!("tfhd box not found in traf"),
+            }
+        }
+    }
+    fragments
+}
+
+pub struct SidxReference {
+    pub referenced_size: u32,
+    pub subsegment_duration: u32,
+    pub starts_with_sap: bool,
+}
+
+/// Builds a version 1 `sidx` box indexing media subsegments which directly follow it.
+pub fn build_sidx(
+    reference_id: u32,
+    timescale: u32,
+    earliest_presentation_time: u64,
+    references: &[SidxReference],
+) -> Vec<u8> {
+    let mut payload = 0x01000000u32.to_be_bytes().to_vec();
+    payload.extend(reference_id.to_be_bytes());
+    payload.extend(timescale.to_be_bytes());
+    payload.extend(earliest_presentation_time.to_be_bytes());
+    payload.extend(0u64.to_be_bytes());
+    payload.extend(0u16.to_be_bytes());
+    payload.extend((references.len() as u16).to_be_bytes());
+    for reference in references {
+        payload.extend((reference.referenced_size & 0x7fffffff).to_be_bytes());
+        payload.extend(reference.subsegment_duration.to_be_bytes());
+        let sap: u32 = match reference.starts_with_sap {
+            true => 0x90000000,
+            false => 0,
+        };
+        payload.extend(sap.to_be_bytes());
+    }
+    let node = Mp4Node::new(b"sidx", payload);
+    let mut out = Vec::new();
+    node.write(&mut out);
+    out
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{parse_tree, top_level_boxes, write_tree};
+
+    pub fn make_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut out = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend(box_type);
+        out.extend(payload);
+        out
+    }
+
+    pub fn full_box(box_type: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+        let mut data = ((version as u32) << 24 | flags).to_be_bytes().to_vec();
+        data.extend(payload);
+        make_box(box_type, &data)
+    }
+
+    /// Init segment of a single fragmented track.
+    pub fn init_segment(track_id: u32, timescale: u32, handler_type: &[u8; 4]) -> Vec<u8> {
+        let mut mvhd = [0u8; 96];
+        mvhd[8..12].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[92..96].copy_from_slice(&(track_id + 1).to_be_bytes());
+        let mut tkhd = [0u8; 80];
+        tkhd[8..12].copy_from_slice(&track_id.to_be_bytes());
+        let mut mdhd = [0u8; 20];
+        mdhd[8..12].copy_from_slice(&timescale.to_be_bytes());
+        let mut hdlr = [0u8; 21];
+        hdlr[4..8].copy_from_slice(handler_type);
+        let mut stsd = 1u32.to_be_bytes().to_vec();
+        stsd.extend(match handler_type {
+            b"vide" => make_box(b"avc1", &[0; 78]),
+            _ => make_box(b"mp4a", &[0; 28]),
+        });
+        let mut stbl = full_box(b"stsd", 0, 0, &stsd);
+        stbl.extend(full_box(b"stts", 0, 0, &[0; 4]));
+        stbl.extend(full_box(b"stsc", 0, 0, &[0; 4]));
+        stbl.extend(full_box(b"stsz", 0, 0, &[0; 8]));
+        stbl.extend(full_box(b"stco", 0, 0, &[0; 4]));
+        let mut mdia = full_box(b"mdhd", 0, 0, &mdhd);
+        mdia.extend(full_box(b"hdlr", 0, 0, &hdlr));
+        mdia.extend(make_box(b"minf", &make_box(b"stbl", &stbl)));
+        let mut trak = full_box(b"tkhd", 0, 3, &tkhd);
+        trak.extend(make_box(b"mdia", &mdia));
+        let mut trex = track_id.to_be_bytes().to_vec();
+        trex.extend([0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let mut moov = full_box(b"mvhd", 0, 0, &mvhd);
+        moov.extend(make_box(b"trak", &trak));
+        moov.extend(make_box(b"mvex", &full_box(b"trex", 0, 0, &trex)));
+        let mut init = make_box(b"ftyp", b"iso6\0\0\0\0");
+        init.extend(make_box(b"moov", &moov));
+        init
+    }
+
+    /// Media segment with one `moof` holding `samples` of equal `duration`.
+    pub fn media_segment(
+        track_id: u32,
+        decode_time: u64,
+        duration: u32,
+        samples: &[&[u8]],
+    ) -> Vec<u8> {
+        media_segment_with(track_id, decode_time, duration, samples, &[])
+    }
+
+    /// Like `media_segment`, with `traf_boxes` added to the `traf` before the `trun`.
+    pub fn media_segment_with(
+        track_id: u32,
+        decode_time: u64,
+        duration: u32,
+        samples: &[&[u8]],
+        traf_boxes: &[u8],
+    ) -> Vec<u8> {
+        let mut trun = (samples.len() as u32).to_be_bytes().to_vec();
+        trun.extend(0u32.to_be_bytes());
+        for sample in samples {
+            trun.extend(duration.to_be_bytes());
+            trun.extend((sample.len() as u32).to_be_bytes());
+        }
+        let mut traf = full_box(b"tfhd", 0, 0x020000, &track_id.to_be_bytes());
+        traf.extend(full_box(b"tfdt", 1, 0, &decode_time.to_be_bytes()));
+        traf.extend(traf_boxes);
+        let trun_size = 12 + trun.len();
+        let moof_size = 8 + 16 + 8 + traf.len() + trun_size;
+        trun[4..8].copy_from_slice(&((moof_size + 8) as u32).to_be_bytes());
+        traf.extend(full_box(b"trun", 0, 0x301, &trun));
+        let mut moof = full_box(b"mfhd", 0, 0, &1u32.to_be_bytes());
+        moof.extend(make_box(b"traf", &traf));
+        let mut segment = make_box(b"styp", b"msdh\0\0\0\0");
+        segment.extend(make_box(b"moof", &moof));
+        segment.extend(make_box(b"mdat", &samples.concat()));
+        segment
+    }
+
+    #[test]
+    fn parse_track_fragments_test() {
+        let init = init_segment(2, 48000, b"soun");
+        let tracks = super::parse_init_tracks(&init);
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].track_id, 2);
+        assert_eq!(tracks[0].timescale, 48000);
+        assert_eq!(&tracks[0].handler_type, b"soun");
+        assert!(tracks[0].trex.is_some());
+
+        let segment = media_segment(2, 96000, 1024, &[b"abc", b"defg"]);
+        let fragments = super::parse_track_fragments(&segment, &tracks);
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].base_media_decode_time, Some(96000));
+        assert_eq!(fragments[0].duration, 2048);
+        assert_eq!(fragments[0].sample_count, 2);
+        let run = &fragments[0].runs[0];
+        assert_eq!(
+            &segment[run.data_offset..run.data_offset + run.data_size()],
+            b"abcdefg"
+        );
+    }
+
+    #[test]
+    fn tree_round_trip() {
+        let tkhd = make_box(b"tkhd", &[0; 84]);
+        let trak = make_box(b"trak", &tkhd);
+        let mut moov_payload = make_box(b"mvhd", &[0; 100]);
+        moov_payload.extend(trak);
+        let mut data = make_box(b"ftyp", b"isom\0\0\0\0");
+        data.extend(make_box(b"moov", &moov_payload));
+
+        let boxes = top_level_boxes(&data);
+        assert_eq!(boxes.len(), 2);
+        assert_eq!(&boxes[1].box_type, b"moov");
+
+        let mut tree = parse_tree(&data);
+        assert_eq!(write_tree(&tree), data);
+        tree[1].children.retain(|child| &child.box_type != b"trak");
+        assert_eq!(write_tree(&tree).len(), data.len() - 8 - 92);
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::storage::Storage;
+
+pub const JOURNAL_FILE_NAME: &str = "journal.jsonl";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Planned,
+    Done,
+    Failed,
+    /// Removed from the manifest and deleted by an update.
+    Pruned,
+}
+
+/// One line of the journal. Later lines replace earlier ones for the same url.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub url: String,
+    /// Path relative to the output directory.
+    pub path: String,
+    pub state: State,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    /// Lower case hex SHA-256 of the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Unix time the entry was written.
+    pub time: u64,
+}
+
+impl Entry {
+    pub fn new(url: &str, path: &str, state: State) -> Entry {
+        Entry {
+            url: url.to_string(),
+            path: path.to_string(),
+            state,
+            size: None,
+            status: None,
+            etag: None,
+            last_modified: None,
+            sha256: None,
+            error: None,
+            time: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn done(url: &str, path: &str, data: &[u8]) -> Entry {
+        Entry::hashed(url, path, data.len() as u64, sha256_hex(data))
+    }
+
+    /// Like `done`, for a file which was hashed while it was read.
+    pub fn hashed(url: &str, path: &str, size: u64, sha256: String) -> Entry {
+        Entry {
+            size: Some(size),
+            sha256: Some(sha256),
+            ..Entry::new(url, path, State::Done)
+        }
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+/// Size and SHA-256 of everything `reader` yields, read in fixed-size chunks.
+pub fn hash_reader(reader: &mut impl std::io::Read) -> std::io::Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let length = reader.read(&mut buffer)?;
+        if length == 0 {
+            return Ok((size, hex(&hasher.finalize())));
+        }
+        hasher.update(&buffer[..length]);
+        size += length as u64;
+    }
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// What a planned download has to do, given the journal and the file on disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// Complete file, nothing to do.
+    Skip,
+    /// File exists without a completed entry, for example from a run without journal.
+    Adopt,
+    /// Not downloaded before.
+    Download,
+    /// Failed or interrupted in an earlier run.
+    Retry,
+    /// Completed earlier, but the file is missing or has a different size.
+    Repair,
+}
+
+/// Differences between the segments of the manifest and the journal of earlier runs.
+#[derive(Default, Debug, PartialEq)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub retried: Vec<String>,
+    pub repaired: Vec<String>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.retried.is_empty()
+            && self.repaired.is_empty()
+    }
+
+    pub fn print(&self) {
+        for (name, urls) in [
+            ("added", &self.added),
+            ("removed", &self.removed),
+            ("retried", &self.retried),
+            ("repaired", &self.repaired),
+        ] {
+            for url in urls {
+                println!("journal {} {}", name, url);
+            }
+        }
+        println!(
+            "journal: {} added {} removed {} retried {} repaired",
+            self.added.len(),
+            self.removed.len(),
+            self.retried.len(),
+            self.repaired.len()
+        );
+    }
+}
+
+/// Download journal in JSON Lines, appended to as downloads progress so an interrupted
+/// run can be resumed.
+pub struct Journal<'a> {
+    storage: &'a dyn Storage,
+    /// Latest entry of every url, in the order they were first recorded.
+    entries: Vec<Entry>,
+    /// Index in `entries` by url.
+    index: HashMap<String, usize>,
+    /// Whether each entry was planned in this run, by index.
+    planned: Vec<bool>,
+    /// Entries were read from an earlier run.
+    resumed: bool,
+}
+
+impl Journal<'_> {
+    /// Reads the journal of `storage`, empty if there is none yet. Lines which cannot be
+    /// parsed, such as one cut short by a crash, are ignored.
+    pub fn open(storage: &dyn Storage) -> Result<Journal<'_>, String> {
+        let mut journal = Journal {
+            storage,
+            entries: Vec::new(),
+            index: HashMap::new(),
+            planned: Vec::new(),
+            resumed: false,
+        };
+        if let Some(data) = storage.read(JOURNAL_FILE_NAME)? {
+            for line in String::from_utf8_lossy(&data)
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+            {
+                match serde_json::from_str::<Entry>(line) {
+                    Ok(entry) => journal.insert(entry),
+                    Err(e) => eprintln!("Ignoring journal line {} : {}", line, e),
+                }
+            }
+        }
+        journal.resumed = !journal.entries.is_empty();
+        Ok(journal)
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        match self.index.get(&entry.url) {
+            Some(&idx) => self.entries[idx] = entry,
+            None => {
+                self.index.insert(entry.url.clone(), self.entries.len());
+                self.entries.push(entry);
+                self.planned.push(false);
+            }
+        }
+    }
+
+    pub fn get(&self, url: &str) -> Option<&Entry> {
+        self.index.get(url).map(|&idx| &self.entries[idx])
+    }
+
+    /// Latest entry of every url, in the order they were first recorded.
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    /// Appends `entry` to the journal file.
+    pub fn record(&mut self, entry: Entry) -> Result<(), String> {
+        let mut line = serde_json::to_string(&entry).map_err(|e| e.to_string())?;
+        line.push('\n');
+        self.storage.append(JOURNAL_FILE_NAME, line.as_bytes())?;
+        self.insert(entry);
+        Ok(())
+    }
+
+    /// Decides what to do for `url`, stored at `path` relative to the output.
+    pub fn action(&self, url: &str, path: &str) -> Result<Action, String> {
+        let size = self.storage.size(path)?;
+        Ok(match (self.get(url), size) {
+            (Some(entry), Some(size)) if entry.state == State::Done => {
+                if entry.size.is_none_or(|expected| expected == size) {
+                    Action::Skip
+                } else {
+                    Action::Repair
+                }
+            }
+            // the file is renamed into place before the journal is updated
+            (_, Some(_)) => Action::Adopt,
+            (Some(entry), None) if entry.state == State::Pruned => Action::Download,
+            (Some(entry), None) if entry.state == State::Done => Action::Repair,
+            (Some(_), None) => Action::Retry,
+            (None, None) => Action::Download,
+        })
+    }
+
+    /// Records `url` of the manifest as planned if it is not in the journal yet, and notes
+    /// in `changes` how it differs from earlier runs. Returns what to do for it.
+    pub fn plan(&mut self, url: &str, path: &str, changes: &mut Changes) -> Result<Action, String> {
+        let action = self.action(url, path)?;
+        match action {
+            Action::Download if self.resumed => changes.added.push(url.to_string()),
+            Action::Retry => changes.retried.push(url.to_string()),
+            Action::Repair => changes.repaired.push(url.to_string()),
+            _ => (),
+        }
+        if self
+            .get(url)
+            .is_none_or(|entry| entry.state == State::Pruned)
+        {
+            self.record(Entry::new(url, path, State::Planned))?;
+        }
+        if let Some(&idx) = self.index.get(url) {
+            self.planned[idx] = true;
+        }
+        Ok(action)
+    }
+
+    /// Urls of earlier runs which were not planned in this one, so are no longer in the
+    /// manifest.
+    pub fn unplanned(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .zip(&self.planned)
+            .filter(|(entry, planned)| entry.state != State::Pruned && !**planned)
+            .map(|(entry, _)| entry.url.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Changes, Entry, Journal, State, JOURNAL_FILE_NAME};
+    use crate::storage::FileStorage;
+
+    fn plan(journal: &mut Journal, planned: &[(String, String)]) -> Changes {
+        let mut changes = Changes::default();
+        for (url, path) in planned {
+            journal.plan(url, path, &mut changes).unwrap();
+        }
+        changes.removed = journal.unplanned();
+        changes
+    }
+
+    #[test]
+    fn journal_resume() {
+        let directory =
+            std::env::temp_dir().join(format!("dash-mirror-journal-{}", std::process::id()));
+        let storage = FileStorage::new(directory.to_str().unwrap());
+        let planned: Vec<(String, String)> = ["a", "b", "c"]
+            .iter()
+            .map(|name| {
+                (
+                    format!("http://origin.test/{}.m4s", name),
+                    format!("{}.m4s", name),
+                )
+            })
+            .collect();
+
+        let mut journal = Journal::open(&storage).unwrap();
+        let changes = plan(&mut journal, &planned);
+        assert!(changes.is_empty());
+        std::fs::write(storage.path(&planned[0].1), b"aaaa").unwrap();
+        journal
+            .record(Entry::done(&planned[0].0, &planned[0].1, b"aaaa"))
+            .unwrap();
+        let mut failed = Entry::new(&planned[1].0, &planned[1].1, State::Failed);
+        failed.status = Some(503);
+        journal.record(failed).unwrap();
+        // a crash in the middle of writing a line
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(directory.join(JOURNAL_FILE_NAME))
+            .and_then(|mut file| std::io::Write::write_all(&mut file, b"{\"url\":"))
+            .unwrap();
+
+        let mut journal = Journal::open(&storage).unwrap();
+        assert_eq!(journal.entries().count(), 3);
+        let entry = journal.get(&planned[0].0).unwrap();
+        assert_eq!(entry.size, Some(4));
+        assert_eq!(
+            entry.sha256.as_deref(),
+            Some("61be55a8e2f6b4e172338bddf184d6dbee29c98853e0a0485ecee7f27b9af0b4")
+        );
+        assert_eq!(
+            journal.action(&planned[0].0, &planned[0].1).unwrap(),
+            Action::Skip
+        );
+        assert_eq!(
+            journal.action(&planned[1].0, &planned[1].1).unwrap(),
+            Action::Retry
+        );
+        assert_eq!(
+            journal.action(&planned[2].0, &planned[2].1).unwrap(),
+            Action::Retry
+        );
+        std::fs::write(storage.path(&planned[0].1), b"a").unwrap();
+        assert_eq!(
+            journal.action(&planned[0].0, &planned[0].1).unwrap(),
+            Action::Repair
+        );
+        std::fs::write(storage.path(&planned[2].1), b"cc").unwrap();
+        assert_eq!(
+            journal.action(&planned[2].0, &planned[2].1).unwrap(),
+            Action::Adopt
+        );
+
+        let mut next = planned[..2].to_vec();
+        next.push(("http://origin.test/d.m4s".to_string(), "d.m4s".to_string()));
+        let changes = plan(&mut journal, &next);
+        assert_eq!(changes.added, vec!["http://origin.test/d.m4s"]);
+        assert_eq!(changes.removed, vec!["http://origin.test/c.m4s"]);
+        assert_eq!(changes.retried, vec!["http://origin.test/b.m4s"]);
+        assert_eq!(changes.repaired, vec!["http://origin.test/a.m4s"]);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+
+use crate::checksum;
+use crate::journal;
+use crate::mpd;
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.mpd";
+
+/// How segment urls map to files in the output directory.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Layout {
+    /// The url path below the base url of the manifest
+    #[default]
+    Path,
+    /// Host name and url path, so segments on other hosts are mirrored too
+    Host,
+    /// One folder per representation, with init and numbered segment files
+    Flat,
+    /// SHA-256 of the url, keeping the extension
+    Hash,
+}
+
+/// `file_name` with a digest of `query` inserted before the extension, so urls which only
+/// differ in their query get different files.
+pub fn query_file_name(file_name: &str, query: &str) -> String {
+    if query.is_empty() {
+        return file_name.to_string();
+    }
+    let digest = &journal::sha256_hex(query.as_bytes())[..16];
+    match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{}~{}.{}", stem, digest, extension)
+        }
+        _ => format!("{}~{}", file_name, digest),
+    }
+}
+
+/// `path` and the query of a url joined into a relative path, `None` if the path is empty,
+/// absolute or has `.` or `..` components.
+fn safe_path(path: &str, query: Option<&str>) -> Option<String> {
+    let mut components: Vec<String> = Vec::new();
+    for component in path.split('/') {
+        let decoded = component.replace("%2e", ".").replace("%2E", ".");
+        if matches!(decoded.as_str(), "" | "." | "..") || component.contains('\\') {
+            return None;
+        }
+        components.push(component.to_string());
+    }
+    if let (Some(query), Some(last)) = (query, components.last_mut()) {
+        *last = query_file_name(last, query);
+    }
+    Some(components.join("/"))
+}
+
+/// Splits the query and fragment off a url.
+fn split_query(url: &str) -> (&str, Option<&str>) {
+    let url = url.split('#').next().unwrap_or_default();
+    match url.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (url, None),
+    }
+}
+
+/// Extension of the last path component of `url`, with the dot, if it looks like one.
+fn extension(url: &str) -> String {
+    let (path, _) = split_query(url);
+    let name = path.rsplit('/').next().unwrap_or_default();
+    match name.rsplit_once('.') {
+        Some((stem, extension))
+            if !stem.is_empty()
+                && (1..=8).contains(&extension.len())
+                && extension.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            format!(".{}", extension)
+        }
+        _ => String::new(),
+    }
+}
+
+/// Path of a segment url relative to the output directory, `None` if the url is not
+/// below `base_url` or the path would leave the output directory.
+pub fn relative_path(base_url: &str, url: &str) -> Option<String> {
+    let (path, query) = split_query(url.strip_prefix(base_url)?);
+    safe_path(path, query)
+}
+
+/// `<host>/<path>`, with `_<port>` after the host for a non-default port.
+fn host_path(url: &str) -> Result<String, String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("invalid url : {}", e))?;
+    let host = parsed.host_str().ok_or("url has no host")?;
+    let mut prefix = host.to_ascii_lowercase().replace(':', "_");
+    if let Some(port) = parsed.port() {
+        prefix.push_str(&format!("_{}", port));
+    }
+    // the parsed path has dot segments removed, check the one from the url itself
+    let (path, query) = split_query(url);
+    let path = path
+        .split_once("://")
+        .and_then(|(_, rest)| rest.split_once('/'))
+        .map(|(_, path)| path)
+        .unwrap_or_default();
+    safe_path(&format!("{}/{}", prefix, path), query).ok_or("unsafe path".to_string())
+}
+
+/// Folder of a representation for `Layout::Flat`, suffixed with the period after the first.
+fn representation_folder(representation: &mpd::RepresentationUrls) -> String {
+    match representation.period_idx {
+        0 => file_name(&representation.id),
+        period_idx => format!("{}.period{}", file_name(&representation.id), period_idx),
+    }
+}
+
+/// Files of the mirror itself, which no segment may overwrite.
+const RESERVED_PATHS: [&str; 3] = [
+    MANIFEST_FILE_NAME,
+    journal::JOURNAL_FILE_NAME,
+    checksum::CHECKSUM_FILE_NAME,
+];
+
+/// Assigns local paths to the urls of a mirror as they are downloaded. Distinct urls get
+/// distinct paths in every layout but `Layout::Flat`, where representation ids which map
+/// to the same folder are rejected, so only the folders are remembered.
+pub struct Paths {
+    base_url: String,
+    layout: Layout,
+    /// Period, adaptation set and id of the representation owning each flat folder.
+    folders: HashMap<String, (usize, usize, String)>,
+}
+
+impl Paths {
+    pub fn new(base_url: &str, layout: Layout) -> Paths {
+        Paths {
+            base_url: base_url.to_string(),
+            layout,
+            folders: HashMap::new(),
+        }
+    }
+
+    /// Local path of `url` in the layout, or why it can not be mirrored. `number` is the
+    /// segment number, `None` for the initialization segment of `representation`.
+    pub fn path(
+        &mut self,
+        representation: &mpd::RepresentationUrls,
+        number: Option<u64>,
+        url: &str,
+    ) -> Result<String, String> {
+        let path = match self.layout {
+            Layout::Path => {
+                relative_path(&self.base_url, url).ok_or(match url.starts_with(&self.base_url) {
+                    true => "unsafe path".to_string(),
+                    false => format!("not below base url {}", self.base_url),
+                })?
+            }
+            Layout::Host => host_path(url)?,
+            Layout::Flat => self.flat_path(representation, number, url)?,
+            Layout::Hash => format!("{}{}", journal::sha256_hex(url.as_bytes()), extension(url)),
+        };
+        match RESERVED_PATHS.contains(&path.as_str()) {
+            true => Err(format!(
+                "path {} is already used by the mirror itself",
+                path
+            )),
+            false => Ok(path),
+        }
+    }
+
+    fn flat_path(
+        &mut self,
+        representation: &mpd::RepresentationUrls,
+        number: Option<u64>,
+        url: &str,
+    ) -> Result<String, String> {
+        let folder = representation_folder(representation);
+        let owner = (
+            representation.period_idx,
+            representation.adaptation_set_idx,
+            representation.id.clone(),
+        );
+        let folder_owner = self.folders.entry(folder.clone()).or_insert(owner.clone());
+        if *folder_owner != owner {
+            return Err(format!(
+                "folder {} is already used by representation {}",
+                folder, folder_owner.2
+            ));
+        }
+        let name = match number {
+            Some(number) => number.to_string(),
+            None => "init".to_string(),
+        };
+        // an id of "" or ".." would put the folder outside the output directory
+        safe_path(&format!("{}/{}{}", folder, name, extension(url)), None)
+            .ok_or(format!("unsafe representation id {:?}", representation.id))
+    }
+}
+
+pub fn local_path(output_directory: &str, base_url: &str, url: &str) -> Option<std::path::PathBuf> {
+    let relative = relative_path(base_url, url)?;
+    Some(std::path::Path::new(output_directory).join(relative))
+}
+
+/// Reads a mirrored segment.
+pub fn read_segment(output_directory: &str, base_url: &str, url: &str) -> Result<Vec<u8>, String> {
+    let path = local_path(output_directory, base_url, url)
+        .ok_or(format!("url {} is not below base url {}", url, base_url))?;
+    std::fs::read(&path).map_err(|e| format!("{} : {}", path.display(), e))
+}
+
+/// File name derived from a representation id.
+pub fn file_name(id: &str) -> String {
+    id.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+pub fn manifest_path(output_directory: &str) -> std::path::PathBuf {
+    std::path::Path::new(output_directory).join(MANIFEST_FILE_NAME)
+}
+
+/// Reads the manifest of an existing mirror and regenerates its segment urls. The
+/// urls are relative to the mirror directory unless the manifest has an absolute
+/// BaseURL, `relative_path` maps both to local files.
+pub fn read_mirror(output_directory: &str) -> Option<(String, mpd::UrlInfo)> {
+    let manifest_path = manifest_path(output_directory);
+    match std::fs::read_to_string(&manifest_path) {
+        Ok(manifest_text) => {
+            let url = format!("{}/{}", output_directory, MANIFEST_FILE_NAME);
+            let url_info = mpd::get_fragment_urls(manifest_text.clone(), &url)?;
+            Some((manifest_text, url_info))
+        }
+        Err(e) => {
+            eprintln!("Error: reading manifest {} {}", manifest_path.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{query_file_name, relative_path, Layout, Paths};
+    use crate::mpd::{get_fragment_urls, UrlInfo};
+
+    /// Path of every url of `url_info`, assigned in manifest order.
+    fn local_paths(url_info: &UrlInfo, layout: Layout) -> HashMap<String, Result<String, String>> {
+        let mut paths = Paths::new(&url_info.base_url, layout);
+        let mut local_paths = HashMap::new();
+        for representation in &url_info.representations {
+            let urls = representation
+                .initialization
+                .iter()
+                .map(|url| (None, url))
+                .chain(
+                    representation
+                        .segments
+                        .iter()
+                        .map(|segment| (Some(segment.number), &segment.url)),
+                );
+            for (number, url) in urls {
+                local_paths
+                    .entry(url.clone())
+                    .or_insert_with(|| paths.path(representation, number, url));
+            }
+        }
+        local_paths
+    }
+
+    #[test]
+    fn layouts() {
+        let xml_text = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <MPD type="static" mediaPresentationDuration="PT4S">
+        <Period duration="PT4S">
+            <AdaptationSet mimeType="video/mp4">
+                <SegmentTemplate timescale="1000" duration="2000" media="v/$Number$.m4s?sig=$Number$" initialization="v/init.mp4"/>
+                <Representation id="v" bandwidth="1000000"/>
+            </AdaptationSet>
+            <AdaptationSet mimeType="audio/mp4">
+                <BaseURL>https://cdn.test:8443/audio/</BaseURL>
+                <SegmentTemplate timescale="1000" duration="2000" media="$Number$.m4s" initialization="init.mp4"/>
+                <Representation id="a/1" bandwidth="64000"/>
+            </AdaptationSet>
+            <AdaptationSet mimeType="text/vtt">
+                <SegmentTemplate timescale="1000" duration="2000" media="../../$Number$.vtt" initialization="../init.vtt"/>
+                <Representation id="t" bandwidth="1000"/>
+            </AdaptationSet>
+        </Period>
+        </MPD>"#
+            .to_owned();
+        let url_info = get_fragment_urls(xml_text, "http://origin.test/vod/manifest.mpd").unwrap();
+        let path = |layout, url: &str| local_paths(&url_info, layout)[url].clone();
+
+        let first = "http://origin.test/vod/v/1.m4s?sig=1";
+        let second = "http://origin.test/vod/v/2.m4s?sig=2";
+        let cdn = "https://cdn.test:8443/audio/1.m4s";
+        let traversal = "http://origin.test/vod/../../1.vtt";
+        let query_path = path(Layout::Path, first).unwrap();
+        assert!(query_path.starts_with("v/1~") && query_path.ends_with(".m4s"));
+        assert_eq!(path(Layout::Path, first), path(Layout::Path, first));
+        assert_ne!(path(Layout::Path, first), path(Layout::Path, second));
+        assert_eq!(relative_path(&url_info.base_url, first), Some(query_path));
+        assert!(path(Layout::Path, cdn).is_err());
+        assert!(path(Layout::Path, traversal).is_err());
+
+        assert_eq!(
+            path(Layout::Host, "http://origin.test/vod/v/init.mp4").unwrap(),
+            "origin.test/vod/v/init.mp4"
+        );
+        assert_eq!(
+            path(Layout::Host, cdn).unwrap(),
+            "cdn.test_8443/audio/1.m4s"
+        );
+        assert!(path(Layout::Host, traversal).is_err());
+
+        assert_eq!(path(Layout::Flat, first).unwrap(), "v/1.m4s");
+        assert_eq!(
+            path(Layout::Flat, "https://cdn.test:8443/audio/init.mp4").unwrap(),
+            "a_1/init.mp4"
+        );
+        assert_eq!(path(Layout::Flat, traversal).unwrap(), "t/1.vtt");
+
+        let xml_text = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <MPD type="static" mediaPresentationDuration="PT2S">
+        <Period duration="PT2S">
+            <AdaptationSet mimeType="video/mp4">
+                <SegmentTemplate timescale="1000" duration="2000" media="$Bandwidth$/$Number$.m4s" initialization="$Bandwidth$/init.mp4"/>
+                <Representation id=".." bandwidth="1000"/>
+                <Representation id="" bandwidth="2000"/>
+                <Representation id="." bandwidth="3000"/>
+            </AdaptationSet>
+        </Period>
+        </MPD>"#
+            .to_owned();
+        let unsafe_ids = get_fragment_urls(xml_text, "http://origin.test/manifest.mpd").unwrap();
+        let paths = local_paths(&unsafe_ids, Layout::Flat);
+        assert_eq!(paths.len(), 6);
+        assert!(paths.values().all(|path| path.is_err()));
+
+        let hashed = path(Layout::Hash, first).unwrap();
+        assert_eq!(hashed.len(), 64 + ".m4s".len());
+        assert_ne!(hashed, path(Layout::Hash, second).unwrap());
+
+        assert_eq!(query_file_name("1.m4s", ""), "1.m4s");
+        assert_eq!(
+            query_file_name("1.m4s", "a=1"),
+            query_file_name("1.m4s", "a=1")
+        );
+        assert!(!query_file_name("segment", "a=1/b").contains('/'));
+    }
+
+    #[test]
+    fn colliding_paths() {
+        let xml_text = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <MPD type="static" mediaPresentationDuration="PT2S">
+        <Period duration="PT2S">
+            <AdaptationSet mimeType="video/mp4">
+                <SegmentTemplate timescale="1000" duration="2000" media="$RepresentationID$/$Number$.m4s" initialization="$RepresentationID$/init.mp4"/>
+                <Representation id="a:1" bandwidth="1000000"/>
+                <Representation id="a/1" bandwidth="2000000"/>
+            </AdaptationSet>
+        </Period>
+        </MPD>"#
+            .to_owned();
+        let url_info = get_fragment_urls(xml_text, "http://origin.test/manifest.mpd").unwrap();
+        let paths = local_paths(&url_info, Layout::Flat);
+        assert_eq!(
+            paths["http://origin.test/a:1/init.mp4"],
+            Ok("a_1/init.mp4".to_string())
+        );
+        assert_eq!(
+            paths["http://origin.test/a/1/init.mp4"],
+            Err("folder a_1 is already used by representation a:1".to_string())
+        );
+        let paths = local_paths(&url_info, Layout::Path);
+        assert!(url_info.urls().all(|url| paths[url].is_ok()));
+
+        let xml_text = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <MPD type="static" mediaPresentationDuration="PT2S">
+        <Period duration="PT2S">
+            <AdaptationSet mimeType="video/mp4">
+                <SegmentTemplate timescale="1000" duration="2000" media="$Number$.m4s" initialization="journal.jsonl"/>
+                <Representation id="v" bandwidth="1000000"/>
+            </AdaptationSet>
+        </Period>
+        </MPD>"#
+            .to_owned();
+        let url_info = get_fragment_urls(xml_text, "http://origin.test/manifest.mpd").unwrap();
+        let paths = local_paths(&url_info, Layout::Path);
+        assert_eq!(
+            paths["http://origin.test/journal.jsonl"],
+            Err("path journal.jsonl is already used by the mirror itself".to_string())
+        );
+        assert_eq!(paths["http://origin.test/1.m4s"], Ok("1.m4s".to_string()));
+    }
+}
@@ -0,0 +1,28 @@
+//! Mirrors MPEG-DASH presentations, and serves, verifies, converts and decrypts the
+//! mirrors. The `dash-mirror` binary is a command line front end to these modules.
+
+pub mod cenc;
+pub mod checksum;
+pub mod concat;
+pub mod drm;
+pub mod fetch;
+pub mod hls;
+pub mod impair;
+pub mod isobmff;
+pub mod journal;
+pub mod layout;
+pub mod live;
+pub mod mirror;
+#[cfg(test)]
+pub mod mock;
+pub mod mpd;
+pub mod mux;
+pub mod order;
+pub mod request;
+pub mod serve;
+pub mod sign;
+pub mod storage;
+pub mod throttle;
+pub mod util;
+pub mod verify;
+pub mod warc;
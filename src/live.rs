@@ -0,0 +1,640 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::impair::Target;
+use crate::isobmff::{self, Mp4Node};
+use crate::layout;
+use crate::mpd::RepresentationUrls;
+use crate::serve::{self, Request, Response};
+use crate::util::format_time;
+
+pub const LIVE_MANIFEST_PATH: &str = "/live/manifest.mpd";
+const LIVE_TIME_PATH: &str = "/live/time";
+const LIVE_PREFIX: &str = "/live/";
+
+#[derive(Clone, Debug)]
+pub struct LiveOptions {
+    /// Seconds of content kept available behind the live edge.
+    pub time_shift_buffer_depth: f64,
+    /// Start again from the first segment after the last one.
+    pub looping: bool,
+}
+
+struct LiveRepresentation {
+    representation: RepresentationUrls,
+    init_path: PathBuf,
+    segment_paths: Vec<PathBuf>,
+    /// Timescale of the track in the init segment, used for `tfdt`.
+    track_timescale: u32,
+    /// Time of the first segment, in `representation.timescale` units.
+    first_time: u64,
+    /// Duration of one pass over all segments, in `representation.timescale` units.
+    loop_duration: u64,
+}
+
+impl LiveRepresentation {
+    fn segment_count(&self) -> u64 {
+        self.representation.segments.len() as u64
+    }
+
+    /// Start and duration on the live timeline of the segment with zero based index `idx`.
+    fn segment_timing(&self, idx: u64) -> (u64, u64) {
+        let segment = &self.representation.segments[(idx % self.segment_count()) as usize];
+        let start = (idx / self.segment_count())
+            .saturating_mul(self.loop_duration)
+            .saturating_add(segment.time.saturating_sub(self.first_time));
+        (start, segment.duration.unwrap_or_default())
+    }
+
+    fn seconds(&self, ticks: u64) -> f64 {
+        ticks as f64 / self.representation.timescale.max(1) as f64
+    }
+
+    /// Indexes of the segments available at `elapsed` seconds after the availability start.
+    fn available_segments(&self, elapsed: f64, options: &LiveOptions) -> std::ops::Range<u64> {
+        let window_start = (elapsed - options.time_shift_buffer_depth).max(0.0);
+        let loop_seconds = self.seconds(self.loop_duration);
+        if loop_seconds <= 0.0 {
+            return 0..0;
+        }
+        let first_loop = (window_start / loop_seconds).floor() as u64;
+        let mut idx = first_loop.saturating_mul(self.segment_count());
+        let mut first = None;
+        loop {
+            if !options.looping && idx >= self.segment_count() {
+                break;
+            }
+            let (start, duration) = self.segment_timing(idx);
+            let end = start.saturating_add(duration);
+            if self.seconds(end) > elapsed || idx == u64::MAX {
+                break;
+            }
+            if first.is_none() && self.seconds(end) >= window_start {
+                first = Some(idx);
+            }
+            idx += 1;
+        }
+        first.unwrap_or(idx)..idx
+    }
+}
+
+/// A VOD mirror republished as a dynamic manifest whose segments become available on
+/// a wall clock schedule starting at `availability_start_time`.
+pub struct LiveStream {
+    options: LiveOptions,
+    availability_start_time: SystemTime,
+    representations: Vec<LiveRepresentation>,
+}
+
+fn load_representation(
+    root: &Path,
+    base_url: &str,
+    representation: &RepresentationUrls,
+) -> Result<LiveRepresentation, String> {
+    let local_path = |url: &str| {
+        layout::local_path(&root.to_string_lossy(), base_url, url)
+            .ok_or(format!("url {} is not below base url {}", url, base_url))
+    };
+    let initialization = representation
+        .initialization
+        .as_ref()
+        .ok_or("initialization segment not present")?;
+    let init_path = local_path(initialization)?;
+    let init = std::fs::read(&init_path).map_err(|e| format!("{} : {}", init_path.display(), e))?;
+    let track_timescale = isobmff::parse_init_tracks(&init)
+        .first()
+        .map(|track| track.timescale)
+        .filter(|timescale| *timescale > 0)
+        .unwrap_or(representation.timescale as u32);
+    let first = representation.segments.first().ok_or("no segments")?;
+    let last = representation.segments.last().ok_or("no segments")?;
+    let last_duration = last.duration.ok_or("segment durations not available")?;
+    if representation
+        .segments
+        .iter()
+        .any(|segment| segment.duration.is_none())
+    {
+        return Err("segment durations not available".to_string());
+    }
+    // a zero duration would never move the live edge forward
+    if representation
+        .segments
+        .iter()
+        .any(|segment| segment.duration == Some(0))
+    {
+        return Err("segment with zero duration".to_string());
+    }
+    if representation
+        .segments
+        .windows(2)
+        .any(|pair| pair[1].time < pair[0].time)
+    {
+        return Err("segment times are not increasing".to_string());
+    }
+    let loop_duration = last
+        .time
+        .checked_add(last_duration)
+        .map(|end| end - first.time)
+        .ok_or("segment times out of range")?;
+    let segment_paths = representation
+        .segments
+        .iter()
+        .map(|segment| local_path(&segment.url))
+        .collect::<Result<Vec<PathBuf>, String>>()?;
+    Ok(LiveRepresentation {
+        representation: representation.clone(),
+        init_path,
+        segment_paths,
+        track_timescale,
+        first_time: first.time,
+        loop_duration,
+    })
+}
+
+fn format_duration(seconds: f64) -> String {
+    format!("PT{:.3}S", seconds)
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Adds `shift` to every `tfdt` of a media segment. Version 0 boxes which can not hold
+/// the new time are upgraded to version 1 and the data offsets are moved accordingly.
+pub fn shift_decode_times(data: &[u8], shift: i64) -> Vec<u8> {
+    let mut nodes = isobmff::parse_tree(data);
+    let mut total_growth: i64 = 0;
+    for moof in nodes.iter_mut().filter(|node| &node.box_type == b"moof") {
+        let old_size = moof.size() as i64;
+        for traf in moof
+            .children
+            .iter_mut()
+            .filter(|node| &node.box_type == b"traf")
+        {
+            if let Some(tfdt) = traf.child_mut(b"tfdt") {
+                shift_tfdt(tfdt, shift);
+            }
+        }
+        let growth = moof.size() as i64 - old_size;
+        total_growth += growth;
+        if total_growth == 0 {
+            continue;
+        }
+        for traf in moof
+            .children
+            .iter_mut()
+            .filter(|node| &node.box_type == b"traf")
+        {
+            let mut explicit_base = false;
+            if let Some(tfhd) = traf.child_mut(b"tfhd") {
+                if isobmff::read_u32(&tfhd.data, 0).unwrap_or_default() & 0x1 != 0 {
+                    explicit_base = true;
+                    add_to_u64(&mut tfhd.data, 8, total_growth);
+                }
+            }
+            if explicit_base {
+                continue;
+            }
+            for trun in traf
+                .children
+                .iter_mut()
+                .filter(|node| &node.box_type == b"trun")
+            {
+                if isobmff::read_u32(&trun.data, 0).unwrap_or_default() & 0x1 != 0 {
+                    if let Some(offset) = isobmff::read_u32(&trun.data, 8) {
+                        let offset = (offset as i32 as i64 + growth) as i32;
+                        trun.data[8..12].copy_from_slice(&offset.to_be_bytes());
+                    }
+                }
+            }
+        }
+    }
+    isobmff::write_tree(&nodes)
+}
+
+fn add_to_u64(data: &mut [u8], pos: usize, value: i64) {
+    if let Some(current) = isobmff::read_u64(data, pos) {
+        let new = (current as i64 + value) as u64;
+        data[pos..pos + 8].copy_from_slice(&new.to_be_bytes());
+    }
+}
+
+fn shift_tfdt(tfdt: &mut Mp4Node, shift: i64) {
+    let Some(time) = isobmff::parse_tfdt(&tfdt.data) else {
+        return;
+    };
+    let time = (time as i64 + shift).max(0) as u64;
+    match (tfdt.data[0], u32::try_from(time)) {
+        (0, Ok(time)) => tfdt.data[4..8].copy_from_slice(&time.to_be_bytes()),
+        _ => {
+            let mut data = vec![1];
+            data.extend(&tfdt.data[1..4]);
+            data.extend(time.to_be_bytes());
+            tfdt.data = data;
+        }
+    }
+}
+
+impl LiveStream {
+    pub fn new(
+        root: &Path,
+        options: LiveOptions,
+        availability_start_time: SystemTime,
+    ) -> Result<LiveStream, String> {
+        let (_, url_info) =
+            layout::read_mirror(&root.to_string_lossy()).ok_or("Could not read mirror manifest")?;
+        if url_info
+            .representations
+            .iter()
+            .any(|rep| rep.period_idx > 0)
+        {
+            eprintln!("only the first period is published live");
+        }
+        let mut representations = Vec::new();
+        for representation in url_info
+            .representations
+            .iter()
+            .filter(|rep| rep.period_idx == 0)
+        {
+            match load_representation(root, &url_info.base_url, representation) {
+                Ok(live_representation) => representations.push(live_representation),
+                Err(e) => eprintln!("rep {} : not published live, {}", representation.id, e),
+            }
+        }
+        if representations.is_empty() {
+            return Err("no representation can be published live".to_string());
+        }
+        Ok(LiveStream {
+            options,
+            availability_start_time,
+            representations,
+        })
+    }
+
+    fn elapsed(&self, now: SystemTime) -> f64 {
+        now.duration_since(self.availability_start_time)
+            .unwrap_or_default()
+            .as_secs_f64()
+    }
+
+    /// True once every segment has been published and the stream does not loop.
+    fn ended(&self, elapsed: f64) -> bool {
+        !self.options.looping
+            && self
+                .representations
+                .iter()
+                .all(|rep| elapsed >= rep.seconds(rep.loop_duration))
+    }
+
+    fn segment_timeline(&self, representation: &LiveRepresentation, elapsed: f64) -> (u64, String) {
+        let available = representation.available_segments(elapsed, &self.options);
+        let mut entries: Vec<(u64, u64, u64)> = Vec::new();
+        for idx in available.clone() {
+            let (start, duration) = representation.segment_timing(idx);
+            match entries.last_mut() {
+                Some((last_start, last_duration, repeat))
+                    if *last_duration == duration
+                        && *last_start + (*repeat + 1) * *last_duration == start =>
+                {
+                    *repeat += 1
+                }
+                _ => entries.push((start, duration, 0)),
+            }
+        }
+        let mut timeline = String::new();
+        for (start, duration, repeat) in entries {
+            match repeat {
+                0 => writeln!(
+                    timeline,
+                    "            <S t=\"{}\" d=\"{}\"/>",
+                    start, duration
+                ),
+                _ => writeln!(
+                    timeline,
+                    "            <S t=\"{}\" d=\"{}\" r=\"{}\"/>",
+                    start, duration, repeat
+                ),
+            }
+            .unwrap();
+        }
+        (available.start + 1, timeline)
+    }
+
+    /// Dynamic manifest as published at `now`. `time_url` is the UTCTiming source.
+    pub fn manifest(&self, now: SystemTime, time_url: &str) -> String {
+        let elapsed = self.elapsed(now);
+        let max_segment_duration = self
+            .representations
+            .iter()
+            .flat_map(|rep| {
+                rep.representation
+                    .segments
+                    .iter()
+                    .map(|segment| rep.seconds(segment.duration.unwrap_or_default()))
+            })
+            .fold(1.0, f64::max);
+        let mut mpd = String::new();
+        writeln!(mpd, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>").unwrap();
+        write!(
+            mpd,
+            "<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" profiles=\"urn:mpeg:dash:profile:isoff-live:2011\" type=\"dynamic\" availabilityStartTime=\"{}\" publishTime=\"{}\" timeShiftBufferDepth=\"{}\" minBufferTime=\"{}\"",
+            format_time(self.availability_start_time),
+            format_time(now),
+            format_duration(self.options.time_shift_buffer_depth),
+            format_duration(max_segment_duration)
+        )
+        .unwrap();
+        match self.ended(elapsed) {
+            true => {
+                let duration = self
+                    .representations
+                    .iter()
+                    .map(|rep| rep.seconds(rep.loop_duration))
+                    .fold(0.0, f64::max);
+                write!(
+                    mpd,
+                    " mediaPresentationDuration=\"{}\"",
+                    format_duration(duration)
+                )
+            }
+            false => write!(
+                mpd,
+                " minimumUpdatePeriod=\"{}\"",
+                format_duration(max_segment_duration)
+            ),
+        }
+        .unwrap();
+        writeln!(mpd, ">").unwrap();
+        writeln!(mpd, "  <Period id=\"0\" start=\"PT0S\">").unwrap();
+        let mut adaptation_sets: Vec<usize> = Vec::new();
+        for rep in &self.representations {
+            if !adaptation_sets.contains(&rep.representation.adaptation_set_idx) {
+                adaptation_sets.push(rep.representation.adaptation_set_idx);
+            }
+        }
+        for adaptation_set_idx in adaptation_sets {
+            let members: Vec<(usize, &LiveRepresentation)> = self
+                .representations
+                .iter()
+                .enumerate()
+                .filter(|(_, rep)| rep.representation.adaptation_set_idx == adaptation_set_idx)
+                .collect();
+            let first = &members[0].1.representation;
+            write!(
+                mpd,
+                "    <AdaptationSet id=\"{}\" mimeType=\"{}\" segmentAlignment=\"true\"",
+                adaptation_set_idx,
+                xml_escape(&first.mime_type)
+            )
+            .unwrap();
+            if let Some(content_type) = &first.content_type {
+                write!(mpd, " contentType=\"{}\"", xml_escape(content_type)).unwrap();
+            }
+            if let Some(lang) = &first.lang {
+                write!(mpd, " lang=\"{}\"", xml_escape(lang)).unwrap();
+            }
+            writeln!(mpd, ">").unwrap();
+            for (rep_idx, rep) in members {
+                let representation = &rep.representation;
+                write!(
+                    mpd,
+                    "      <Representation id=\"{}\" bandwidth=\"{}\"",
+                    xml_escape(&representation.id),
+                    representation.bandwidth
+                )
+                .unwrap();
+                if let Some(codecs) = &representation.codecs {
+                    write!(mpd, " codecs=\"{}\"", xml_escape(codecs)).unwrap();
+                }
+                if let (Some(width), Some(height)) = (representation.width, representation.height) {
+                    write!(mpd, " width=\"{}\" height=\"{}\"", width, height).unwrap();
+                }
+                if let Some(frame_rate) = &representation.frame_rate {
+                    write!(mpd, " frameRate=\"{}\"", xml_escape(frame_rate)).unwrap();
+                }
+                writeln!(mpd, ">").unwrap();
+                let (start_number, timeline) = self.segment_timeline(rep, elapsed);
+                writeln!(
+                    mpd,
+                    "        <SegmentTemplate timescale=\"{}\" startNumber=\"{}\" initialization=\"{}/init.mp4\" media=\"{}/$Number$.m4s\">",
+                    representation.timescale, start_number, rep_idx, rep_idx
+                )
+                .unwrap();
+                writeln!(mpd, "          <SegmentTimeline>").unwrap();
+                mpd.push_str(&timeline);
+                writeln!(mpd, "          </SegmentTimeline>").unwrap();
+                writeln!(mpd, "        </SegmentTemplate>").unwrap();
+                writeln!(mpd, "      </Representation>").unwrap();
+            }
+            writeln!(mpd, "    </AdaptationSet>").unwrap();
+        }
+        writeln!(mpd, "  </Period>").unwrap();
+        writeln!(
+            mpd,
+            "  <UTCTiming schemeIdUri=\"urn:mpeg:dash:utc:http-iso:2014\" value=\"{}\"/>",
+            xml_escape(time_url)
+        )
+        .unwrap();
+        writeln!(mpd, "</MPD>").unwrap();
+        mpd
+    }
+
+    /// Media segment `number` of representation `rep_idx` with its decode times moved
+    /// to the live timeline, `None` if it is not available at `now`.
+    pub fn segment(&self, rep_idx: usize, number: u64, now: SystemTime) -> Option<Vec<u8>> {
+        let rep = self.representations.get(rep_idx)?;
+        let idx = number.checked_sub(1)?;
+        if !rep
+            .available_segments(self.elapsed(now), &self.options)
+            .contains(&idx)
+        {
+            return None;
+        }
+        let data = std::fs::read(&rep.segment_paths[(idx % rep.segment_count()) as usize]).ok()?;
+        let timescale = rep.representation.timescale.max(1) as i128;
+        let to_track =
+            |ticks: u64| (ticks as i128 * rep.track_timescale as i128 / timescale) as i64;
+        let loop_offset = idx / rep.segment_count() * rep.loop_duration;
+        let shift = to_track(loop_offset) - to_track(rep.first_time);
+        Some(shift_decode_times(&data, shift))
+    }
+
+    /// Representation and segment number of a live media segment request.
+    pub fn target(&self, path: &str) -> Option<Target> {
+        let (rep_idx, file) = path.strip_prefix(LIVE_PREFIX)?.split_once('/')?;
+        let rep = self.representations.get(rep_idx.parse::<usize>().ok()?)?;
+        Some(Target {
+            representation: Some(rep.representation.id.clone()),
+            segment_number: file.strip_suffix(".m4s").and_then(|n| n.parse().ok()),
+        })
+    }
+
+    /// Answers requests below `/live/`, `None` for other paths.
+    pub fn handle(&self, request: &Request, now: SystemTime) -> Option<Response> {
+        let path = request.path.strip_prefix(LIVE_PREFIX)?;
+        if request.method != "GET" && request.method != "HEAD" {
+            return None;
+        }
+        let response = match format!("{}{}", LIVE_PREFIX, path).as_str() {
+            LIVE_MANIFEST_PATH => {
+                let time_url = match request.header("Host") {
+                    Some(host) => format!("http://{}{}", host, LIVE_TIME_PATH),
+                    None => LIVE_TIME_PATH.to_string(),
+                };
+                Response::new(
+                    200,
+                    "application/dash+xml",
+                    self.manifest(now, &time_url).into_bytes(),
+                )
+                .header("Cache-Control", "no-cache")
+            }
+            LIVE_TIME_PATH => Response::new(200, "text/plain", format_time(now).into_bytes())
+                .header("Cache-Control", "no-cache"),
+            _ => {
+                let (rep_idx, file) = path.split_once('/')?;
+                let rep_idx: usize = rep_idx.parse().ok()?;
+                let rep = self.representations.get(rep_idx)?;
+                match file {
+                    "init.mp4" => serve::file_response(request, &rep.init_path),
+                    file => {
+                        let number: u64 = file.strip_suffix(".m4s")?.parse().ok()?;
+                        match self.segment(rep_idx, number, now) {
+                            Some(data) => serve::bytes_response(request, data, "video/iso.segment"),
+                            None => Response::error(404),
+                        }
+                    }
+                }
+            }
+        };
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LiveOptions, LiveStream};
+    use crate::isobmff::{self, tests::init_segment, tests::media_segment};
+    use std::time::{Duration, SystemTime};
+
+    fn after(start: SystemTime, seconds: f64) -> SystemTime {
+        start + Duration::from_secs_f64(seconds)
+    }
+
+    /// Mirror of one audio representation with a SegmentTimeline of `timeline`.
+    fn write_mirror(name: &str, timeline: &str) -> std::path::PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("dash-mirror-live-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(directory.join("audio")).unwrap();
+        let manifest = r#"<MPD type="static" mediaPresentationDuration="PT4S">
+            <Period duration="PT4S">
+            <AdaptationSet mimeType="audio/mp4" lang="en">
+            <SegmentTemplate timescale="1000" media="audio/$Time$.m4s" initialization="audio/init.mp4">
+            <SegmentTimeline>{timeline}</SegmentTimeline>
+            </SegmentTemplate>
+            <Representation id="a1" bandwidth="128000" codecs="mp4a.40.2"/>
+            </AdaptationSet>
+            </Period>
+            </MPD>"#;
+        std::fs::write(
+            directory.join("manifest.mpd"),
+            manifest.replace("{timeline}", timeline),
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("audio/init.mp4"),
+            init_segment(1, 48000, b"soun"),
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("audio/10000.m4s"),
+            media_segment(1, 480000, 48000, &[b"a", b"b"]),
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("audio/12000.m4s"),
+            media_segment(1, 576000, 48000, &[b"c", b"d"]),
+        )
+        .unwrap();
+        directory
+    }
+
+    #[test]
+    fn rejects_unusable_timelines() {
+        let start = SystemTime::UNIX_EPOCH;
+        let options = LiveOptions {
+            time_shift_buffer_depth: 4.0,
+            looping: true,
+        };
+        for (name, timeline) in [
+            ("zero", r#"<S t="10000" d="0" r="1"/>"#),
+            (
+                "backwards",
+                r#"<S t="10000" d="2000"/><S t="8000" d="2000"/>"#,
+            ),
+        ] {
+            let directory = write_mirror(name, timeline);
+            assert!(LiveStream::new(&directory, options.clone(), start).is_err());
+            std::fs::remove_dir_all(directory).unwrap();
+        }
+    }
+
+    #[test]
+    fn looping_live_stream() {
+        let directory = write_mirror("loop", r#"<S t="10000" d="2000" r="1"/>"#);
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let options = LiveOptions {
+            time_shift_buffer_depth: 4.0,
+            looping: true,
+        };
+        let live = LiveStream::new(&directory, options, start).unwrap();
+
+        let mpd = live.manifest(after(start, 5.0), "/live/time");
+        assert!(mpd.contains("type=\"dynamic\""));
+        assert!(mpd.contains("availabilityStartTime=\"1970-01-12T13:46:40.000Z\""));
+        assert!(mpd.contains("startNumber=\"1\""));
+        assert!(mpd.contains("<S t=\"0\" d=\"2000\" r=\"1\"/>"));
+
+        let mpd = live.manifest(after(start, 9.0), "/live/time");
+        assert!(mpd.contains("startNumber=\"3\""));
+        assert!(mpd.contains("<S t=\"4000\" d=\"2000\" r=\"1\"/>"));
+        assert!(mpd.contains("minimumUpdatePeriod"));
+
+        assert!(live.segment(0, 5, after(start, 9.0)).is_none());
+        assert!(live.segment(0, 1, after(start, 9.0)).is_none());
+        let segment = live.segment(0, 3, after(start, 9.0)).unwrap();
+        let fragments = isobmff::parse_track_fragments(&segment, &[]);
+        assert_eq!(fragments[0].base_media_decode_time, Some(192000));
+        let run = &fragments[0].runs[0];
+        assert_eq!(&segment[run.data_offset..run.data_offset + 2], b"ab");
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn shift_decode_times_upgrades_tfdt() {
+        // the helper writes a version 1 tfdt, rewrite it as version 0
+        let mut tree = isobmff::parse_tree(&media_segment(1, 0, 1000, &[b"xyz"]));
+        let traf = tree[1].child_mut(b"traf").unwrap();
+        traf.child_mut(b"tfdt").unwrap().data = vec![0; 8];
+        let trun = traf.child_mut(b"trun").unwrap();
+        let trun_offset = isobmff::read_u32(&trun.data, 8).unwrap() - 4;
+        trun.data[8..12].copy_from_slice(&trun_offset.to_be_bytes());
+        let segment = isobmff::write_tree(&tree);
+
+        let shifted = super::shift_decode_times(&segment, 5_000_000_000);
+        assert_eq!(shifted.len(), segment.len() + 4);
+        let fragments = isobmff::parse_track_fragments(&shifted, &[]);
+        assert_eq!(fragments[0].base_media_decode_time, Some(5_000_000_000));
+        let run = &fragments[0].runs[0];
+        assert_eq!(&shifted[run.data_offset..run.data_offset + 3], b"xyz");
+        let trun = isobmff::find_path(&shifted, &[b"moof", b"traf", b"trun"]).unwrap();
+        assert_eq!(
+            isobmff::read_u32(trun.payload(&shifted), 8),
+            Some(trun_offset + 4)
+        );
+    }
+}
@@ -1,84 +1,559 @@
 use clap::Parser;
 
-pub mod mpd;
+use dash_mirror::{
+    cenc, concat, drm, fetch, hls, layout, live, mirror, mux, order, request, serve, sign, storage,
+    throttle, verify, warc,
+};
 
-fn download(url: &str, path: &std::path::Path) {
-    let parent = path.parent();
-    if let Some(directory) = parent {
-        match std::fs::create_dir_all(directory) {
-            Ok(_) => {
-                let result = reqwest::blocking::get(url);
-                match result {
-                    Ok(response) => match response.bytes() {
-                        Ok(bytes) => match std::fs::write(path, bytes) {
-                            Ok(_) => println!("downloaded  url {}", url),
-                            Err(e) => eprintln!("HTTP get failure : url {} error {}", url, e),
-                        },
-                        Err(_) => todo!(),
-                    },
-                    Err(e) => eprintln!("HTTP get failure : {}", e),
-                }
-            }
-            Err(e) => eprintln!("Could not create parent directory {}", e),
-        }
-    } else {
-        eprintln!("Could not get parent directory");
-    }
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Export ContentProtection information (PSSH boxes and key IDs per track) as JSON
+    Inspect {
+        /// Manifest file to inspect
+        manifest: String,
+        /// JSON file to write, printed to stdout if not given
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Write a clear copy of a mirror whose segments are protected with cenc or cbcs
+    Decrypt {
+        /// Mirror directory containing manifest.mpd
+        input_directory: String,
+        /// Output folder to store the decrypted mirror
+        #[arg(short, long, default_value_t = {"decrypted".to_string()})]
+        output_directory: String,
+        /// Key as KID:key in hex, can be repeated
+        #[arg(long = "key")]
+        keys: Vec<String>,
+        /// File with one KID:key pair per line
+        #[arg(long)]
+        key_file: Option<String>,
+    },
+    /// Write HLS (fMP4) playlists next to the manifest of a mirror
+    Hls {
+        /// Mirror directory containing manifest.mpd
+        input_directory: String,
+    },
+    /// Serve a mirror over HTTP with Range and CORS support
+    Serve {
+        /// Mirror directory to serve
+        directory: String,
+        /// Address to listen on
+        #[arg(long, default_value_t = {"127.0.0.1".to_string()})]
+        bind: String,
+        /// Port to listen on
+        #[arg(short, long, default_value_t = 8080)]
+        port: u16,
+        /// Also publish the mirror as a simulated live stream at /live/manifest.mpd
+        #[arg(long)]
+        live: bool,
+        /// Seconds of content kept available behind the live edge
+        #[arg(long, default_value_t = 30.0, requires = "live")]
+        time_shift_buffer_depth: f64,
+        /// Start the live stream again from the first segment after the last one
+        #[arg(long = "loop", requires = "live")]
+        looping: bool,
+        /// JSON file of impairment rules: latency, bandwidth, errors, truncated bodies
+        /// and stalls per representation or segment number
+        #[arg(long)]
+        impairments: Option<String>,
+    },
+    /// Check file checksums, and the box structure and timing of every mirrored segment against the manifest
+    Verify {
+        /// Mirror directory containing manifest.mpd
+        input_directory: String,
+    },
+    /// Combine one video and one or more audio representations of a mirror into a single MP4
+    Mux {
+        /// Mirror directory containing manifest.mpd
+        input_directory: String,
+        /// MP4 file to write
+        #[arg(short, long, default_value_t = {"muxed.mp4".to_string()})]
+        output: String,
+        /// Video representation id, the highest bandwidth one if not given
+        #[arg(long)]
+        video: Option<String>,
+        /// Audio representation id, can be repeated. Defaults to the highest bandwidth
+        /// representation of every audio adaptation set
+        #[arg(long)]
+        audio: Vec<String>,
+        /// Index of the period to mux
+        #[arg(long, default_value_t = 0)]
+        period: usize,
+        /// Write a progressive MP4 with a single moov and mdat instead of fragments
+        #[arg(long)]
+        progressive: bool,
+    },
 }
 
 #[derive(clap::Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct CommandLineArgs {
-    /// Output folder to store files
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Output folder to store files, a .tar or .zip archive or s3://bucket/prefix
     #[arg(short, long, default_value_t = {"harvest".to_string()})]
     output_directory: String,
+    #[arg(long, required = true)]
+    url: Option<String>,
+    /// After mirroring, join the init and media segments of each representation into one file
+    #[arg(long)]
+    concat: bool,
+    /// Write a sidx box into the joined files
+    #[arg(long, requires = "concat")]
+    sidx: bool,
+    /// Number of retries for network errors, 5xx and 429 responses
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+    /// Update an existing mirror: re-check segments with conditional requests and replace changed ones
+    #[arg(long)]
+    update: bool,
+    /// Delete files of segments which are no longer in the manifest
+    #[arg(long)]
+    prune: bool,
+    /// Order of segment downloads; interleaved and lowest-bitrate leave a playable prefix if interrupted
+    #[arg(long, value_enum, default_value_t)]
+    order: order::Order,
+    /// How segment urls map to local files; the other subcommands read mirrors in the path layout
+    #[arg(long, value_enum, default_value_t)]
+    layout: layout::Layout,
+    /// Append the query of the manifest url to every segment request
+    #[arg(long)]
+    propagate_query: bool,
+    /// Extra request header as "Name: value", can be repeated
+    #[arg(short = 'H', long = "header")]
+    headers: Vec<String>,
+    /// User-Agent header for all requests
+    #[arg(short = 'A', long)]
+    user_agent: Option<String>,
+    /// Referer header for all requests
+    #[arg(long)]
+    referer: Option<String>,
+    /// Cookie as name=value, or several separated by ';', sent to the manifest host
+    #[arg(short = 'b', long = "cookie")]
+    cookies: Vec<String>,
+    /// Netscape format cookie file to read cookies from and save received cookies to
+    #[arg(long)]
+    cookie_jar: Option<String>,
+    /// Basic authentication as user:password, sent to the manifest host only
+    #[arg(short = 'u', long, conflicts_with = "bearer")]
+    user: Option<String>,
+    /// Bearer token, sent to the manifest host only
+    #[arg(long)]
+    bearer: Option<String>,
+    /// Read credentials from ~/.netrc, or $NETRC
+    #[arg(long, conflicts_with = "netrc_file")]
+    netrc: bool,
+    /// Read credentials from this netrc file
     #[arg(long)]
-    url: String,
+    netrc_file: Option<String>,
+    /// Proxy for all requests, http://, https://, socks5:// or socks5h://
+    #[arg(short = 'x', long)]
+    proxy: Option<String>,
+    /// PEM file with additional trusted CA certificates
+    #[arg(long)]
+    cacert: Option<String>,
+    /// Client certificate, PKCS #12, or PEM together with --key
+    #[arg(short = 'E', long)]
+    cert: Option<String>,
+    /// PKCS #8 PEM private key for a PEM --cert
+    #[arg(long, requires = "cert")]
+    key: Option<String>,
+    /// Password of a PKCS #12 --cert
+    #[arg(long, requires = "cert")]
+    cert_password: Option<String>,
+    /// Do not verify server certificates
+    #[arg(short = 'k', long)]
+    insecure: bool,
+    /// Seconds to wait for a connection
+    #[arg(long)]
+    connect_timeout: Option<f64>,
+    /// Seconds a request may take, including reading the body [default: 30, none while
+    /// rate limited]
+    #[arg(long)]
+    timeout: Option<f64>,
+    /// Use addr for host, as host:port:addr[,addr]..., can be repeated
+    #[arg(long)]
+    resolve: Vec<String>,
+    /// Maximum download rate in bytes per second, with k, m or g suffix
+    #[arg(long)]
+    limit_rate: Option<String>,
+    /// Rate for a time of day as HH:MM-HH:MM=rate, 0 for no limit, can be repeated
+    #[arg(long)]
+    schedule: Vec<String>,
+    /// Offset from UTC of the --schedule times as +HH:MM or -HH:MM [default: +00:00]
+    #[arg(long, allow_hyphen_values = true)]
+    utc_offset: Option<String>,
+    /// Maximum requests per second to each host
+    #[arg(long)]
+    host_rate: Option<f64>,
+    /// Seconds to wait between requests
+    #[arg(long, default_value_t = 0.0)]
+    delay: f64,
+    /// Up to this many seconds added to --delay at random
+    #[arg(long, default_value_t = 0.0)]
+    jitter: f64,
+    /// Sign segment urls with a command, run with the url as last argument, printing the signed url
+    #[arg(long, conflicts_with = "sign_key")]
+    sign_command: Option<String>,
+    /// Sign segment urls with an HMAC-SHA256 token using this key
+    #[arg(long)]
+    sign_key: Option<String>,
+    /// Signed message, with {url}, {path}, {expires} and {sep} placeholders
+    #[arg(long, requires = "sign_key", default_value_t = sign::DEFAULT_SIGN_MESSAGE.to_string())]
+    sign_message: String,
+    /// Signed url, with {url}, {path}, {expires}, {sep} and {token} placeholders
+    #[arg(long, requires = "sign_key", default_value_t = sign::DEFAULT_SIGN_TEMPLATE.to_string())]
+    sign_template: String,
+    /// Seconds until signed urls expire
+    #[arg(long, requires = "sign_key", default_value_t = 3600)]
+    sign_ttl: u64,
+    /// Endpoint of an S3-compatible object store for s3:// outputs, defaults to AWS_ENDPOINT_URL
+    #[arg(long)]
+    s3_endpoint: Option<String>,
+    /// Also record every request and response in a WARC 1.1 file, gzip compressed if it ends in .gz, indexed in a .cdxj file next to it
+    #[arg(long)]
+    warc: Option<String>,
+}
+
+fn http_options(args: &CommandLineArgs) -> Result<fetch::HttpOptions, String> {
+    let mut schedule = throttle::RateSchedule::new(
+        args.limit_rate
+            .as_deref()
+            .map(throttle::parse_rate)
+            .transpose()?
+            .filter(|rate| *rate > 0),
+    );
+    for window in &args.schedule {
+        schedule.add_window(window)?;
+    }
+    if let Some(offset) = &args.utc_offset {
+        schedule.utc_offset = throttle::parse_utc_offset(offset)?;
+    }
+    Ok(fetch::HttpOptions {
+        proxy: args.proxy.clone(),
+        ca_bundle: args.cacert.as_ref().map(Into::into),
+        client_certificate: args.cert.as_ref().map(Into::into),
+        client_key: args.key.as_ref().map(Into::into),
+        client_certificate_password: args.cert_password.clone(),
+        insecure: args.insecure,
+        connect_timeout: args.connect_timeout.map(seconds).transpose()?,
+        timeout: args.timeout.map(seconds).transpose()?,
+        resolve: args
+            .resolve
+            .iter()
+            .map(|entry| fetch::parse_resolve(entry))
+            .collect::<Result<_, _>>()?,
+        rate_limiter: (!schedule.is_unlimited())
+            .then(|| std::sync::Arc::new(throttle::RateLimiter::new(schedule))),
+    })
+}
+
+fn seconds(seconds: f64) -> Result<std::time::Duration, String> {
+    std::time::Duration::try_from_secs_f64(seconds)
+        .map_err(|_| format!("invalid number of seconds {}", seconds))
+}
+
+fn polite_options(args: &CommandLineArgs) -> Result<throttle::PoliteOptions, String> {
+    if args
+        .host_rate
+        .is_some_and(|rate| rate.is_nan() || rate <= 0.0)
+    {
+        return Err("--host-rate must be positive".to_string());
+    }
+    Ok(throttle::PoliteOptions {
+        host_rate: args.host_rate,
+        delay: seconds(args.delay)?,
+        jitter: seconds(args.jitter)?,
+    })
+}
+
+fn request_options(args: &CommandLineArgs, url: &str) -> Result<request::RequestOptions, String> {
+    let mut options = request::RequestOptions {
+        headers: args
+            .headers
+            .iter()
+            .map(|header| request::parse_header(header))
+            .collect::<Result<_, _>>()?,
+        user_agent: args.user_agent.clone(),
+        referer: args.referer.clone(),
+        ..Default::default()
+    };
+    if let Some(user) = &args.user {
+        options.auth = Some(request::Auth::basic(user));
+    } else if let Some(token) = &args.bearer {
+        options.auth = Some(request::Auth::Bearer(token.clone()));
+    }
+    options.auth_host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.to_ascii_lowercase()));
+    let netrc_path = match &args.netrc_file {
+        Some(path) => Some(std::path::PathBuf::from(path)),
+        None if args.netrc => request::Netrc::default_path(),
+        None => None,
+    };
+    if let Some(path) = netrc_path {
+        options.netrc = Some(request::Netrc::load(&path)?);
+    }
+    if args.cookie_jar.is_some() || !args.cookies.is_empty() {
+        let mut jar = match &args.cookie_jar {
+            Some(path) => request::CookieJar::load(std::path::Path::new(path))?,
+            None => Default::default(),
+        };
+        if let Ok(mut origin) = reqwest::Url::parse(url) {
+            origin.set_path("/");
+            origin.set_query(None);
+            for cookie in args.cookies.iter().flat_map(|cookies| cookies.split(';')) {
+                jar.store(origin.as_str(), cookie.trim());
+            }
+        }
+        options.cookies = Some(std::sync::Mutex::new(jar));
+    }
+    Ok(options)
+}
+
+fn inspect(manifest: &str, output: Option<&str>) {
+    match std::fs::read_to_string(manifest) {
+        Ok(manifest_text) => {
+            let report = drm::inspect(manifest_text);
+            let json = serde_json::to_string_pretty(&report).unwrap_or_default();
+            match output {
+                Some(path) => match std::fs::write(path, json) {
+                    Ok(_) => println!("content protection written to {}", path),
+                    Err(e) => eprintln!("Could not write {} error {}", path, e),
+                },
+                None => println!("{}", json),
+            }
+        }
+        Err(e) => {
+            println!("Error: reading manifest {}", e);
+        }
+    }
+}
+
+fn decrypt(input_directory: &str, output_directory: &str, keys: &[String], key_file: Option<&str>) {
+    let mut key_map = cenc::KeyMap::new();
+    for key in keys {
+        match cenc::parse_key(key) {
+            Some((kid, key)) => {
+                key_map.insert(kid, key);
+            }
+            None => {
+                eprintln!("Invalid key {}, expected KID:key in hex", key);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = key_file {
+        match cenc::read_key_file(path) {
+            Ok(keys) => key_map.extend(keys),
+            Err(e) => {
+                eprintln!("Could not read key file {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let failures = cenc::decrypt_mirror(input_directory, output_directory, &key_map);
+    if failures > 0 {
+        eprintln!("{} files could not be decrypted", failures);
+        std::process::exit(1);
+    }
 }
 
 fn main() {
     let args = CommandLineArgs::parse();
-    let url = args.url;
-    println!("url {}", url);
-    let mut manifest_path_str = args.output_directory.clone();
-    manifest_path_str.push(std::path::MAIN_SEPARATOR);
-    manifest_path_str.push_str("manifest.mpd");
-    let manifest_path = std::path::Path::new(&manifest_path_str);
-    download(&url, manifest_path);
-
-    match std::fs::read_to_string(manifest_path) {
-        Ok(manifest_text) => match mpd::get_fragment_urls(manifest_text, &url) {
-            Some(url_info) => {
-                let iterator = url_info.urls.iter();
-                for (url_idx, url) in iterator.enumerate() {
-                    if url.starts_with(&url_info.base_url) {
-                        let mut path_str = args.output_directory.clone();
-                        path_str.push(std::path::MAIN_SEPARATOR);
-                        path_str.push_str(&url[url_info.base_url.len()..]);
-                        let end = path_str.find("?").unwrap_or(path_str.len());
-                        path_str.truncate(end);
-                        let path = std::path::Path::new(&path_str);
-                        if path.exists() {
-                            println!(
-                                "Segment {} url {} path {} exists, skip",
-                                url_idx, url, path_str
-                            );
-                        } else {
-                            download(url, path);
-                        }
-                    } else {
-                        println!(
-                            "Segment {} url {} is not start with base_url {}",
-                            url_idx, url, url_info.base_url
-                        );
+    if let Some(command) = args.command {
+        match command {
+            Command::Inspect { manifest, output } => inspect(&manifest, output.as_deref()),
+            Command::Decrypt {
+                input_directory,
+                output_directory,
+                keys,
+                key_file,
+            } => decrypt(
+                &input_directory,
+                &output_directory,
+                &keys,
+                key_file.as_deref(),
+            ),
+            Command::Hls { input_directory } => {
+                let failures = hls::write_playlists(&input_directory);
+                if failures > 0 {
+                    eprintln!("{} playlists could not be written", failures);
+                    std::process::exit(1);
+                }
+            }
+            Command::Serve {
+                directory,
+                bind,
+                port,
+                live,
+                time_shift_buffer_depth,
+                looping,
+                impairments,
+            } => {
+                let live = live.then_some(live::LiveOptions {
+                    time_shift_buffer_depth,
+                    looping,
+                });
+                if let Err(e) = serve::serve(
+                    &directory,
+                    &format!("{}:{}", bind, port),
+                    live,
+                    impairments.as_deref(),
+                ) {
+                    eprintln!("Could not serve {}", e);
+                    std::process::exit(1);
+                }
+            }
+            Command::Verify { input_directory } => {
+                let issues = verify::verify_mirror(&input_directory);
+                if issues > 0 {
+                    eprintln!("{} issues found", issues);
+                    std::process::exit(1);
+                }
+            }
+            Command::Mux {
+                input_directory,
+                output,
+                video,
+                audio,
+                period,
+                progressive,
+            } => {
+                let options = mux::MuxOptions {
+                    period_idx: period,
+                    video,
+                    audio,
+                    progressive,
+                };
+                match mux::mux_mirror(&input_directory, &output, &options) {
+                    Ok(_) => println!("muxed into {}", output),
+                    Err(e) => {
+                        eprintln!("Could not mux {}", e);
+                        std::process::exit(1);
                     }
                 }
             }
-            None => {
-                eprintln!("fragement urls not available")
+        }
+        return;
+    }
+    let url = args.url.clone().unwrap_or_default();
+    println!("url {}", url);
+    let url = match fetch::FileFetcher::path(&url) {
+        // relative segment urls are resolved against the manifest directory
+        Some(path) if !url.starts_with("file:") => std::path::absolute(path)
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or(url),
+        _ => url,
+    };
+    let fetcher =
+        match http_options(&args).and_then(|options| fetch::fetcher_for_url(&url, &options)) {
+            Ok(fetcher) => fetcher,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
             }
+        };
+    let request_options = match request_options(&args, &url) {
+        Ok(request_options) => request_options,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let polite_options = match polite_options(&args) {
+        Ok(polite_options) => polite_options,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let fetcher = throttle::PoliteFetcher::new(fetcher.as_ref(), polite_options);
+    let warc = match args.warc.as_deref().map(warc::WarcWriter::create) {
+        Some(Ok(writer)) => Some(warc::WarcFetcher::new(&fetcher, writer)),
+        Some(Err(e)) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        None => None,
+    };
+    let fetcher = request::RequestFetcher {
+        fetcher: match &warc {
+            Some(warc) => warc,
+            None => &fetcher,
         },
+        options: &request_options,
+    };
+    if args.concat && args.layout != layout::Layout::Path {
+        eprintln!("Error: --concat needs the path layout");
+        std::process::exit(1);
+    }
+    let mut options = mirror::MirrorOptions::new(&url, &args.output_directory);
+    options.retries = args.retries;
+    options.update = args.update;
+    options.prune = args.prune;
+    options.order = args.order;
+    options.layout = args.layout;
+    options.url_options.propagate_query = args.propagate_query;
+    if let Some(command) = &args.sign_command {
+        match sign::CommandSigner::new(command) {
+            Ok(signer) => options.url_transform = Some(Box::new(signer)),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(key) = &args.sign_key {
+        let mut signer = sign::HmacSigner::new(key.as_bytes());
+        signer.message = args.sign_message.clone();
+        signer.template = args.sign_template.clone();
+        signer.ttl = args.sign_ttl;
+        options.url_transform = Some(Box::new(signer));
+    }
+    let storage = match storage::open(&args.output_directory, args.s3_endpoint.as_deref()) {
+        Ok(storage) => storage,
         Err(e) => {
-            println!("Error: reading manifest {}", e);
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let result = mirror::mirror_to(&fetcher, storage.as_ref(), &options);
+    if let Some(path) = &args.cookie_jar {
+        if let Err(e) = request_options.save_cookies(std::path::Path::new(path)) {
+            eprintln!("Error: saving cookies {}", e);
+        }
+    }
+    if let Some(warc) = &warc {
+        let mut writer = warc.writer.lock().unwrap();
+        match writer.finish() {
+            Ok(index_path) => println!(
+                "warc {} records {} index {}",
+                writer.path, writer.records, index_path
+            ),
+            Err(e) => eprintln!("Error: writing WARC {}", e),
+        }
+    }
+    match result {
+        Ok(summary) => println!(
+            "downloaded {} skipped {} failed {}",
+            summary.downloaded, summary.skipped, summary.failed
+        ),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if args.concat {
+        let Some(output_directory) = storage.local_directory() else {
+            eprintln!("Error: --concat needs an output directory");
+            std::process::exit(1);
+        };
+        let failures = concat::concat_mirror(output_directory, args.sidx);
+        if failures > 0 {
+            eprintln!("{} representations could not be joined", failures);
+            std::process::exit(1);
         }
     }
 }
@@ -0,0 +1,898 @@
+use std::io::Write;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::checksum::{self, Checksum};
+use crate::fetch::{self, FetchRequest, FetchResponse, Fetcher};
+use crate::journal::{self, Action, Journal};
+use crate::layout::{self, Layout};
+use crate::mpd;
+use crate::order::{self, Order};
+use crate::sign::UrlTransform;
+use crate::storage::{self, FileWriter, Storage};
+
+const MAX_REDIRECTS: usize = 10;
+
+pub struct MirrorOptions {
+    pub url: String,
+    pub output_directory: String,
+    /// Additional attempts for network errors, 5xx and 429 responses.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every further attempt.
+    pub retry_delay: Duration,
+    /// Applied to every segment url before it is fetched.
+    pub url_transform: Option<Box<dyn UrlTransform>>,
+    pub url_options: mpd::UrlOptions,
+    /// Re-check existing segments with conditional requests and replace changed ones.
+    pub update: bool,
+    /// Delete files of segments which are no longer in the manifest.
+    pub prune: bool,
+    /// Order of segment downloads.
+    pub order: Order,
+    /// How segment urls map to paths in the output.
+    pub layout: Layout,
+}
+
+impl MirrorOptions {
+    pub fn new(url: &str, output_directory: &str) -> MirrorOptions {
+        MirrorOptions {
+            url: url.to_string(),
+            output_directory: output_directory.to_string(),
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+            url_transform: None,
+            url_options: mpd::UrlOptions::default(),
+            update: false,
+            prune: false,
+            order: Order::default(),
+            layout: Layout::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct MirrorSummary {
+    pub downloaded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// Performs a request, following redirects.
+pub fn fetch_following_redirects(
+    fetcher: &dyn Fetcher,
+    request: FetchRequest,
+) -> Result<FetchResponse, String> {
+    follow_redirects(fetcher, request, None)
+}
+
+/// Performs a request, following redirects, with the final body written to `sink` if
+/// there is one.
+fn follow_redirects(
+    fetcher: &dyn Fetcher,
+    request: FetchRequest,
+    mut sink: Option<&mut dyn Write>,
+) -> Result<FetchResponse, String> {
+    let mut request = request;
+    for _ in 0..=MAX_REDIRECTS {
+        let mut response = match sink.as_deref_mut() {
+            Some(sink) => fetcher.fetch_to(&request, sink)?,
+            None => fetcher.fetch(&request)?,
+        };
+        if !response.is_redirect() {
+            if response.url.is_empty() {
+                response.url = request.url;
+            }
+            return Ok(response);
+        }
+        let location = response
+            .header("Location")
+            .ok_or(format!("redirect without Location from {}", request.url))?;
+        let url = fetch::resolve_location(&request.url, location)
+            .ok_or(format!("invalid redirect location {}", location))?;
+        println!("redirect {} -> {}", request.url, url);
+        request.url = url;
+    }
+    Err(format!("too many redirects for {}", request.url))
+}
+
+fn is_retryable(result: &Result<FetchResponse, String>) -> bool {
+    match result {
+        Ok(response) => response.status == 429 || response.status >= 500,
+        Err(_) => true,
+    }
+}
+
+/// Performs `request`, following redirects and retrying transient failures. Only
+/// successful responses are returned.
+pub fn fetch(
+    fetcher: &dyn Fetcher,
+    request: &FetchRequest,
+    options: &MirrorOptions,
+) -> Result<FetchResponse, String> {
+    fetch_with(fetcher, request, options, None)
+}
+
+/// Like `fetch`, but the body is streamed to `writer` instead of being returned.
+pub fn fetch_to(
+    fetcher: &dyn Fetcher,
+    request: &FetchRequest,
+    options: &MirrorOptions,
+    writer: &mut SegmentWriter,
+) -> Result<FetchResponse, String> {
+    fetch_with(fetcher, request, options, Some(writer))
+}
+
+fn fetch_with(
+    fetcher: &dyn Fetcher,
+    request: &FetchRequest,
+    options: &MirrorOptions,
+    mut writer: Option<&mut SegmentWriter>,
+) -> Result<FetchResponse, String> {
+    let url = &request.url;
+    let mut attempt = 0;
+    loop {
+        let sink = match writer.as_deref_mut() {
+            Some(writer) => {
+                // a body cut short by a network error must not be continued
+                writer.reset()?;
+                Some(writer as &mut dyn Write)
+            }
+            None => None,
+        };
+        let result = follow_redirects(fetcher, request.clone(), sink);
+        if attempt < options.retries && is_retryable(&result) {
+            let delay = options.retry_delay * 2u32.pow(attempt);
+            match &result {
+                Ok(response) => eprintln!(
+                    "HTTP status {} for url {}, retry in {:?}",
+                    response.status, url, delay
+                ),
+                Err(e) => eprintln!(
+                    "HTTP get failure : url {} error {}, retry in {:?}",
+                    url, e, delay
+                ),
+            }
+            std::thread::sleep(delay);
+            attempt += 1;
+            continue;
+        }
+        return match result {
+            // only returned for conditional requests
+            Ok(response) if response.is_success() || response.status == 304 => Ok(response),
+            Ok(response) => Err(format!("HTTP status {}", response.status)),
+            Err(e) => Err(e),
+        };
+    }
+}
+
+/// File of a storage being written, with its size and SHA-256 computed along the way.
+pub struct SegmentWriter<'a> {
+    file: Box<dyn FileWriter + 'a>,
+    sha256: Sha256,
+    pub size: u64,
+}
+
+impl<'a> SegmentWriter<'a> {
+    pub fn new(file: Box<dyn FileWriter + 'a>) -> SegmentWriter<'a> {
+        SegmentWriter {
+            file,
+            sha256: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    pub fn reset(&mut self) -> Result<(), String> {
+        self.file.reset()?;
+        self.sha256 = Sha256::new();
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Lower case hex SHA-256 of the data written so far.
+    pub fn sha256(&self) -> String {
+        self.sha256
+            .clone()
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    pub fn commit(self) -> Result<(), String> {
+        self.file.commit()
+    }
+}
+
+impl Write for SegmentWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.sha256.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Streams `request` to `path` of `storage`, returning the final response without its
+/// body and the journal entry of the file.
+pub fn download(
+    fetcher: &dyn Fetcher,
+    request: &FetchRequest,
+    storage: &dyn Storage,
+    url: &str,
+    path: &str,
+    options: &MirrorOptions,
+) -> Result<(FetchResponse, journal::Entry), String> {
+    let mut writer = SegmentWriter::new(storage.create(path)?);
+    let response = fetch_to(fetcher, request, options, &mut writer)?;
+    let entry = done_entry(url, path, &response, &writer);
+    writer.commit()?;
+    println!("downloaded  url {}", request.url);
+    Ok((response, entry))
+}
+
+fn done_entry(
+    url: &str,
+    relative_path: &str,
+    response: &FetchResponse,
+    writer: &SegmentWriter,
+) -> journal::Entry {
+    let mut entry = journal::Entry::new(url, relative_path, journal::State::Done);
+    entry.size = Some(writer.size);
+    entry.sha256 = Some(writer.sha256());
+    entry.status = Some(response.status);
+    entry.etag = response.header("ETag").map(str::to_string);
+    entry.last_modified = response.header("Last-Modified").map(str::to_string);
+    entry
+}
+
+/// Reports representations added to or removed from the manifest since the last run.
+fn compare_manifests(previous: Option<&mpd::Manifest>, current: &mpd::Manifest) {
+    let representations =
+        |manifest: Option<&mpd::Manifest>| -> std::collections::BTreeSet<(usize, String)> {
+            manifest
+                .into_iter()
+                .flat_map(|manifest| manifest.representations())
+                .map(|representation| (representation.info.period_idx, representation.info.id))
+                .collect()
+        };
+    let (previous, current) = (representations(previous), representations(Some(current)));
+    for (period_idx, id) in current.difference(&previous) {
+        println!("update: period {} representation {} added", period_idx, id);
+    }
+    for (period_idx, id) in previous.difference(&current) {
+        println!(
+            "update: period {} representation {} removed",
+            period_idx, id
+        );
+    }
+}
+
+/// Mirrors the manifest at `options.url` and every segment it references into the
+/// storage for `options.output_directory`. Segments which already exist are skipped.
+pub fn mirror(fetcher: &dyn Fetcher, options: &MirrorOptions) -> Result<MirrorSummary, String> {
+    let storage = storage::open(&options.output_directory, None)?;
+    mirror_to(fetcher, storage.as_ref(), options)
+}
+
+/// Mirrors into `storage` and finishes it, also when the mirror fails part way.
+pub fn mirror_to(
+    fetcher: &dyn Fetcher,
+    storage: &dyn Storage,
+    options: &MirrorOptions,
+) -> Result<MirrorSummary, String> {
+    let result = mirror_files(fetcher, storage, options);
+    let finished = storage.finish();
+    let summary = result?;
+    finished.map(|_| summary)
+}
+
+fn mirror_files(
+    fetcher: &dyn Fetcher,
+    storage: &dyn Storage,
+    options: &MirrorOptions,
+) -> Result<MirrorSummary, String> {
+    let previous_manifest = match options.update {
+        true => storage
+            .read(layout::MANIFEST_FILE_NAME)?
+            .map(|data| String::from_utf8_lossy(&data).to_string()),
+        false => None,
+    };
+    let request = FetchRequest::new(&options.url);
+    let response = fetch(fetcher, &request, options)
+        .and_then(|response| {
+            storage.write(layout::MANIFEST_FILE_NAME, &response.body)?;
+            Ok(response)
+        })
+        .map_err(|e| format!("manifest {} : {}", options.url, e))?;
+    println!("downloaded  url {}", request.url);
+    let manifest_text = String::from_utf8_lossy(&response.body).to_string();
+    // relative urls resolve against the location the manifest was finally served from
+    let manifest = mpd::parse_manifest(manifest_text.clone(), &response.url, &options.url_options)
+        .ok_or("fragement urls not available")?;
+    if let Some(previous_manifest) = previous_manifest {
+        if previous_manifest == manifest_text {
+            println!("update: manifest unchanged");
+        } else {
+            println!("update: manifest changed");
+            let previous =
+                mpd::parse_manifest(previous_manifest, &response.url, &Default::default());
+            compare_manifests(previous.as_ref(), &manifest);
+        }
+    }
+
+    let mut summary = MirrorSummary::default();
+    let representations: Vec<_> = manifest.representations().collect();
+    let mut paths = layout::Paths::new(manifest.base_url(), options.layout);
+    let mut journal = Journal::open(storage)?;
+    let mut changes = journal::Changes::default();
+    // segments are planned and downloaded as the manifest yields them
+    for (url_idx, ordered) in order::ordered_urls(&representations, options.order).enumerate() {
+        let representation = &representations[ordered.representation_idx].info;
+        let url = &ordered.url;
+        let relative_path = match paths.path(representation, ordered.number, url) {
+            Ok(relative_path) => relative_path,
+            Err(e) => {
+                println!("Segment {} url {} skipped : {}", url_idx, url, e);
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        let relative_path = &relative_path;
+        let action = journal.plan(url, relative_path, &mut changes)?;
+        if action == Action::Adopt {
+            let (size, sha256) = storage
+                .hash(relative_path)?
+                .ok_or(format!("{} : file disappeared", relative_path))?;
+            journal.record(journal::Entry::hashed(url, relative_path, size, sha256))?;
+        }
+        let existing = matches!(action, Action::Skip | Action::Adopt);
+        if existing && !options.update {
+            println!(
+                "Segment {} url {} path {} exists, skip",
+                url_idx, url, relative_path
+            );
+            summary.skipped += 1;
+            continue;
+        }
+        let parameters = representation.request_parameters.clone();
+        let fetch_url = parameters.apply(url);
+        let fetch_url = match &options.url_transform {
+            Some(transform) => match transform.transform(&fetch_url) {
+                Ok(fetch_url) => fetch_url,
+                Err(e) => {
+                    eprintln!("Could not sign url {} error {}", url, e);
+                    let mut entry = journal::Entry::new(url, relative_path, journal::State::Failed);
+                    entry.error = Some(e);
+                    journal.record(entry)?;
+                    summary.failed += 1;
+                    continue;
+                }
+            },
+            None => fetch_url,
+        };
+        let mut request = FetchRequest {
+            url: fetch_url,
+            headers: parameters.headers,
+        };
+        if existing {
+            let previous = journal.get(url).cloned().ok_or("journal entry missing")?;
+            if let Some(etag) = &previous.etag {
+                request
+                    .headers
+                    .push(("If-None-Match".to_string(), etag.clone()));
+            }
+            if let Some(last_modified) = &previous.last_modified {
+                request
+                    .headers
+                    .push(("If-Modified-Since".to_string(), last_modified.clone()));
+            }
+            // the new body replaces the file only if it differs
+            let mut writer = SegmentWriter::new(storage.create(relative_path)?);
+            match fetch_to(fetcher, &request, options, &mut writer) {
+                Ok(response) if response.status == 304 => {
+                    println!("Segment {} url {} not modified", url_idx, url);
+                    summary.skipped += 1;
+                }
+                Ok(response) => {
+                    let entry = done_entry(url, relative_path, &response, &writer);
+                    if entry.sha256 == previous.sha256 {
+                        println!("Segment {} url {} unchanged", url_idx, url);
+                        summary.skipped += 1;
+                    } else {
+                        writer.commit()?;
+                        println!("Segment {} url {} changed upstream, updated", url_idx, url);
+                        summary.downloaded += 1;
+                    }
+                    journal.record(entry)?;
+                }
+                // the mirrored file is still complete, so its entry stays done
+                Err(e) => {
+                    eprintln!("HTTP get failure : url {} error {}", url, e);
+                    summary.failed += 1;
+                }
+            }
+            continue;
+        }
+        match download(fetcher, &request, storage, url, relative_path, options) {
+            Ok((_, entry)) => {
+                journal.record(entry)?;
+                summary.downloaded += 1;
+            }
+            Err(e) => {
+                eprintln!("HTTP get failure : url {} error {}", url, e);
+                let mut entry = journal::Entry::new(url, relative_path, journal::State::Failed);
+                entry.error = Some(e);
+                journal.record(entry)?;
+                summary.failed += 1;
+            }
+        }
+    }
+
+    changes.removed = journal.unplanned();
+    if !changes.is_empty() {
+        changes.print();
+    }
+
+    if options.prune {
+        for url in &changes.removed {
+            let Some(entry) = journal.get(url).cloned() else {
+                continue;
+            };
+            match storage.remove(&entry.path) {
+                Ok(_) => println!("pruned url {} path {}", url, entry.path),
+                Err(e) => {
+                    eprintln!("Could not prune {} : {}", entry.path, e);
+                    continue;
+                }
+            }
+            journal.record(journal::Entry::new(
+                url,
+                &entry.path,
+                journal::State::Pruned,
+            ))?;
+        }
+    }
+
+    let mut checksums = vec![Checksum::new(layout::MANIFEST_FILE_NAME, &response.body)];
+    for entry in journal.entries() {
+        if let (journal::State::Done, Some(size), Some(sha256)) =
+            (entry.state, entry.size, &entry.sha256)
+        {
+            if storage.size(&entry.path)?.is_none() {
+                continue;
+            }
+            checksums.push(Checksum {
+                path: entry.path.clone(),
+                size,
+                sha256: sha256.clone(),
+            });
+        }
+    }
+    checksum::write_checksums(storage, &checksums)?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{download, mirror, mirror_to, MirrorOptions, MirrorSummary};
+    use crate::fetch::{FetchRequest, FetchResponse, Fetcher};
+    use crate::journal::{Entry, Journal, State};
+    use crate::layout::Layout;
+    use crate::mock::{MockOrigin, Route};
+    use crate::storage::{FileStorage, S3Storage};
+    use std::time::Duration;
+
+    fn options(url: &str, name: &str) -> (MirrorOptions, std::path::PathBuf) {
+        let directory = std::env::temp_dir().join(format!(
+            "dash-mirror-mirror-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let mut options = MirrorOptions::new(url, directory.to_str().unwrap());
+        options.retry_delay = Duration::from_millis(1);
+        (options, directory)
+    }
+
+    #[test]
+    fn mirror_with_host_layout() {
+        let origin = MockOrigin::synthetic_vod("http://origin.test/vod/", 2);
+        let manifest =
+            String::from_utf8(origin.body("http://origin.test/vod/manifest.mpd").unwrap())
+                .unwrap()
+                .replace(
+                    r#"lang="en">"#,
+                    r#"lang="en"><BaseURL>http://cdn.test/vod/</BaseURL>"#,
+                );
+        origin.add(
+            "http://origin.test/vod/manifest.mpd",
+            Route::Body(manifest.into_bytes()),
+        );
+        for name in ["init.mp4", "1.m4s", "2.m4s"] {
+            origin.add(
+                &format!("http://cdn.test/vod/audio/{}", name),
+                origin
+                    .route(&format!("http://origin.test/vod/audio/{}", name))
+                    .unwrap(),
+            );
+        }
+        let (mut options, directory) = options("http://origin.test/vod/manifest.mpd", "host");
+        let summary = mirror(&origin, &options).unwrap();
+        assert_eq!((summary.downloaded, summary.skipped), (3, 3));
+
+        options.layout = Layout::Host;
+        let summary = mirror(&origin, &options).unwrap();
+        assert_eq!((summary.downloaded, summary.skipped), (6, 0));
+        assert_eq!(
+            std::fs::read(directory.join("cdn.test/vod/audio/2.m4s")).unwrap(),
+            origin.body("http://cdn.test/vod/audio/2.m4s").unwrap()
+        );
+        assert!(directory.join("origin.test/vod/video/1.m4s").is_file());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn mirror_end_to_end() {
+        let origin = MockOrigin::synthetic_vod("http://origin.test/vod/", 3);
+        origin.add(
+            "http://origin.test/start.mpd",
+            Route::Redirect(302, "vod/manifest.mpd".to_string()),
+        );
+        origin.add(
+            "http://origin.test/vod/video/2.m4s",
+            Route::Flaky(
+                2,
+                503,
+                Box::new(origin.route("http://origin.test/vod/video/2.m4s").unwrap()),
+            ),
+        );
+        origin.add("http://origin.test/vod/audio/3.m4s", Route::Status(404));
+        let (options, directory) = options("http://origin.test/start.mpd", "e2e");
+
+        let summary = mirror(&origin, &options).unwrap();
+        assert_eq!(
+            summary,
+            MirrorSummary {
+                downloaded: 7,
+                skipped: 0,
+                failed: 1
+            }
+        );
+        assert!(directory.join("manifest.mpd").is_file());
+        assert_eq!(
+            std::fs::read(directory.join("video/2.m4s")).unwrap(),
+            origin.body("http://origin.test/vod/video/2.m4s").unwrap()
+        );
+        assert_eq!(
+            origin.request_count("http://origin.test/vod/video/2.m4s"),
+            3
+        );
+        assert_eq!(
+            origin.request_count("http://origin.test/vod/audio/3.m4s"),
+            1
+        );
+        assert!(!directory.join("audio/3.m4s").exists());
+        assert!(!directory.join("audio/3.m4s.part").exists());
+
+        origin.add(
+            "http://origin.test/vod/audio/3.m4s",
+            origin.route("http://origin.test/vod/audio/2.m4s").unwrap(),
+        );
+        let summary = mirror(&origin, &options).unwrap();
+        assert_eq!(
+            summary,
+            MirrorSummary {
+                downloaded: 1,
+                skipped: 7,
+                failed: 0
+            }
+        );
+        assert_eq!(
+            origin.request_count("http://origin.test/vod/video/1.m4s"),
+            1
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn mirror_to_object_store() {
+        let origin = MockOrigin::synthetic_vod("http://origin.test/vod/", 2);
+        let (endpoint, objects) = crate::storage::tests::s3_stand_in();
+        let config = crate::storage::tests::s3_config(&endpoint);
+        let storage = S3Storage::new(config, "media", "mirrors/vod").unwrap();
+        let options = MirrorOptions::new("http://origin.test/vod/manifest.mpd", "s3://media");
+        let summary = mirror_to(&origin, &storage, &options).unwrap();
+        assert_eq!((summary.downloaded, summary.failed), (6, 0));
+        let key = "/media/mirrors/vod/video/2.m4s";
+        assert_eq!(
+            objects.lock().unwrap()[key],
+            origin.body("http://origin.test/vod/video/2.m4s").unwrap()
+        );
+        for name in ["manifest.mpd", "journal.jsonl", "checksums.sha256"] {
+            assert!(objects
+                .lock()
+                .unwrap()
+                .contains_key(&format!("/media/mirrors/vod/{}", name)));
+        }
+        // resumes from the journal and sizes in the bucket
+        let summary = mirror_to(&origin, &storage, &options).unwrap();
+        assert_eq!((summary.downloaded, summary.skipped), (0, 6));
+        assert_eq!(
+            origin.request_count("http://origin.test/vod/video/2.m4s"),
+            1
+        );
+    }
+
+    #[test]
+    fn mirror_resumes_from_journal() {
+        let origin = MockOrigin::synthetic_vod("http://origin.test/vod/", 2);
+        let video = "http://origin.test/vod/video/2.m4s";
+        let body = origin.body(video).unwrap();
+        origin.add(video, Route::Status(404));
+        let (options, directory) = options("http://origin.test/vod/manifest.mpd", "journal");
+        let storage = FileStorage::new(directory.to_str().unwrap());
+        let summary = mirror(&origin, &options).unwrap();
+        assert_eq!((summary.downloaded, summary.failed), (5, 1));
+        let journal = Journal::open(&storage).unwrap();
+        assert_eq!(journal.entries().count(), 6);
+        let entry = journal.get(video).unwrap();
+        assert_eq!(entry.state, State::Failed);
+        assert_eq!(entry.error.as_deref(), Some("HTTP status 404"));
+        let entry = journal.get("http://origin.test/vod/audio/1.m4s").unwrap();
+        assert_eq!((entry.state, entry.status), (State::Done, Some(200)));
+        assert_eq!(entry.path, "audio/1.m4s");
+
+        origin.add(video, Route::Body(body));
+        std::fs::remove_file(directory.join("audio/1.m4s")).unwrap();
+        let summary = mirror(&origin, &options).unwrap();
+        assert_eq!((summary.downloaded, summary.skipped), (2, 4));
+        assert_eq!(
+            origin.request_count("http://origin.test/vod/audio/1.m4s"),
+            2
+        );
+        assert_eq!(
+            origin.request_count("http://origin.test/vod/audio/2.m4s"),
+            1
+        );
+        let journal = Journal::open(&storage).unwrap();
+        assert!(journal.entries().all(|entry| entry.state == State::Done));
+        let checksums = crate::checksum::read_checksums(directory.to_str().unwrap()).unwrap();
+        assert_eq!(checksums.len(), 7);
+        assert_eq!(checksums[0].path, "audio/1.m4s");
+        assert!(crate::verify::verify_files(directory.to_str().unwrap())
+            .unwrap()
+            .is_empty());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn mirror_update() {
+        let origin = MockOrigin::synthetic_vod("http://origin.test/vod/", 2);
+        let manifest_url = "http://origin.test/vod/manifest.mpd";
+        for name in ["video/1.m4s", "video/2.m4s"] {
+            let url = format!("http://origin.test/vod/{}", name);
+            origin.add(
+                &url,
+                Route::Tagged(origin.body(&url).unwrap(), "\"v1\"".to_string()),
+            );
+        }
+        let (mut options, directory) = options(manifest_url, "update");
+        let storage = FileStorage::new(directory.to_str().unwrap());
+        mirror(&origin, &options).unwrap();
+        let journal = Journal::open(&storage).unwrap();
+        assert_eq!(
+            journal
+                .get("http://origin.test/vod/video/1.m4s")
+                .unwrap()
+                .etag
+                .as_deref(),
+            Some("\"v1\"")
+        );
+
+        // the origin re-packages video segment 2 and drops the audio representation
+        origin.add(
+            "http://origin.test/vod/video/2.m4s",
+            Route::Tagged(b"repackaged".to_vec(), "\"v2\"".to_string()),
+        );
+        let manifest = String::from_utf8(origin.body(manifest_url).unwrap()).unwrap();
+        let start = manifest.find("<AdaptationSet mimeType=\"audio").unwrap();
+        let end = manifest.rfind("</AdaptationSet>").unwrap() + "</AdaptationSet>".len();
+        let manifest = format!("{}{}", &manifest[..start], &manifest[end..]);
+        origin.add(manifest_url, Route::Body(manifest.into_bytes()));
+        options.update = true;
+        options.prune = true;
+        let summary = mirror(&origin, &options).unwrap();
+        assert_eq!(
+            summary,
+            MirrorSummary {
+                downloaded: 1,
+                skipped: 2,
+                failed: 0
+            }
+        );
+        assert_eq!(
+            std::fs::read(directory.join("video/2.m4s")).unwrap(),
+            b"repackaged"
+        );
+        let journal = Journal::open(&storage).unwrap();
+        let request_headers = origin
+            .request_headers("http://origin.test/vod/video/1.m4s")
+            .unwrap();
+        assert!(request_headers.contains(&("If-None-Match".to_string(), "\"v1\"".to_string())));
+        assert_eq!(
+            journal
+                .get("http://origin.test/vod/video/2.m4s")
+                .unwrap()
+                .etag
+                .as_deref(),
+            Some("\"v2\"")
+        );
+        assert!(!directory.join("audio/init.mp4").exists());
+        assert_eq!(
+            journal
+                .get("http://origin.test/vod/audio/1.m4s")
+                .unwrap()
+                .state,
+            State::Pruned
+        );
+        assert!(crate::verify::verify_files(directory.to_str().unwrap())
+            .unwrap()
+            .is_empty());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn mirror_signed_urls() {
+        let unsigned = MockOrigin::synthetic_vod("http://origin.test/vod/", 1);
+        let origin = MockOrigin::new();
+        origin.add(
+            "http://origin.test/vod/manifest.mpd",
+            unsigned
+                .route("http://origin.test/vod/manifest.mpd")
+                .unwrap(),
+        );
+        for name in ["video/init.mp4", "video/1.m4s", "audio/init.mp4"] {
+            let url = format!("http://origin.test/vod/{}", name);
+            origin.add(&format!("{}?token=ok", url), unsigned.route(&url).unwrap());
+        }
+        let (mut options, directory) = options("http://origin.test/vod/manifest.mpd", "signed");
+        options.url_transform = Some(Box::new(|url: &str| {
+            if url.ends_with("audio/1.m4s") {
+                Err("no key".to_string())
+            } else {
+                Ok(format!("{}?token=ok", url))
+            }
+        }));
+        let summary = mirror(&origin, &options).unwrap();
+        assert_eq!((summary.downloaded, summary.failed), (3, 1));
+        assert!(directory.join("video/1.m4s").is_file());
+        assert_eq!(
+            origin.request_count("http://origin.test/vod/video/1.m4s"),
+            0
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn mirror_propagates_query() {
+        let plain = MockOrigin::synthetic_vod("http://origin.test/vod/", 1);
+        let origin = MockOrigin::new();
+        let manifest = String::from_utf8(plain.body("http://origin.test/vod/manifest.mpd").unwrap())
+            .unwrap()
+            .replace(
+                "<Period",
+                r#"<EssentialProperty schemeIdUri="urn:mpeg:dash:urlparam:2016"><ExtHttpHeaderInfo headerName="X-Session" queryTemplate="$query:session$" useMPDUrlQuery="true"/></EssentialProperty><Period"#,
+            );
+        origin.add(
+            "http://origin.test/vod/manifest.mpd?session=42",
+            Route::Body(manifest.into_bytes()),
+        );
+        for name in [
+            "video/init.mp4",
+            "video/1.m4s",
+            "audio/init.mp4",
+            "audio/1.m4s",
+        ] {
+            let url = format!("http://origin.test/vod/{}", name);
+            origin.add(&format!("{}?session=42", url), plain.route(&url).unwrap());
+        }
+        let (mut options, directory) =
+            options("http://origin.test/vod/manifest.mpd?session=42", "query");
+        options.url_options.propagate_query = true;
+        let summary = mirror(&origin, &options).unwrap();
+        assert_eq!((summary.downloaded, summary.failed), (4, 0));
+        assert!(directory.join("audio/1.m4s").is_file());
+        assert_eq!(
+            origin.request_headers("http://origin.test/vod/audio/1.m4s?session=42"),
+            Some(vec![("X-Session".to_string(), "42".to_string())])
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    /// Origin whose first streamed response breaks off after half the body.
+    struct Truncating {
+        origin: MockOrigin,
+        truncated: std::sync::Mutex<bool>,
+    }
+
+    impl Fetcher for Truncating {
+        fn fetch(&self, request: &FetchRequest) -> Result<FetchResponse, String> {
+            self.origin.fetch(request)
+        }
+
+        fn fetch_to(
+            &self,
+            request: &FetchRequest,
+            sink: &mut dyn std::io::Write,
+        ) -> Result<FetchResponse, String> {
+            let mut truncated = self.truncated.lock().unwrap();
+            if *truncated {
+                return self.origin.fetch_to(request, sink);
+            }
+            *truncated = true;
+            let body = self.origin.body(&request.url).unwrap();
+            sink.write_all(&body[..body.len() / 2]).unwrap();
+            Err("connection reset".to_string())
+        }
+    }
+
+    #[test]
+    fn download_streams_and_retries() {
+        let url = "http://origin.test/vod/video/1.m4s";
+        let fetcher = Truncating {
+            origin: MockOrigin::synthetic_vod("http://origin.test/vod/", 1),
+            truncated: std::sync::Mutex::new(false),
+        };
+        let (options, directory) = options("http://origin.test/vod/manifest.mpd", "stream");
+        let storage = FileStorage::new(directory.to_str().unwrap());
+        let (response, entry) = download(
+            &fetcher,
+            &FetchRequest::new(url),
+            &storage,
+            url,
+            "video/1.m4s",
+            &options,
+        )
+        .unwrap();
+        let body = fetcher.origin.body(url).unwrap();
+        assert!(response.body.is_empty());
+        assert_eq!(fetcher.origin.request_count(url), 1);
+        assert_eq!(std::fs::read(directory.join("video/1.m4s")).unwrap(), body);
+        let expected = Entry::done(url, "video/1.m4s", &body);
+        assert_eq!(
+            (entry.size, &entry.sha256),
+            (expected.size, &expected.sha256)
+        );
+        assert_eq!((entry.state, entry.status), (State::Done, Some(200)));
+        assert!(!directory.join("video/1.m4s.part").exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn mirror_manifest_errors() {
+        let origin = MockOrigin::new();
+        origin.add(
+            "http://origin.test/a.mpd",
+            Route::Redirect(301, "/b.mpd".to_string()),
+        );
+        origin.add(
+            "http://origin.test/b.mpd",
+            Route::Redirect(301, "/a.mpd".to_string()),
+        );
+        origin.add("http://origin.test/gone.mpd", Route::Status(500));
+        let (redirecting, directory) = options("http://origin.test/a.mpd", "errors");
+        let error = mirror(&origin, &redirecting).unwrap_err();
+        assert!(error.contains("too many redirects"));
+
+        let (mut failing, _) = options("http://origin.test/gone.mpd", "errors");
+        failing.retries = 2;
+        let error = mirror(&origin, &failing).unwrap_err();
+        assert!(error.contains("HTTP status 500"));
+        assert_eq!(origin.request_count("http://origin.test/gone.mpd"), 3);
+        assert!(!directory.join("manifest.mpd").exists());
+    }
+}
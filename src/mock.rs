@@ -0,0 +1,181 @@
+//! In-process mock origin for end-to-end tests of the mirror flow.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::fetch::{FetchRequest, FetchResponse, Fetcher};
+use crate::isobmff::tests::{init_segment, media_segment};
+
+#[derive(Clone)]
+pub enum Route {
+    Body(Vec<u8>),
+    /// Body with an `ETag`, answering a matching `If-None-Match` with 304.
+    Tagged(Vec<u8>, String),
+    /// Status and `Location` header.
+    Redirect(u16, String),
+    Status(u16),
+    /// Fails with the status for the given number of requests, then serves the route.
+    Flaky(u32, u16, Box<Route>),
+    /// Transport error, as for a refused connection.
+    Unreachable,
+}
+
+#[derive(Default)]
+pub struct MockOrigin {
+    routes: Mutex<HashMap<String, Route>>,
+    requests: Mutex<Vec<FetchRequest>>,
+}
+
+impl MockOrigin {
+    pub fn new() -> MockOrigin {
+        MockOrigin::default()
+    }
+
+    /// Origin serving `base_url/manifest.mpd`, a static manifest with one video and one
+    /// audio representation of `segment_count` segments of two seconds each.
+    pub fn synthetic_vod(base_url: &str, segment_count: u32) -> MockOrigin {
+        let origin = MockOrigin::new();
+        let duration = segment_count * 2;
+        let manifest = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT{0}S">
+  <Period duration="PT{0}S">
+    <AdaptationSet mimeType="video/mp4" codecs="avc1.64001f" width="1280" height="720">
+      <SegmentTemplate timescale="1000" duration="2000" media="video/$Number$.m4s" initialization="video/init.mp4"/>
+      <Representation id="video" bandwidth="2000000"/>
+    </AdaptationSet>
+    <AdaptationSet mimeType="audio/mp4" codecs="mp4a.40.2" lang="en">
+      <SegmentTemplate timescale="1000" duration="2000" media="audio/$Number$.m4s" initialization="audio/init.mp4"/>
+      <Representation id="audio" bandwidth="128000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>
+"#,
+            duration
+        );
+        origin.add(
+            &format!("{}manifest.mpd", base_url),
+            Route::Body(manifest.into_bytes()),
+        );
+        for (name, timescale, handler_type) in
+            [("video", 90000, b"vide"), ("audio", 48000, b"soun")]
+        {
+            origin.add(
+                &format!("{}{}/init.mp4", base_url, name),
+                Route::Body(init_segment(1, timescale, handler_type)),
+            );
+            for number in 1..=segment_count {
+                let decode_time = (number - 1) as u64 * 2 * timescale as u64;
+                let segment = media_segment(1, decode_time, timescale, &[b"sample 1", b"sample 2"]);
+                origin.add(
+                    &format!("{}{}/{}.m4s", base_url, name, number),
+                    Route::Body(segment),
+                );
+            }
+        }
+        origin
+    }
+
+    pub fn add(&self, url: &str, route: Route) {
+        self.routes.lock().unwrap().insert(url.to_string(), route);
+    }
+
+    pub fn route(&self, url: &str) -> Option<Route> {
+        self.routes.lock().unwrap().get(url).cloned()
+    }
+
+    pub fn body(&self, url: &str) -> Option<Vec<u8>> {
+        let mut route = self.route(url)?;
+        loop {
+            match route {
+                Route::Body(body) | Route::Tagged(body, _) => return Some(body),
+                Route::Flaky(_, _, inner) => route = *inner,
+                _ => return None,
+            }
+        }
+    }
+
+    /// Number of requests received for `url`.
+    pub fn request_count(&self, url: &str) -> usize {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| request.url == url)
+            .count()
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|request| request.url.clone())
+            .collect()
+    }
+
+    /// Headers of the last request for `url`.
+    pub fn request_headers(&self, url: &str) -> Option<Vec<(String, String)>> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|request| request.url == url)
+            .map(|request| request.headers.clone())
+    }
+}
+
+impl Fetcher for MockOrigin {
+    fn fetch(&self, request: &FetchRequest) -> Result<FetchResponse, String> {
+        self.requests.lock().unwrap().push(FetchRequest {
+            url: request.url.clone(),
+            headers: request.headers.clone(),
+        });
+        let mut routes = self.routes.lock().unwrap();
+        let route = routes.get_mut(&request.url);
+        let response = |status: u16, headers: Vec<(String, String)>, body: Vec<u8>| {
+            Ok(FetchResponse {
+                status,
+                url: request.url.clone(),
+                headers,
+                body,
+            })
+        };
+        let mut current = match route {
+            Some(Route::Flaky(failures, status, inner)) => {
+                if *failures > 0 {
+                    *failures -= 1;
+                    return response(*status, Vec::new(), Vec::new());
+                }
+                inner.as_ref().clone()
+            }
+            Some(route) => route.clone(),
+            None => return response(404, Vec::new(), Vec::new()),
+        };
+        loop {
+            return match current {
+                Route::Body(body) => response(200, Vec::new(), body),
+                Route::Tagged(body, etag) => {
+                    let headers = vec![("ETag".to_string(), etag.clone())];
+                    let matches = request.headers.iter().any(|(name, value)| {
+                        name.eq_ignore_ascii_case("If-None-Match") && *value == etag
+                    });
+                    match matches {
+                        true => response(304, headers, Vec::new()),
+                        false => response(200, headers, body),
+                    }
+                }
+                Route::Redirect(status, location) => {
+                    response(status, vec![("Location".to_string(), location)], Vec::new())
+                }
+                Route::Status(status) => response(status, Vec::new(), Vec::new()),
+                Route::Flaky(_, _, inner) => {
+                    current = *inner;
+                    continue;
+                }
+                Route::Unreachable => Err(format!("connection refused: {}", request.url)),
+            };
+        }
+    }
+}
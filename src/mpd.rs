@@ -21,28 +21,108 @@ struct SegmentTemplate {
     start_number: u64,
     duration: Option<u64>,
     timescale: u64,
+    presentation_time_offset: u64,
+}
+
+#[derive(Default, Clone)]
+pub struct ContentProtection {
+    pub scheme_id_uri: String,
+    pub value: Option<String>,
+    pub default_kid: Option<String>,
+    pub pssh: Vec<String>,
+    pub pro: Option<String>,
+    pub laurl: Option<String>,
+}
+
+/// `UrlQueryInfo`, `ExtUrlQueryInfo` or `ExtHttpHeaderInfo` of an `urn:mpeg:dash:urlparam`
+/// descriptor (ISO/IEC 23009-1 Annex I).
+#[derive(Default, Clone)]
+struct UrlQueryInfo {
+    query_template: String,
+    use_mpd_url_query: bool,
+    query_string: String,
+    include_in_requests: Vec<String>,
+    /// Set for `ExtHttpHeaderInfo`, the query is sent in this header instead of the url.
+    header_name: Option<String>,
+}
+
+impl UrlQueryInfo {
+    /// Query or header value for a request, given the query of the MPD url.
+    fn expand(&self, mpd_query: Option<&str>) -> String {
+        let mut parts = Vec::new();
+        if self.use_mpd_url_query {
+            parts.extend(mpd_query.filter(|query| !query.is_empty()));
+        }
+        if !self.query_string.is_empty() {
+            parts.push(self.query_string.as_str());
+        }
+        let query_part = parts.join("&");
+        let mut ret = String::new();
+        let mut tokens = self.query_template.split('$');
+        if let Some(text) = tokens.next() {
+            ret.push_str(text);
+        }
+        while let Some(token) = tokens.next() {
+            match token {
+                "" => ret.push('$'),
+                "querypart" => ret.push_str(&query_part),
+                _ => {
+                    if let Some(name) = token.strip_prefix("query:") {
+                        ret.push_str(&query_parameter(&query_part, name).unwrap_or_default());
+                    }
+                }
+            }
+            if let Some(text) = tokens.next() {
+                ret.push_str(text);
+            }
+        }
+        ret
+    }
+}
+
+fn query_parameter(query: &str, name: &str) -> Option<String> {
+    query.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (key == name).then(|| value.to_string())
+    })
 }
 
 #[derive(Default)]
 struct Representation {
     id: String,
     bandwidth: u64,
+    mime_type: Option<String>,
+    codecs: Option<String>,
+    width: Option<u64>,
+    height: Option<u64>,
+    frame_rate: Option<String>,
     segment_template: Option<SegmentTemplate>,
     base_url: Option<String>,
+    content_protections: Vec<ContentProtection>,
+    url_query_infos: Vec<UrlQueryInfo>,
 }
 
 #[derive(Default)]
 struct AdaptationSet {
     mime_type: String,
+    content_type: Option<String>,
+    lang: Option<String>,
+    codecs: Option<String>,
+    width: Option<u64>,
+    height: Option<u64>,
+    frame_rate: Option<String>,
     segment_template: Option<SegmentTemplate>,
     representations: Vec<Representation>,
     base_url: Option<String>,
+    content_protections: Vec<ContentProtection>,
+    url_query_infos: Vec<UrlQueryInfo>,
 }
 
 #[derive(Default)]
 struct Period {
     adaptation_sets: Vec<AdaptationSet>,
     duration: Option<f32>,
+    url_query_infos: Vec<UrlQueryInfo>,
 }
 
 #[derive(Default)]
@@ -51,6 +131,7 @@ struct MpegDash {
     url: String,
     media_presentation_duration: Option<f32>,
     base_url: Option<String>,
+    url_query_infos: Vec<UrlQueryInfo>,
 }
 
 impl MpegDash {
@@ -59,6 +140,7 @@ impl MpegDash {
     }
 }
 
+#[allow(clippy::manual_map)]
 fn get_optional_attibute_from_node(node: &roxmltree::Node, attribute: &str) -> Option<String> {
     match node.attribute(attribute) {
         Some(val) => Some(val.to_string()),
@@ -66,6 +148,7 @@ fn get_optional_attibute_from_node(node: &roxmltree::Node, attribute: &str) -> O
     }
 }
 
+#[allow(clippy::manual_ok_err)]
 fn get_optional_u64_attibute_from_node(node: &roxmltree::Node, attribute: &str) -> Option<u64> {
     match node.attribute(attribute) {
         Some(val) => match val.parse() {
@@ -76,6 +159,22 @@ fn get_optional_u64_attibute_from_node(node: &roxmltree::Node, attribute: &str)
     }
 }
 
+fn get_optional_attibute_by_local_name(node: &roxmltree::Node, attribute: &str) -> Option<String> {
+    node.attributes()
+        .iter()
+        .find(|attr| attr.name() == attribute)
+        .map(|attr| attr.value().to_string())
+}
+
+fn get_optional_text_from_node(node: &roxmltree::Node) -> Option<String> {
+    node.text().map(|text| text.trim().to_string())
+}
+
+#[allow(
+    clippy::manual_unwrap_or,
+    clippy::manual_unwrap_or_default,
+    clippy::needless_return
+)]
 fn parse_segment_timeline_segment(node: roxmltree::Node) -> Segment {
     let mut segment = Segment {
         ..Default::default()
@@ -93,6 +192,7 @@ fn parse_segment_timeline_segment(node: roxmltree::Node) -> Segment {
     return segment;
 }
 
+#[allow(clippy::needless_return)]
 fn parse_segment_timeline(node: roxmltree::Node) -> SegmentTimeline {
     let mut segment_timeline = SegmentTimeline {
         ..Default::default()
@@ -107,6 +207,7 @@ fn parse_segment_timeline(node: roxmltree::Node) -> SegmentTimeline {
     return segment_timeline;
 }
 
+#[allow(clippy::needless_return)]
 fn parse_segment_template(node: roxmltree::Node) -> SegmentTemplate {
     let mut segment_template = SegmentTemplate {
         ..Default::default()
@@ -144,9 +245,12 @@ fn parse_segment_template(node: roxmltree::Node) -> SegmentTemplate {
         },
         None => segment_template.timescale = 1,
     }
+    segment_template.presentation_time_offset =
+        get_optional_u64_attibute_from_node(&node, "presentationTimeOffset").unwrap_or_default();
     return segment_template;
 }
 
+#[allow(clippy::needless_return)]
 fn check_and_parse_segment_template(node: roxmltree::Node) -> Option<SegmentTemplate> {
     let mut segment_template = Some(SegmentTemplate {
         ..Default::default()
@@ -157,6 +261,83 @@ fn check_and_parse_segment_template(node: roxmltree::Node) -> Option<SegmentTemp
     return segment_template;
 }
 
+fn parse_content_protection(node: roxmltree::Node) -> ContentProtection {
+    let mut content_protection = ContentProtection {
+        value: get_optional_attibute_from_node(&node, "value"),
+        default_kid: get_optional_attibute_by_local_name(&node, "default_KID"),
+        ..Default::default()
+    };
+    match node.attribute("schemeIdUri") {
+        Some(val) => content_protection.scheme_id_uri = val.to_lowercase(),
+        None => {
+            eprintln!("Could not find schemeIdUri of content protection")
+        }
+    }
+    for child in node.children() {
+        if child.has_tag_name("pssh") {
+            match get_optional_text_from_node(&child) {
+                Some(pssh) => content_protection.pssh.push(pssh),
+                None => {
+                    eprintln!("could not get pssh for content protection");
+                }
+            }
+        } else if child.has_tag_name("pro") {
+            content_protection.pro = get_optional_text_from_node(&child);
+        } else if child.has_tag_name("laurl") || child.has_tag_name("Laurl") {
+            content_protection.laurl = get_optional_text_from_node(&child)
+                .filter(|laurl| !laurl.is_empty())
+                .or_else(|| get_optional_attibute_from_node(&child, "licenseUrl"));
+        }
+    }
+    content_protection
+}
+
+/// Parses the url parameter infos of an EssentialProperty or SupplementalProperty
+/// descriptor, other descriptors have none.
+fn parse_url_query_infos(node: roxmltree::Node) -> Vec<UrlQueryInfo> {
+    let mut url_query_infos = Vec::new();
+    let scheme_id_uri = node.attribute("schemeIdUri").unwrap_or_default();
+    if !scheme_id_uri.starts_with("urn:mpeg:dash:urlparam:") {
+        return url_query_infos;
+    }
+    for child in node.children() {
+        let header_name =
+            if child.has_tag_name("UrlQueryInfo") || child.has_tag_name("ExtUrlQueryInfo") {
+                None
+            } else if child.has_tag_name("ExtHttpHeaderInfo") {
+                match child.attribute("headerName") {
+                    Some(header_name) => Some(header_name.to_string()),
+                    None => {
+                        eprintln!("Could not find headerName of ExtHttpHeaderInfo");
+                        continue;
+                    }
+                }
+            } else {
+                continue;
+            };
+        url_query_infos.push(UrlQueryInfo {
+            query_template: get_optional_attibute_from_node(&child, "queryTemplate")
+                .unwrap_or("$querypart$".to_string()),
+            use_mpd_url_query: child.attribute("useMPDUrlQuery") == Some("true"),
+            query_string: get_optional_attibute_from_node(&child, "queryString")
+                .unwrap_or_default(),
+            include_in_requests: child
+                .attribute("includeInRequests")
+                .unwrap_or("segment")
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            header_name,
+        });
+    }
+    url_query_infos
+}
+
+fn is_descriptor_with_url_query_infos(node: &roxmltree::Node) -> bool {
+    node.has_tag_name("EssentialProperty") || node.has_tag_name("SupplementalProperty")
+}
+
+#[allow(clippy::needless_return)]
 fn parse_representation(node: roxmltree::Node) -> Representation {
     let mut representation = Representation {
         ..Default::default()
@@ -179,6 +360,12 @@ fn parse_representation(node: roxmltree::Node) -> Representation {
         }
     }
 
+    representation.mime_type = get_optional_attibute_from_node(&node, "mimeType");
+    representation.codecs = get_optional_attibute_from_node(&node, "codecs");
+    representation.width = get_optional_u64_attibute_from_node(&node, "width");
+    representation.height = get_optional_u64_attibute_from_node(&node, "height");
+    representation.frame_rate = get_optional_attibute_from_node(&node, "frameRate");
+
     for child in node.children() {
         if child.has_tag_name("SegmentTemplate") {
             representation.segment_template = check_and_parse_segment_template(child);
@@ -190,11 +377,20 @@ fn parse_representation(node: roxmltree::Node) -> Representation {
                     eprintln!("could not get base_url for representation");
                 }
             }
+        } else if child.has_tag_name("ContentProtection") {
+            representation
+                .content_protections
+                .push(parse_content_protection(child));
+        } else if is_descriptor_with_url_query_infos(&child) {
+            representation
+                .url_query_infos
+                .extend(parse_url_query_infos(child));
         }
     }
     return representation;
 }
 
+#[allow(clippy::needless_return)]
 fn parse_adaptation_set(node: roxmltree::Node) -> AdaptationSet {
     let mut adaptation_set = AdaptationSet {
         ..Default::default()
@@ -205,6 +401,12 @@ fn parse_adaptation_set(node: roxmltree::Node) -> AdaptationSet {
             eprintln!("Could not find mimeType of adaptation set")
         }
     }
+    adaptation_set.content_type = get_optional_attibute_from_node(&node, "contentType");
+    adaptation_set.lang = get_optional_attibute_from_node(&node, "lang");
+    adaptation_set.codecs = get_optional_attibute_from_node(&node, "codecs");
+    adaptation_set.width = get_optional_u64_attibute_from_node(&node, "width");
+    adaptation_set.height = get_optional_u64_attibute_from_node(&node, "height");
+    adaptation_set.frame_rate = get_optional_attibute_from_node(&node, "frameRate");
     for child in node.children() {
         if child.has_tag_name("Representation") {
             let representation = parse_representation(child);
@@ -218,11 +420,20 @@ fn parse_adaptation_set(node: roxmltree::Node) -> AdaptationSet {
                     eprintln!("could not get base_url for adaptation set");
                 }
             }
+        } else if child.has_tag_name("ContentProtection") {
+            adaptation_set
+                .content_protections
+                .push(parse_content_protection(child));
+        } else if is_descriptor_with_url_query_infos(&child) {
+            adaptation_set
+                .url_query_infos
+                .extend(parse_url_query_infos(child));
         }
     }
     return adaptation_set;
 }
 
+#[allow(clippy::needless_return)]
 fn parse_period(node: roxmltree::Node) -> Period {
     let mut period = Period {
         ..Default::default()
@@ -233,6 +444,11 @@ fn parse_period(node: roxmltree::Node) -> Period {
             period.adaptation_sets.push(adaptation_set);
         }
     }
+    for child in node.children() {
+        if is_descriptor_with_url_query_infos(&child) {
+            period.url_query_infos.extend(parse_url_query_infos(child));
+        }
+    }
     match get_optional_attibute_from_node(&node, "duration") {
         Some(duration) => {
             period.duration = duration
@@ -247,6 +463,7 @@ fn parse_period(node: roxmltree::Node) -> Period {
     return period;
 }
 
+#[allow(clippy::needless_return)]
 fn parse_mpd(xml: String, url: String) -> MpegDash {
     let mut mpeg_dash = MpegDash {
         ..Default::default()
@@ -281,6 +498,10 @@ fn parse_mpd(xml: String, url: String) -> MpegDash {
                                 //Some(_) => todo!(),
                                 None => mpeg_dash.base_url = None,
                             }
+                        } else if is_descriptor_with_url_query_infos(&child) {
+                            mpeg_dash
+                                .url_query_infos
+                                .extend(parse_url_query_infos(child));
                         }
                     }
                 }
@@ -300,6 +521,7 @@ struct FragementDescriptor<'a> {
     repeat: u64,
 }
 
+#[allow(clippy::needless_late_init, clippy::needless_return)]
 fn replace_with_printf_format(template: String, identifier: &str, value: u64) -> String {
     let mut ret: String = template.clone();
     let mut token_started = false;
@@ -337,6 +559,7 @@ fn replace_with_printf_format(template: String, identifier: &str, value: u64) ->
     return ret;
 }
 
+#[allow(clippy::needless_return)]
 fn expand_segment_template(
     template_string: &str,
     fragement_descriptor: &FragementDescriptor,
@@ -357,196 +580,519 @@ fn expand_segment_template(
     return ret;
 }
 
+#[derive(Default, Clone)]
+pub struct SegmentUrl {
+    pub url: String,
+    pub number: u64,
+    /// Start of the segment on the media timeline, in `timescale` units.
+    pub time: u64,
+    pub duration: Option<u64>,
+}
+
+#[derive(Default, Clone)]
+pub struct RepresentationUrls {
+    pub period_idx: usize,
+    pub adaptation_set_idx: usize,
+    pub id: String,
+    pub bandwidth: u64,
+    pub mime_type: String,
+    pub content_type: Option<String>,
+    pub lang: Option<String>,
+    pub codecs: Option<String>,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    pub frame_rate: Option<String>,
+    pub timescale: u64,
+    /// Segment time at which the period starts, in `timescale` units.
+    pub presentation_time_offset: u64,
+    /// Segment times and durations come from a SegmentTimeline instead of duration math.
+    pub segment_timeline: bool,
+    pub initialization: Option<String>,
+    /// Query and headers to add when requesting any url of the representation.
+    pub request_parameters: RequestParameters,
+    pub segments: Vec<SegmentUrl>,
+}
+
+/// Query and headers to add when requesting a url, from Annex I url parameter
+/// descriptors or query propagation. They are not part of the segment urls, so they do
+/// not change the local path.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct RequestParameters {
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestParameters {
+    /// `url` with the query appended.
+    pub fn apply(&self, url: &str) -> String {
+        match &self.query {
+            Some(query) => {
+                let separator = if url.contains('?') { '&' } else { '?' };
+                format!("{}{}{}", url, separator, query)
+            }
+            None => url.to_string(),
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct UrlInfo {
     pub base_url: String,
-    pub urls: Vec<String>,
+    pub representations: Vec<RepresentationUrls>,
 }
 
-fn get_urls(mpd: MpegDash) -> Option<UrlInfo> {
-    let mut ret: UrlInfo = UrlInfo {
-        ..Default::default()
+impl UrlInfo {
+    /// Every url of every representation, the initialization segment first.
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        self.representations.iter().flat_map(|representation| {
+            representation
+                .initialization
+                .as_deref()
+                .into_iter()
+                .chain(representation.segments.iter().map(|s| s.url.as_str()))
+        })
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct UrlOptions {
+    /// Append the query of the MPD url to every segment request, as if the MPD had a
+    /// UrlQueryInfo with useMPDUrlQuery.
+    pub propagate_query: bool,
+}
+
+fn request_parameters(
+    url_query_infos: &[&UrlQueryInfo],
+    mpd_query: Option<&str>,
+    options: &UrlOptions,
+) -> RequestParameters {
+    let mut queries = Vec::new();
+    let mut headers = Vec::new();
+    if options.propagate_query {
+        queries.extend(
+            mpd_query
+                .filter(|query| !query.is_empty())
+                .map(str::to_string),
+        );
+    }
+    for url_query_info in url_query_infos {
+        if !url_query_info
+            .include_in_requests
+            .iter()
+            .any(|request| request == "segment")
+        {
+            continue;
+        }
+        let value = url_query_info.expand(mpd_query);
+        match &url_query_info.header_name {
+            Some(header_name) => headers.push((header_name.clone(), value)),
+            None if !value.is_empty() => queries.push(value),
+            None => (),
+        }
+    }
+    RequestParameters {
+        query: (!queries.is_empty()).then(|| queries.join("&")),
+        headers,
+    }
+}
+
+/// A parsed manifest whose segment urls are expanded on demand, so that memory stays flat
+/// for long timelines.
+pub struct Manifest {
+    mpd: MpegDash,
+    base_url: String,
+    mpd_query: Option<String>,
+    options: UrlOptions,
+}
+
+/// One representation of a `Manifest`. `info` carries everything but the segments, which
+/// `segments` yields lazily.
+pub struct RepresentationSegments<'a> {
+    pub info: RepresentationUrls,
+    representation: &'a Representation,
+    segment_template: &'a SegmentTemplate,
+    /// Base url with the adaptation set and representation BaseURLs appended.
+    prefix: String,
+    total_duration: Option<f32>,
+}
+
+enum SegmentSource<'a> {
+    Timeline(std::slice::Iter<'a, Segment>, Option<&'a Segment>),
+    Duration,
+    Done,
+}
+
+/// Segments of one representation, in order, expanded as they are requested.
+pub struct Segments<'a> {
+    media: &'a str,
+    prefix: &'a str,
+    segment_template: &'a SegmentTemplate,
+    total_duration: Option<f32>,
+    fragment_descriptor: FragementDescriptor<'a>,
+    source: SegmentSource<'a>,
+}
+
+pub fn parse_manifest(xml_text: String, url: &str, options: &UrlOptions) -> Option<Manifest> {
+    let mpd = parse_mpd(xml_text, url.to_owned());
+    // the query may contain '/', the base url is derived from the path only
+    let (mpd_path, mpd_query) = match mpd.url.split_once('?') {
+        Some((path, query)) => (path, Some(query.split('#').next().unwrap_or_default())),
+        None => (mpd.url.split('#').next().unwrap_or_default(), None),
     };
-    let base_url = match mpd.base_url {
+    let base_url = match &mpd.base_url {
         Some(mpd_base_url) => {
             match mpd_base_url.starts_with("http://") || mpd_base_url.starts_with("https://") {
-                true => mpd_base_url,
+                true => mpd_base_url.clone(),
                 false => {
-                    let pos = mpd.url.rfind('/')?;
-                    let mut base_url_appended = mpd.url[..pos + 1].to_string();
-                    base_url_appended.push_str(&mpd_base_url);
+                    let pos = mpd_path.rfind('/')?;
+                    let mut base_url_appended = mpd_path[..pos + 1].to_string();
+                    base_url_appended.push_str(mpd_base_url);
                     base_url_appended
                 }
             }
         }
         None => {
-            let pos = mpd.url.rfind('/')?;
-            mpd.url[..pos + 1].to_string()
+            let pos = mpd_path.rfind('/')?;
+            mpd_path[..pos + 1].to_string()
         }
     };
+    let mpd_query = mpd_query.map(str::to_string);
+    Some(Manifest {
+        mpd,
+        base_url,
+        mpd_query,
+        options: *options,
+    })
+}
 
-    let periods_iter = mpd.periods.iter();
-    for (period_idx, period) in periods_iter.enumerate() {
-        debug_println!("period_idx {} ", period_idx);
-        let adaptation_set_iter: std::slice::Iter<AdaptationSet> = period.adaptation_sets.iter();
-        for (adaptation_set_idx, adaptation_set) in adaptation_set_iter.enumerate() {
-            debug_println!(
-                "adaptation_set_idx {} mimeType {}",
-                adaptation_set_idx,
-                adaptation_set.mime_type
-            );
-            let representation_iter = adaptation_set.representations.iter();
-            for (representation_idx, representation) in representation_iter.enumerate() {
-                debug_println!(
-                    "representation_idx {} id {} bandwidth {}",
-                    representation_idx,
-                    representation.id,
-                    representation.bandwidth
+impl Manifest {
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Representations with a SegmentTemplate, in manifest order.
+    pub fn representations(&self) -> impl Iterator<Item = RepresentationSegments<'_>> {
+        self.mpd
+            .periods
+            .iter()
+            .enumerate()
+            .flat_map(move |(period_idx, period)| {
+                debug_println!("period_idx {} ", period_idx);
+                period.adaptation_sets.iter().enumerate().flat_map(
+                    move |(adaptation_set_idx, adaptation_set)| {
+                        debug_println!(
+                            "adaptation_set_idx {} mimeType {}",
+                            adaptation_set_idx,
+                            adaptation_set.mime_type
+                        );
+                        adaptation_set
+                            .representations
+                            .iter()
+                            .filter_map(move |representation| {
+                                self.representation(
+                                    period_idx,
+                                    period,
+                                    adaptation_set_idx,
+                                    adaptation_set,
+                                    representation,
+                                )
+                            })
+                    },
+                )
+            })
+    }
+
+    fn representation<'a>(
+        &'a self,
+        period_idx: usize,
+        period: &'a Period,
+        adaptation_set_idx: usize,
+        adaptation_set: &'a AdaptationSet,
+        representation: &'a Representation,
+    ) -> Option<RepresentationSegments<'a>> {
+        debug_println!(
+            "id {} bandwidth {}",
+            representation.id,
+            representation.bandwidth
+        );
+        let Some(segment_template) = representation
+            .segment_template
+            .as_ref()
+            .or(adaptation_set.segment_template.as_ref())
+        else {
+            eprintln!("Segment Template not present, other formats not supported yet");
+            return None;
+        };
+        let mut prefix = self.base_url.clone();
+        for base_url in [&adaptation_set.base_url, &representation.base_url]
+            .into_iter()
+            .flatten()
+        {
+            // an absolute BaseURL, possibly on another host, replaces the one above it
+            if base_url.starts_with("http://") || base_url.starts_with("https://") {
+                prefix.clear();
+            }
+            prefix.push_str(base_url);
+        }
+        let initialization = match &segment_template.initialization {
+            Some(initialization) => {
+                let fragment_descriptor = FragementDescriptor {
+                    number: segment_template.start_number,
+                    representation,
+                    time: 0,
+                    repeat: 0,
+                };
+                Some(
+                    prefix.clone() + &expand_segment_template(initialization, &fragment_descriptor),
+                )
+            }
+            None => {
+                eprintln!(
+                    "initialization segment is not present for rep {}",
+                    representation.id
                 );
-                let segment_template_opt: Option<&SegmentTemplate>;
-                match &representation.segment_template {
-                    Some(st) => {
-                        segment_template_opt = Some(st);
-                    }
-                    None => match &adaptation_set.segment_template {
-                        Some(st) => {
-                            segment_template_opt = Some(st);
-                        }
-                        None => {
-                            segment_template_opt = None;
+                None
+            }
+        };
+        let url_query_infos: Vec<&UrlQueryInfo> = self
+            .mpd
+            .url_query_infos
+            .iter()
+            .chain(&period.url_query_infos)
+            .chain(&adaptation_set.url_query_infos)
+            .chain(&representation.url_query_infos)
+            .collect();
+        let request_parameters =
+            request_parameters(&url_query_infos, self.mpd_query.as_deref(), &self.options);
+        Some(RepresentationSegments {
+            info: RepresentationUrls {
+                period_idx,
+                adaptation_set_idx,
+                id: representation.id.clone(),
+                bandwidth: representation.bandwidth,
+                mime_type: representation
+                    .mime_type
+                    .clone()
+                    .unwrap_or(adaptation_set.mime_type.clone()),
+                content_type: adaptation_set.content_type.clone(),
+                lang: adaptation_set.lang.clone(),
+                codecs: representation
+                    .codecs
+                    .clone()
+                    .or(adaptation_set.codecs.clone()),
+                width: representation.width.or(adaptation_set.width),
+                height: representation.height.or(adaptation_set.height),
+                frame_rate: representation
+                    .frame_rate
+                    .clone()
+                    .or(adaptation_set.frame_rate.clone()),
+                timescale: segment_template.timescale,
+                presentation_time_offset: segment_template.presentation_time_offset,
+                segment_timeline: segment_template.media.is_some()
+                    && segment_template.segment_timeline.is_some(),
+                initialization,
+                request_parameters,
+                segments: Vec::new(),
+            },
+            representation,
+            segment_template,
+            prefix,
+            total_duration: period.duration.or(self.mpd.media_presentation_duration),
+        })
+    }
+}
+
+impl<'a> RepresentationSegments<'a> {
+    pub fn segments(&self) -> Segments<'_> {
+        let source = match (
+            &self.segment_template.media,
+            &self.segment_template.segment_timeline,
+        ) {
+            (None, _) => {
+                eprintln!("media is not present for rep {}", self.representation.id);
+                SegmentSource::Done
+            }
+            (Some(_), Some(segment_timeline)) => {
+                SegmentSource::Timeline(segment_timeline.segments.iter(), None)
+            }
+            (Some(_), None) => {
+                eprintln!("Segment timeline not present");
+                SegmentSource::Duration
+            }
+        };
+        Segments {
+            media: self.segment_template.media.as_deref().unwrap_or_default(),
+            prefix: &self.prefix,
+            segment_template: self.segment_template,
+            total_duration: self.total_duration,
+            fragment_descriptor: FragementDescriptor {
+                number: self.segment_template.start_number,
+                representation: self.representation,
+                time: 0,
+                repeat: 0,
+            },
+            source,
+        }
+    }
+}
+
+impl Segments<'_> {
+    /// Skips every segment numbered up to `number`, without expanding their urls. A live
+    /// mode uses this to continue after the last segment it saw in a previous manifest.
+    pub fn resume_after(&mut self, number: u64) {
+        while self.fragment_descriptor.number <= number && self.advance().is_some() {}
+    }
+
+    /// Moves to the next segment, returning the number, template time and duration of the
+    /// one moved past.
+    fn advance(&mut self) -> Option<(u64, u64, Option<u64>)> {
+        let fragment_descriptor = &mut self.fragment_descriptor;
+        match &mut self.source {
+            SegmentSource::Timeline(entries, current) => {
+                let s = match current {
+                    Some(s) => *s,
+                    None => {
+                        let s = entries.next()?;
+                        if let Some(time) = s.t {
+                            fragment_descriptor.time = time;
                         }
-                    },
+                        fragment_descriptor.repeat = s.r;
+                        *current = Some(s);
+                        s
+                    }
+                };
+                let segment = (
+                    fragment_descriptor.number,
+                    fragment_descriptor.time,
+                    Some(s.d),
+                );
+                fragment_descriptor.time += s.d;
+                fragment_descriptor.number += 1;
+                if fragment_descriptor.repeat == 0 {
+                    *current = None;
+                } else {
+                    fragment_descriptor.repeat -= 1;
                 }
-                match segment_template_opt {
-                    Some(segment_template) => {
-                        let mut fragment_descriptor = FragementDescriptor {
-                            number: segment_template.start_number,
-                            representation,
-                            time: 0,
-                            repeat: 0,
-                        };
-                        match &segment_template.initialization {
-                            Some(initialization) => {
-                                let mut initialization_url = base_url.clone();
-                                if let Some(adaptation_set_base_url) = &adaptation_set.base_url {
-                                    initialization_url.push_str(adaptation_set_base_url);
-                                }
-                                if let Some(representation_base_url) = &representation.base_url {
-                                    initialization_url.push_str(representation_base_url);
-                                }
-                                initialization_url.push_str(&expand_segment_template(
-                                    &initialization,
-                                    &fragment_descriptor,
-                                ));
-                                ret.urls.push(initialization_url);
-                            }
-                            None => {
-                                eprintln!(
-                                    "initialization segment is not present for rep {}",
-                                    representation.id
-                                )
+                Some(segment)
+            }
+            SegmentSource::Duration => {
+                let segment_template = self.segment_template;
+                let segment = (
+                    fragment_descriptor.number,
+                    fragment_descriptor.time,
+                    segment_template.duration,
+                );
+                fragment_descriptor.number += 1;
+                match self.total_duration {
+                    Some(max_time) => match segment_template.duration {
+                        Some(segment_duration) => {
+                            fragment_descriptor.time += segment_duration;
+                            let time: f32 =
+                                (fragment_descriptor.time / segment_template.timescale) as f32;
+                            if time >= max_time {
+                                debug_println!("fragment descriptor time reached max time, break");
+                                self.source = SegmentSource::Done;
                             }
                         }
-                        match &segment_template.media {
-                            Some(media) => match &segment_template.segment_timeline {
-                                Some(segment_timeline) => {
-                                    for s in &segment_timeline.segments {
-                                        if let Some(time) = s.t {
-                                            fragment_descriptor.time = time;
-                                        }
-                                        fragment_descriptor.repeat = s.r;
-                                        loop {
-                                            let mut segment_url = base_url.clone();
-                                            if let Some(adaptation_set_base_url) =
-                                                &adaptation_set.base_url
-                                            {
-                                                segment_url.push_str(adaptation_set_base_url);
-                                            }
-                                            if let Some(representation_base_url) =
-                                                &representation.base_url
-                                            {
-                                                segment_url.push_str(representation_base_url);
-                                            }
-                                            segment_url.push_str(&expand_segment_template(
-                                                media,
-                                                &fragment_descriptor,
-                                            ));
-                                            ret.urls.push(segment_url);
-                                            fragment_descriptor.time += s.d;
-                                            fragment_descriptor.number += 1;
-                                            if fragment_descriptor.repeat == 0 {
-                                                break;
-                                            }
-                                            fragment_descriptor.repeat -= 1;
-                                        }
-                                    }
-                                }
-                                None => {
-                                    eprintln!("Segment timeline not present");
-                                    let mut total_duration: Option<f32> = period.duration;
-                                    if total_duration.is_none() {
-                                        total_duration = mpd.media_presentation_duration;
-                                    }
-                                    loop {
-                                        let mut segment_url = base_url.clone();
-                                        if let Some(adaptation_set_base_url) =
-                                            &adaptation_set.base_url
-                                        {
-                                            segment_url.push_str(adaptation_set_base_url);
-                                        }
-                                        if let Some(representation_base_url) =
-                                            &representation.base_url
-                                        {
-                                            segment_url.push_str(representation_base_url);
-                                        }
-                                        segment_url.push_str(&expand_segment_template(
-                                            media,
-                                            &fragment_descriptor,
-                                        ));
-                                        ret.urls.push(segment_url);
-                                        fragment_descriptor.number += 1;
-
-                                        match total_duration {
-                                            Some(max_time) => match segment_template.duration {
-                                                Some(segment_duration) => {
-                                                    fragment_descriptor.time += segment_duration;
-                                                    let time: f32 = (fragment_descriptor.time
-                                                        / segment_template.timescale)
-                                                        as f32;
-                                                    if time >= max_time {
-                                                        debug_println!("fragment descriptor time reached max time, break");
-                                                        break;
-                                                    }
-                                                }
-                                                None => {}
-                                            },
-                                            None => {
-                                                eprintln!("total_duration not available");
-                                                break;
-                                            }
-                                        }
-                                    }
-                                }
-                            },
-                            None => {
-                                eprintln!("media is not present for rep {}", representation.id)
-                            }
+                        // the time never advances, so max time would never be reached
+                        None => {
+                            eprintln!("segment duration not available");
+                            self.source = SegmentSource::Done;
                         }
-                    }
+                    },
                     None => {
-                        eprintln!("Segment Template not present, other formats not supported yet")
+                        eprintln!("total_duration not available");
+                        self.source = SegmentSource::Done;
                     }
                 }
+                Some(segment)
             }
+            SegmentSource::Done => None,
         }
     }
-    ret.base_url = base_url;
-    return Some(ret);
+}
+
+impl Iterator for Segments<'_> {
+    type Item = SegmentUrl;
+
+    fn next(&mut self) -> Option<SegmentUrl> {
+        let (number, time, duration) = self.advance()?;
+        let fragment_descriptor = FragementDescriptor {
+            number,
+            representation: self.fragment_descriptor.representation,
+            time,
+            repeat: 0,
+        };
+        let mut url = self.prefix.to_string();
+        url.push_str(&expand_segment_template(self.media, &fragment_descriptor));
+        // without a timeline the reported time includes the presentation time offset
+        let time = match self.segment_template.segment_timeline {
+            Some(_) => time,
+            None => time + self.segment_template.presentation_time_offset,
+        };
+        Some(SegmentUrl {
+            url,
+            number,
+            time,
+            duration,
+        })
+    }
+}
+
+fn get_urls(manifest: Manifest) -> UrlInfo {
+    let mut ret: UrlInfo = UrlInfo {
+        ..Default::default()
+    };
+    for representation in manifest.representations() {
+        let mut representation_urls = representation.info.clone();
+        representation_urls.segments = representation.segments().collect();
+        ret.representations.push(representation_urls);
+    }
+    ret.base_url = manifest.base_url;
+    ret
 }
 
 pub fn get_fragment_urls(xml_text: String, url: &str) -> Option<UrlInfo> {
-    let mpd = parse_mpd(xml_text, url.to_owned());
-    return get_urls(mpd);
+    get_fragment_urls_with_options(xml_text, url, &UrlOptions::default())
+}
+
+pub fn get_fragment_urls_with_options(
+    xml_text: String,
+    url: &str,
+    options: &UrlOptions,
+) -> Option<UrlInfo> {
+    parse_manifest(xml_text, url, options).map(get_urls)
+}
+
+pub struct TrackProtection {
+    pub period_idx: usize,
+    pub adaptation_set_idx: usize,
+    pub mime_type: String,
+    pub representation_id: String,
+    pub bandwidth: u64,
+    pub adaptation_set_protections: Vec<ContentProtection>,
+    pub representation_protections: Vec<ContentProtection>,
+}
+
+pub fn get_track_protections(xml_text: String) -> Vec<TrackProtection> {
+    let mpd = parse_mpd(xml_text, Default::default());
+    let mut tracks = Vec::new();
+    for (period_idx, period) in mpd.periods.iter().enumerate() {
+        for (adaptation_set_idx, adaptation_set) in period.adaptation_sets.iter().enumerate() {
+            for representation in &adaptation_set.representations {
+                tracks.push(TrackProtection {
+                    period_idx,
+                    adaptation_set_idx,
+                    mime_type: adaptation_set.mime_type.clone(),
+                    representation_id: representation.id.clone(),
+                    bandwidth: representation.bandwidth,
+                    adaptation_set_protections: adaptation_set.content_protections.clone(),
+                    representation_protections: representation.content_protections.clone(),
+                });
+            }
+        }
+    }
+    tracks
 }
 
 #[cfg(test)]
@@ -556,9 +1102,10 @@ mod tests {
     use crate::mpd::expand_segment_template;
     use crate::mpd::FragementDescriptor;
 
-    use super::Representation;
+    use super::{get_fragment_urls_with_options, parse_manifest, Representation, UrlOptions};
 
     #[test]
+    #[allow(clippy::field_reassign_with_default)]
     fn expand_segment_template_test_1() {
         let mut representation: Representation = Default::default();
         representation.id = "repId".to_owned();
@@ -610,21 +1157,18 @@ mod tests {
         let url_info_opt = get_fragment_urls(xml_text, "http://test.com/manifest.mpd");
         assert!(url_info_opt.is_some());
         if let Some(url_info) = url_info_opt {
-            assert_eq!(url_info.urls.len(), 3);
-            for url in url_info.urls.iter() {
+            assert_eq!(url_info.urls().count(), 3);
+            for url in url_info.urls() {
                 println!("url : {}", url);
             }
             assert!(url_info
-                .urls
-                .iter()
+                .urls()
                 .any(|url| url == "http://test.com/audio103_3_460000_t10399888_init.mp4"));
             assert!(url_info
-                .urls
-                .iter()
+                .urls()
                 .any(|url| url == "http://test.com/audio103_3_460000_t10399888.mp4"));
             assert!(url_info
-                .urls
-                .iter()
+                .urls()
                 .any(|url| url == "http://test.com/audio103_3_460000_t30879888.mp4"));
         }
     }
@@ -645,21 +1189,18 @@ mod tests {
         let url_info_opt = get_fragment_urls(xml_text, "http://test.com/");
         assert!(url_info_opt.is_some());
         if let Some(url_info) = url_info_opt {
-            assert_eq!(url_info.urls.len(), 31);
-            for url in url_info.urls.iter() {
+            assert_eq!(url_info.urls().count(), 31);
+            for url in url_info.urls() {
                 println!("url : {}", url);
             }
             assert!(url_info
-                .urls
-                .iter()
+                .urls()
                 .any(|url| url == "https://baseurl.net/abcde/fgh/video_8000k_init.mp4"));
             assert!(url_info
-                .urls
-                .iter()
+                .urls()
                 .any(|url| url == "https://baseurl.net/abcde/fgh/video_8000k_1.mp4"));
             assert!(url_info
-                .urls
-                .iter()
+                .urls()
                 .any(|url| url == "https://baseurl.net/abcde/fgh/video_8000k_30.mp4"));
         }
     }
@@ -679,21 +1220,164 @@ mod tests {
         let url_info_opt = get_fragment_urls(xml_text, "http://test.com/");
         assert!(url_info_opt.is_some());
         if let Some(url_info) = url_info_opt {
-            for url in url_info.urls.iter() {
+            for url in url_info.urls() {
                 println!("url : {}", url);
             }
             assert!(url_info
-                .urls
-                .iter()
+                .urls()
                 .any(|url| url == "http://test.com/audio/160kbps/IS.mp4"));
             assert!(url_info
-                .urls
-                .iter()
+                .urls()
                 .any(|url| url == "http://test.com/audio/160kbps/000010.m4s"));
             assert!(url_info
-                .urls
-                .iter()
+                .urls()
                 .any(|url| url == "http://test.com/audio/96kbps/000010.m4s"));
         }
     }
+
+    #[test]
+    fn segment_template_no_duration() {
+        let xml_text = r#"<?xml version="1.0"?>
+        <MPD type="static" mediaPresentationDuration="PT60S">
+         <Period>
+          <AdaptationSet>
+           <Representation id="1" bandwidth="5678742">
+            <SegmentTemplate timescale="1000" media="$Number$.mp4" initialization="init.mp4"/>
+           </Representation>
+          </AdaptationSet>
+         </Period>
+        </MPD>"#
+            .to_owned();
+        let url_info = get_fragment_urls(xml_text, "http://test.com/").unwrap();
+        assert_eq!(
+            url_info.urls().collect::<Vec<_>>(),
+            ["http://test.com/init.mp4", "http://test.com/1.mp4"]
+        );
+    }
+
+    #[test]
+    fn url_query_info_test() {
+        let xml_text = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <MPD type="static" mediaPresentationDuration="PT4S" xmlns:up="urn:mpeg:dash:schema:urlparam:2016">
+        <SupplementalProperty schemeIdUri="urn:mpeg:dash:urlparam:2014">
+            <up:UrlQueryInfo queryTemplate="$querypart$" useMPDUrlQuery="true"/>
+        </SupplementalProperty>
+        <Period duration="PT4S">
+            <AdaptationSet mimeType="video/mp4">
+                <EssentialProperty schemeIdUri="urn:mpeg:dash:urlparam:2016">
+                    <up:ExtHttpHeaderInfo headerName="X-Token" queryTemplate="$query:token$" useMPDUrlQuery="true"/>
+                    <up:ExtUrlQueryInfo queryString="cdn=1" includeInRequests="segment mpd"/>
+                    <up:ExtUrlQueryInfo queryString="unused=1" includeInRequests="mpd"/>
+                </EssentialProperty>
+                <SegmentTemplate timescale="1000" duration="2000" media="v/$Number$.m4s" initialization="v/init.mp4" />
+                <Representation id="v" bandwidth="1000" />
+            </AdaptationSet>
+            <AdaptationSet mimeType="audio/mp4">
+                <SegmentTemplate timescale="1000" duration="2000" media="a/$Number$.m4s?x=$Number$" initialization="a/init.mp4" />
+                <Representation id="a" bandwidth="1000" />
+            </AdaptationSet>
+        </Period>
+        </MPD>"#.to_owned();
+        let url = "http://test.com/vod/manifest.mpd?token=a/b&user=1";
+        let url_info = get_fragment_urls(xml_text.clone(), url).unwrap();
+        assert_eq!(url_info.base_url, "http://test.com/vod/");
+        assert!(url_info.urls().all(|url| !url.contains("token")));
+        let parameters = &url_info.representations[0].request_parameters;
+        assert_eq!(parameters.query.as_deref(), Some("token=a/b&user=1&cdn=1"));
+        assert_eq!(
+            parameters.headers,
+            vec![("X-Token".to_string(), "a/b".to_string())]
+        );
+        assert_eq!(
+            url_info.representations[1]
+                .request_parameters
+                .apply("http://test.com/vod/a/2.m4s?x=2"),
+            "http://test.com/vod/a/2.m4s?x=2&token=a/b&user=1"
+        );
+
+        let url_info =
+            get_fragment_urls(xml_text.clone(), "http://test.com/vod/manifest.mpd").unwrap();
+        let parameters = &url_info.representations[0].request_parameters;
+        assert_eq!(parameters.query.as_deref(), Some("cdn=1"));
+        assert_eq!(parameters.headers[0].1, "");
+        assert_eq!(
+            url_info.representations[1].request_parameters,
+            Default::default()
+        );
+
+        let xml_text = xml_text.replace("urn:mpeg:dash:urlparam:", "urn:example:");
+        let options = UrlOptions {
+            propagate_query: true,
+        };
+        let url_info = get_fragment_urls_with_options(xml_text, url, &options).unwrap();
+        let parameters = &url_info.representations[0].request_parameters;
+        assert_eq!(parameters.query.as_deref(), Some("token=a/b&user=1"));
+        assert!(parameters.headers.is_empty());
+    }
+
+    #[test]
+    fn lazy_segments() {
+        let xml_text = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <MPD type="static" mediaPresentationDuration="PT24H">
+        <Period>
+            <AdaptationSet id="1" mimeType="video/mp4">
+                <SegmentTemplate timescale="1000" startNumber="5" initialization="$RepresentationID$/init.mp4" media="$RepresentationID$/$Number$_$Time$.m4s">
+                <SegmentTimeline>
+                    <S t="1000" d="2000" r="999999"/>
+                    <S d="500"/>
+                </SegmentTimeline>
+                </SegmentTemplate>
+                <Representation id="v1" bandwidth="1000000"/>
+            </AdaptationSet>
+            <AdaptationSet id="2" mimeType="audio/mp4">
+                <SegmentTemplate timescale="1000" presentationTimeOffset="100" duration="4000" media="a/$Number$.m4s" initialization="a/init.mp4"/>
+                <Representation id="a1" bandwidth="64000"/>
+            </AdaptationSet>
+        </Period>
+        </MPD>"#
+            .to_owned();
+        let manifest = parse_manifest(
+            xml_text,
+            "http://test.com/manifest.mpd",
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(manifest.base_url(), "http://test.com/");
+        let representations: Vec<_> = manifest.representations().collect();
+        assert_eq!(representations.len(), 2);
+        let video = &representations[0];
+        assert!(video.info.segment_timeline);
+        assert_eq!(
+            video.info.initialization.as_deref(),
+            Some("http://test.com/v1/init.mp4")
+        );
+        let first: Vec<_> = video.segments().take(2).map(|s| s.url).collect();
+        assert_eq!(
+            first,
+            [
+                "http://test.com/v1/5_1000.m4s",
+                "http://test.com/v1/6_3000.m4s"
+            ]
+        );
+
+        let mut segments = video.segments();
+        segments.resume_after(1_000_003);
+        let rest: Vec<_> = segments.collect();
+        assert_eq!(rest.len(), 2);
+        assert_eq!(rest[0].url, "http://test.com/v1/1000004_1999999000.m4s");
+        assert_eq!(rest[1].number, 1_000_005);
+        assert_eq!(rest[1].time, 2_000_001_000);
+        assert_eq!(rest[1].duration, Some(500));
+
+        let audio = &representations[1];
+        assert!(!audio.info.segment_timeline);
+        let mut segments = audio.segments();
+        let first = segments.next().unwrap();
+        assert_eq!((first.number, first.time), (1, 100));
+        segments.resume_after(21_599);
+        let last = segments.next().unwrap();
+        assert_eq!(last.url, "http://test.com/a/21600.m4s");
+        assert_eq!(last.time, 86_396_100);
+        assert!(segments.next().is_none());
+    }
 }
@@ -0,0 +1,946 @@
+use std::io::Write;
+
+use crate::isobmff::{self, Mp4Node, SampleInfo, TrackInit};
+use crate::layout;
+use crate::mpd::{RepresentationUrls, UrlInfo};
+
+#[derive(Default)]
+pub struct MuxOptions {
+    pub period_idx: usize,
+    pub video: Option<String>,
+    pub audio: Vec<String>,
+    pub progressive: bool,
+}
+
+struct MuxTrack<'a> {
+    representation: &'a RepresentationUrls,
+    ftyp: Option<Mp4Node>,
+    moov: Mp4Node,
+    defaults: Vec<TrackInit>,
+    source_track_id: u32,
+    track_id: u32,
+    timescale: u32,
+    movie_timescale: u32,
+    first_decode_time: u64,
+    /// Ticks subtracted from every decode time so that all tracks share one origin.
+    rebase: u64,
+}
+
+struct Chunk {
+    track_idx: usize,
+    segment_idx: usize,
+    data_offset: usize,
+    data_size: usize,
+    decode_time: u64,
+}
+
+fn rescale(value: u64, from: u32, to: u32) -> u64 {
+    match from {
+        0 => value,
+        from => (value as u128 * to as u128 / from as u128) as u64,
+    }
+}
+
+fn seconds(value: u64, timescale: u32) -> f64 {
+    value as f64 / timescale.max(1) as f64
+}
+
+fn select_representations<'a>(
+    url_info: &'a UrlInfo,
+    options: &MuxOptions,
+) -> Result<Vec<&'a RepresentationUrls>, String> {
+    let period: Vec<&RepresentationUrls> = url_info
+        .representations
+        .iter()
+        .filter(|rep| rep.period_idx == options.period_idx)
+        .collect();
+    let find = |id: &str| {
+        period
+            .iter()
+            .find(|rep| rep.id == id)
+            .copied()
+            .ok_or(format!("representation {} not found", id))
+    };
+    let mut selected = Vec::new();
+    match &options.video {
+        Some(id) => selected.push(find(id)?),
+        None => {
+            let video = period
+                .iter()
+                .filter(|rep| rep.mime_type.starts_with("video"))
+                .max_by_key(|rep| rep.bandwidth);
+            selected.extend(video);
+        }
+    }
+    match options.audio.is_empty() {
+        false => {
+            for id in &options.audio {
+                selected.push(find(id)?);
+            }
+        }
+        true => {
+            let mut adaptation_sets: Vec<usize> = Vec::new();
+            for rep in period
+                .iter()
+                .filter(|rep| rep.mime_type.starts_with("audio"))
+            {
+                if !adaptation_sets.contains(&rep.adaptation_set_idx) {
+                    adaptation_sets.push(rep.adaptation_set_idx);
+                }
+            }
+            for adaptation_set_idx in adaptation_sets {
+                let audio = period
+                    .iter()
+                    .filter(|rep| rep.adaptation_set_idx == adaptation_set_idx)
+                    .max_by_key(|rep| rep.bandwidth);
+                selected.extend(audio);
+            }
+        }
+    }
+    match selected.is_empty() {
+        true => Err(format!(
+            "no representation in period {}",
+            options.period_idx
+        )),
+        false => Ok(selected),
+    }
+}
+
+fn mvhd_timescale(moov: &Mp4Node) -> Option<u32> {
+    let mvhd = moov.child(b"mvhd")?;
+    match mvhd.data.first()? {
+        1 => isobmff::read_u32(&mvhd.data, 20),
+        _ => isobmff::read_u32(&mvhd.data, 12),
+    }
+}
+
+fn load_track<'a>(
+    output_directory: &str,
+    base_url: &str,
+    representation: &'a RepresentationUrls,
+    track_id: u32,
+) -> Result<MuxTrack<'a>, String> {
+    let initialization = representation.initialization.as_ref().ok_or(format!(
+        "rep {} has no initialization segment",
+        representation.id
+    ))?;
+    let init = layout::read_segment(output_directory, base_url, initialization)?;
+    let tree = isobmff::parse_tree(&init);
+    let moov = tree
+        .iter()
+        .find(|node| &node.box_type == b"moov")
+        .cloned()
+        .ok_or(format!("rep {} : moov box not found", representation.id))?;
+    let defaults = isobmff::parse_init_tracks(&init);
+    let source = defaults.first().ok_or(format!(
+        "rep {} : no track in init segment",
+        representation.id
+    ))?;
+    if defaults.len() > 1 {
+        eprintln!(
+            "rep {} : init segment has {} tracks, only track {} is used",
+            representation.id,
+            defaults.len(),
+            source.track_id
+        );
+    }
+    let timescale = match source.timescale {
+        0 => representation.timescale as u32,
+        timescale => timescale,
+    };
+    let mut first_decode_time = representation
+        .segments
+        .first()
+        .map(|segment| segment.time)
+        .unwrap_or_default();
+    if let Some(segment) = representation.segments.first() {
+        let data = layout::read_segment(output_directory, base_url, &segment.url)?;
+        let fragments = isobmff::parse_track_fragments(&data, &defaults);
+        if let Some(time) = fragments.first().and_then(|f| f.base_media_decode_time) {
+            first_decode_time = time;
+        }
+    }
+    Ok(MuxTrack {
+        representation,
+        ftyp: tree.iter().find(|node| &node.box_type == b"ftyp").cloned(),
+        movie_timescale: mvhd_timescale(&moov).unwrap_or(1000),
+        moov,
+        source_track_id: source.track_id,
+        track_id,
+        timescale,
+        first_decode_time,
+        defaults,
+        rebase: 0,
+    })
+}
+
+/// Chooses the rebase of every track so that the earliest track starts at zero and
+/// the others keep their offset to it.
+fn rebase_tracks(tracks: &mut [MuxTrack]) {
+    let origin = tracks
+        .iter()
+        .map(|track| seconds(track.first_decode_time, track.timescale))
+        .fold(f64::MAX, f64::min);
+    for track in tracks.iter_mut() {
+        let rebase = (origin * track.timescale as f64).floor() as u64;
+        track.rebase = rebase.min(track.first_decode_time);
+    }
+}
+
+fn set_u32(data: &mut [u8], pos: usize, value: u32) {
+    data[pos..pos + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn set_u64(data: &mut [u8], pos: usize, value: u64) {
+    data[pos..pos + 8].copy_from_slice(&value.to_be_bytes());
+}
+
+fn full_box(box_type: &[u8; 4], version: u8, flags: u32, payload: Vec<u8>) -> Mp4Node {
+    let mut data = ((version as u32) << 24 | flags).to_be_bytes().to_vec();
+    data.extend(payload);
+    Mp4Node::new(box_type, data)
+}
+
+/// Converts a version 0 `mvhd`, `tkhd` or `mdhd` to version 1 so that 64 bit
+/// durations can be stored.
+fn upgrade_to_v1(node: &mut Mp4Node) {
+    if node.data.first() != Some(&0) || node.data.len() < 24 {
+        return;
+    }
+    let old = std::mem::take(&mut node.data);
+    let read = |pos| isobmff::read_u32(&old, pos).unwrap_or_default() as u64;
+    let mut data = vec![1];
+    data.extend(&old[1..4]);
+    data.extend(read(4).to_be_bytes());
+    data.extend(read(8).to_be_bytes());
+    match &node.box_type {
+        b"tkhd" => {
+            data.extend(&old[12..20]);
+            data.extend(read(20).to_be_bytes());
+            data.extend(&old[24..]);
+        }
+        _ => {
+            data.extend(&old[12..16]);
+            data.extend(read(16).to_be_bytes());
+            data.extend(&old[20..]);
+        }
+    }
+    node.data = data;
+}
+
+fn set_duration(node: &mut Mp4Node, duration: u64) {
+    upgrade_to_v1(node);
+    match &node.box_type {
+        b"tkhd" => set_u64(&mut node.data, 28, duration),
+        _ => set_u64(&mut node.data, 24, duration),
+    }
+}
+
+fn set_tkhd_track_id(trak: &mut Mp4Node, track_id: u32) {
+    if let Some(tkhd) = trak.child_mut(b"tkhd") {
+        match tkhd.data.first() {
+            Some(1) => set_u32(&mut tkhd.data, 20, track_id),
+            _ => set_u32(&mut tkhd.data, 12, track_id),
+        }
+    }
+}
+
+/// Rescales the movie timescale durations of a `trak` from `from` to `to`.
+fn rescale_trak(trak: &mut Mp4Node, from: u32, to: u32) {
+    if from == to {
+        return;
+    }
+    if let Some(tkhd) = trak.child_mut(b"tkhd") {
+        let duration = match tkhd.data.first() {
+            Some(1) => isobmff::read_u64(&tkhd.data, 28),
+            _ => isobmff::read_u32(&tkhd.data, 20).map(|duration| duration as u64),
+        };
+        if let Some(duration) = duration {
+            set_duration(tkhd, rescale(duration, from, to));
+        }
+    }
+    let elst = trak
+        .child_mut(b"edts")
+        .and_then(|edts| edts.child_mut(b"elst"));
+    if let Some(elst) = elst {
+        let version = elst.data.first().copied().unwrap_or_default();
+        let entry_count = isobmff::read_u32(&elst.data, 4).unwrap_or_default() as usize;
+        let entry_size = if version == 1 { 20 } else { 12 };
+        for entry_idx in 0..entry_count {
+            let pos = 8 + entry_idx * entry_size;
+            if version == 1 {
+                if let Some(duration) = isobmff::read_u64(&elst.data, pos) {
+                    set_u64(&mut elst.data, pos, rescale(duration, from, to));
+                }
+            } else if let Some(duration) = isobmff::read_u32(&elst.data, pos) {
+                set_u32(
+                    &mut elst.data,
+                    pos,
+                    rescale(duration as u64, from, to) as u32,
+                );
+            }
+        }
+    }
+}
+
+/// Media time of the first non empty edit, used to keep composition offsets.
+fn edit_media_time(trak: &Mp4Node) -> i64 {
+    let Some(elst) = trak.child(b"edts").and_then(|edts| edts.child(b"elst")) else {
+        return 0;
+    };
+    let version = elst.data.first().copied().unwrap_or_default();
+    let entry_count = isobmff::read_u32(&elst.data, 4).unwrap_or_default() as usize;
+    for entry_idx in 0..entry_count {
+        let media_time = match version {
+            1 => isobmff::read_u64(&elst.data, 8 + entry_idx * 20 + 8).map(|t| t as i64),
+            _ => isobmff::read_u32(&elst.data, 8 + entry_idx * 12 + 4).map(|t| t as i32 as i64),
+        };
+        match media_time {
+            Some(-1) => continue,
+            Some(media_time) => return media_time,
+            None => break,
+        }
+    }
+    0
+}
+
+fn source_trak(track: &MuxTrack) -> Result<Mp4Node, String> {
+    track
+        .moov
+        .children
+        .iter()
+        .find(|child| &child.box_type == b"trak")
+        .cloned()
+        .ok_or(format!(
+            "rep {} : trak box not found",
+            track.representation.id
+        ))
+}
+
+/// `moov` skeleton of the output: `mvhd` and other boxes of the first track, without tracks.
+fn base_moov(tracks: &[MuxTrack]) -> Mp4Node {
+    let mut moov = tracks[0].moov.clone();
+    moov.children
+        .retain(|child| !matches!(&child.box_type, b"trak" | b"mvex" | b"pssh"));
+    if let Some(mvhd) = moov.child_mut(b"mvhd") {
+        let next_track_id = tracks.len() as u32 + 1;
+        match mvhd.data.first() {
+            Some(1) => set_u32(&mut mvhd.data, 108, next_track_id),
+            _ => set_u32(&mut mvhd.data, 96, next_track_id),
+        }
+    }
+    moov
+}
+
+fn write_output(
+    path: &str,
+    boxes: &[Mp4Node],
+) -> Result<std::io::BufWriter<std::fs::File>, String> {
+    let path = std::path::Path::new(path);
+    if let Some(directory) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(directory)
+            .map_err(|e| format!("{} : {}", directory.display(), e))?;
+    }
+    let file = std::fs::File::create(path).map_err(|e| format!("{} : {}", path.display(), e))?;
+    let mut writer = std::io::BufWriter::new(file);
+    writer
+        .write_all(&isobmff::write_tree(boxes))
+        .map_err(|e| format!("{} : {}", path.display(), e))?;
+    Ok(writer)
+}
+
+fn fragmented_moov(tracks: &[MuxTrack]) -> Result<Mp4Node, String> {
+    let mut moov = base_moov(tracks);
+    let movie_timescale = tracks[0].movie_timescale;
+    let mut mvex = Mp4Node::new(b"mvex", Vec::new());
+    if let Some(mehd) = tracks[0]
+        .moov
+        .child(b"mvex")
+        .and_then(|mvex| mvex.child(b"mehd"))
+    {
+        mvex.children.push(mehd.clone());
+    }
+    let mut pssh_boxes: Vec<Mp4Node> = Vec::new();
+    for track in tracks {
+        let mut trak = source_trak(track)?;
+        set_tkhd_track_id(&mut trak, track.track_id);
+        rescale_trak(&mut trak, track.movie_timescale, movie_timescale);
+        moov.children.push(trak);
+        let trex = track
+            .moov
+            .child(b"mvex")
+            .and_then(|mvex| {
+                mvex.children.iter().find(|child| {
+                    &child.box_type == b"trex"
+                        && isobmff::read_u32(&child.data, 4) == Some(track.source_track_id)
+                })
+            })
+            .cloned();
+        let mut trex = trex.unwrap_or(full_box(b"trex", 0, 0, vec![0; 20]));
+        set_u32(&mut trex.data, 4, track.track_id);
+        if isobmff::read_u32(&trex.data, 8) == Some(0) {
+            set_u32(&mut trex.data, 8, 1);
+        }
+        mvex.children.push(trex);
+        for pssh in track
+            .moov
+            .children
+            .iter()
+            .filter(|c| &c.box_type == b"pssh")
+        {
+            if !pssh_boxes.iter().any(|known| known.data == pssh.data) {
+                pssh_boxes.push(pssh.clone());
+            }
+        }
+    }
+    moov.children.push(mvex);
+    moov.children.extend(pssh_boxes);
+    Ok(moov)
+}
+
+/// Rewrites sequence number, track id and decode time of a `moof` in place.
+fn patch_moof(data: &mut [u8], moof: &isobmff::BoxHeader, track: &MuxTrack, sequence_number: u32) {
+    for child in isobmff::children(data, moof) {
+        match &child.box_type {
+            b"mfhd" => set_u32(data, child.payload_offset() + 4, sequence_number),
+            b"traf" => {
+                for traf_child in isobmff::children(data, &child) {
+                    let payload = traf_child.payload_offset();
+                    match &traf_child.box_type {
+                        b"tfhd" => set_u32(data, payload + 4, track.track_id),
+                        b"tfdt" => {
+                            let decode_time = isobmff::parse_tfdt(traf_child.payload(data))
+                                .unwrap_or_default()
+                                .saturating_sub(track.rebase);
+                            match data[payload] {
+                                1 => set_u64(data, payload + 4, decode_time),
+                                _ => set_u32(data, payload + 4, decode_time as u32),
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn write_fragmented(
+    output_directory: &str,
+    base_url: &str,
+    tracks: &[MuxTrack],
+    output: &str,
+) -> Result<(), String> {
+    let mut boxes: Vec<Mp4Node> = tracks[0].ftyp.iter().cloned().collect();
+    boxes.push(fragmented_moov(tracks)?);
+    let mut writer = write_output(output, &boxes)?;
+
+    let mut order: Vec<(f64, usize, usize)> = Vec::new();
+    for (track_idx, track) in tracks.iter().enumerate() {
+        for (segment_idx, segment) in track.representation.segments.iter().enumerate() {
+            let time = seconds(segment.time, track.representation.timescale as u32);
+            order.push((time, track_idx, segment_idx));
+        }
+    }
+    order.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+    let mut sequence_number = 1;
+    for (_, track_idx, segment_idx) in order {
+        let track = &tracks[track_idx];
+        let segment = &track.representation.segments[segment_idx];
+        let mut data = layout::read_segment(output_directory, base_url, &segment.url)?;
+        for header in isobmff::top_level_boxes(&data) {
+            match &header.box_type {
+                b"styp" | b"sidx" | b"ssix" => continue,
+                b"moof" => {
+                    patch_moof(&mut data, &header, track, sequence_number);
+                    sequence_number += 1;
+                }
+                _ => {}
+            }
+            writer
+                .write_all(&data[header.offset..header.end()])
+                .map_err(|e| format!("{} : {}", output, e))?;
+        }
+    }
+    writer.flush().map_err(|e| format!("{} : {}", output, e))
+}
+
+struct TrackSamples {
+    samples: Vec<SampleInfo>,
+    chunks: Vec<usize>,
+    first_decode_time: Option<u64>,
+}
+
+fn collect_chunks(
+    output_directory: &str,
+    base_url: &str,
+    tracks: &[MuxTrack],
+) -> Result<(Vec<TrackSamples>, Vec<Chunk>), String> {
+    let mut track_samples = Vec::new();
+    let mut chunks = Vec::new();
+    for (track_idx, track) in tracks.iter().enumerate() {
+        let mut samples = TrackSamples {
+            samples: Vec::new(),
+            chunks: Vec::new(),
+            first_decode_time: None,
+        };
+        let mut decode_time = track.first_decode_time;
+        for (segment_idx, segment) in track.representation.segments.iter().enumerate() {
+            let data = layout::read_segment(output_directory, base_url, &segment.url)?;
+            for fragment in isobmff::parse_track_fragments(&data, &track.defaults) {
+                if fragment.track_id != track.source_track_id {
+                    continue;
+                }
+                if let Some(time) = fragment.base_media_decode_time {
+                    decode_time = time;
+                }
+                for run in &fragment.runs {
+                    if run.data_offset + run.data_size() > data.len() {
+                        return Err(format!("{} : sample data out of range", segment.url));
+                    }
+                    samples.first_decode_time.get_or_insert(decode_time);
+                    samples.chunks.push(run.samples.len());
+                    chunks.push(Chunk {
+                        track_idx,
+                        segment_idx,
+                        data_offset: run.data_offset,
+                        data_size: run.data_size(),
+                        decode_time: decode_time.saturating_sub(track.rebase),
+                    });
+                    decode_time += run.samples.iter().map(|s| s.duration as u64).sum::<u64>();
+                    samples.samples.extend(&run.samples);
+                }
+            }
+        }
+        track_samples.push(samples);
+    }
+    chunks.sort_by(|a, b| {
+        let a_time = seconds(a.decode_time, tracks[a.track_idx].timescale);
+        let b_time = seconds(b.decode_time, tracks[b.track_idx].timescale);
+        a_time
+            .total_cmp(&b_time)
+            .then(a.track_idx.cmp(&b.track_idx))
+    });
+    Ok((track_samples, chunks))
+}
+
+fn run_length<T: PartialEq + Copy>(values: impl Iterator<Item = T>) -> Vec<(u32, T)> {
+    let mut runs: Vec<(u32, T)> = Vec::new();
+    for value in values {
+        match runs.last_mut() {
+            Some((count, last)) if *last == value => *count += 1,
+            _ => runs.push((1, value)),
+        }
+    }
+    runs
+}
+
+fn build_sample_table(
+    stsd: Mp4Node,
+    samples: &TrackSamples,
+    chunk_offsets: &[u64],
+    co64: bool,
+) -> Mp4Node {
+    let mut stbl = Mp4Node::new(b"stbl", Vec::new());
+    stbl.children.push(stsd);
+
+    let stts = run_length(samples.samples.iter().map(|s| s.duration));
+    let mut payload = (stts.len() as u32).to_be_bytes().to_vec();
+    for (count, duration) in stts {
+        payload.extend(count.to_be_bytes());
+        payload.extend(duration.to_be_bytes());
+    }
+    stbl.children.push(full_box(b"stts", 0, 0, payload));
+
+    if samples
+        .samples
+        .iter()
+        .any(|s| s.composition_time_offset != 0)
+    {
+        let negative = samples
+            .samples
+            .iter()
+            .any(|s| s.composition_time_offset < 0);
+        let ctts = run_length(samples.samples.iter().map(|s| s.composition_time_offset));
+        let mut payload = (ctts.len() as u32).to_be_bytes().to_vec();
+        for (count, offset) in ctts {
+            payload.extend(count.to_be_bytes());
+            payload.extend((offset as i32).to_be_bytes());
+        }
+        stbl.children
+            .push(full_box(b"ctts", u8::from(negative), 0, payload));
+    }
+
+    if samples.samples.iter().any(|s| !s.is_sync()) {
+        let sync: Vec<u32> = (1..)
+            .zip(samples.samples.iter())
+            .filter(|(_, s)| s.is_sync())
+            .map(|(number, _)| number)
+            .collect();
+        let mut payload = (sync.len() as u32).to_be_bytes().to_vec();
+        for number in sync {
+            payload.extend(number.to_be_bytes());
+        }
+        stbl.children.push(full_box(b"stss", 0, 0, payload));
+    }
+
+    let stsc = run_length(samples.chunks.iter().copied());
+    let mut payload = (stsc.len() as u32).to_be_bytes().to_vec();
+    let mut first_chunk = 1u32;
+    for (count, samples_per_chunk) in stsc {
+        payload.extend(first_chunk.to_be_bytes());
+        payload.extend((samples_per_chunk as u32).to_be_bytes());
+        payload.extend(1u32.to_be_bytes());
+        first_chunk += count;
+    }
+    stbl.children.push(full_box(b"stsc", 0, 0, payload));
+
+    let sizes: Vec<u32> = samples.samples.iter().map(|s| s.size).collect();
+    let mut payload = Vec::new();
+    match sizes.first() {
+        Some(size) if sizes.iter().all(|s| s == size) => {
+            payload.extend(size.to_be_bytes());
+            payload.extend((sizes.len() as u32).to_be_bytes());
+        }
+        _ => {
+            payload.extend(0u32.to_be_bytes());
+            payload.extend((sizes.len() as u32).to_be_bytes());
+            for size in sizes {
+                payload.extend(size.to_be_bytes());
+            }
+        }
+    }
+    stbl.children.push(full_box(b"stsz", 0, 0, payload));
+
+    let mut payload = (chunk_offsets.len() as u32).to_be_bytes().to_vec();
+    for offset in chunk_offsets {
+        match co64 {
+            true => payload.extend(offset.to_be_bytes()),
+            false => payload.extend((*offset as u32).to_be_bytes()),
+        }
+    }
+    let box_type = if co64 { b"co64" } else { b"stco" };
+    stbl.children.push(full_box(box_type, 0, 0, payload));
+    stbl
+}
+
+fn progressive_moov(
+    tracks: &[MuxTrack],
+    track_samples: &[TrackSamples],
+    chunk_offsets: &[Vec<u64>],
+    co64: bool,
+) -> Result<Mp4Node, String> {
+    let mut moov = base_moov(tracks);
+    let movie_timescale = tracks[0].movie_timescale;
+    let mut movie_duration = 0;
+    for (track_idx, track) in tracks.iter().enumerate() {
+        let samples = &track_samples[track_idx];
+        let mut trak = source_trak(track)?;
+        let media_time = edit_media_time(&trak);
+        set_tkhd_track_id(&mut trak, track.track_id);
+        let media_duration: u64 = samples.samples.iter().map(|s| s.duration as u64).sum();
+        let start = samples
+            .first_decode_time
+            .unwrap_or_default()
+            .saturating_sub(track.rebase);
+        let duration = rescale(media_duration, track.timescale, movie_timescale);
+        let empty_duration = rescale(start, track.timescale, movie_timescale);
+        movie_duration = movie_duration.max(empty_duration + duration);
+        if let Some(tkhd) = trak.child_mut(b"tkhd") {
+            set_duration(tkhd, empty_duration + duration);
+        }
+
+        trak.children.retain(|child| &child.box_type != b"edts");
+        if empty_duration > 0 || media_time != 0 {
+            let mut entries: Vec<(u64, i64)> = Vec::new();
+            if empty_duration > 0 {
+                entries.push((empty_duration, -1));
+            }
+            entries.push((duration, media_time));
+            let mut payload = (entries.len() as u32).to_be_bytes().to_vec();
+            for (segment_duration, media_time) in entries {
+                payload.extend(segment_duration.to_be_bytes());
+                payload.extend(media_time.to_be_bytes());
+                payload.extend(0x00010000u32.to_be_bytes());
+            }
+            let mut edts = Mp4Node::new(b"edts", Vec::new());
+            edts.children.push(full_box(b"elst", 1, 0, payload));
+            trak.children.insert(1, edts);
+        }
+
+        let mdia = trak.child_mut(b"mdia").ok_or(format!(
+            "rep {} : mdia box not found",
+            track.representation.id
+        ))?;
+        if let Some(mdhd) = mdia.child_mut(b"mdhd") {
+            set_duration(mdhd, media_duration);
+        }
+        let stbl = mdia
+            .child_mut(b"minf")
+            .and_then(|minf| minf.child_mut(b"stbl"))
+            .ok_or(format!(
+                "rep {} : stbl box not found",
+                track.representation.id
+            ))?;
+        let stsd = stbl.child(b"stsd").cloned().ok_or(format!(
+            "rep {} : stsd box not found",
+            track.representation.id
+        ))?;
+        *stbl = build_sample_table(stsd, samples, &chunk_offsets[track_idx], co64);
+        moov.children.push(trak);
+    }
+    if let Some(mvhd) = moov.child_mut(b"mvhd") {
+        set_duration(mvhd, movie_duration);
+    }
+    for track in tracks {
+        for pssh in track
+            .moov
+            .children
+            .iter()
+            .filter(|c| &c.box_type == b"pssh")
+        {
+            moov.children.push(pssh.clone());
+        }
+    }
+    Ok(moov)
+}
+
+fn write_progressive(
+    output_directory: &str,
+    base_url: &str,
+    tracks: &[MuxTrack],
+    output: &str,
+) -> Result<(), String> {
+    let (track_samples, chunks) = collect_chunks(output_directory, base_url, tracks)?;
+    let mdat_size: u64 = chunks.iter().map(|chunk| chunk.data_size as u64).sum();
+    let large_mdat = mdat_size + 8 > u32::MAX as u64;
+
+    let ftyp: Vec<Mp4Node> = tracks[0].ftyp.iter().cloned().collect();
+    let placeholder: Vec<Vec<u64>> = track_samples
+        .iter()
+        .map(|samples| vec![0; samples.chunks.len()])
+        .collect();
+    let moov_size = progressive_moov(tracks, &track_samples, &placeholder, large_mdat)?.size();
+    let ftyp_size: usize = ftyp.iter().map(|node| node.size()).sum();
+    let mdat_header_size = if large_mdat { 16 } else { 8 };
+
+    let mut chunk_offsets: Vec<Vec<u64>> = vec![Vec::new(); tracks.len()];
+    let mut offset = (ftyp_size + moov_size + mdat_header_size) as u64;
+    for chunk in &chunks {
+        chunk_offsets[chunk.track_idx].push(offset);
+        offset += chunk.data_size as u64;
+    }
+    let mut boxes = ftyp;
+    boxes.push(progressive_moov(
+        tracks,
+        &track_samples,
+        &chunk_offsets,
+        large_mdat,
+    )?);
+    let mut writer = write_output(output, &boxes)?;
+    let write_error = |e: std::io::Error| format!("{} : {}", output, e);
+    match large_mdat {
+        true => {
+            writer.write_all(&1u32.to_be_bytes()).map_err(write_error)?;
+            writer.write_all(b"mdat").map_err(write_error)?;
+            writer
+                .write_all(&(mdat_size + 16).to_be_bytes())
+                .map_err(write_error)?;
+        }
+        false => {
+            writer
+                .write_all(&((mdat_size + 8) as u32).to_be_bytes())
+                .map_err(write_error)?;
+            writer.write_all(b"mdat").map_err(write_error)?;
+        }
+    }
+    let mut cached: Option<((usize, usize), Vec<u8>)> = None;
+    for chunk in &chunks {
+        let key = (chunk.track_idx, chunk.segment_idx);
+        if cached.as_ref().map(|(cached_key, _)| *cached_key) != Some(key) {
+            let segment = &tracks[chunk.track_idx].representation.segments[chunk.segment_idx];
+            cached = Some((
+                key,
+                layout::read_segment(output_directory, base_url, &segment.url)?,
+            ));
+        }
+        if let Some((_, data)) = &cached {
+            writer
+                .write_all(&data[chunk.data_offset..chunk.data_offset + chunk.data_size])
+                .map_err(write_error)?;
+        }
+    }
+    writer.flush().map_err(write_error)
+}
+
+/// Muxes one video and one or more audio representations of a mirror into a single MP4.
+pub fn mux_mirror(
+    output_directory: &str,
+    output: &str,
+    options: &MuxOptions,
+) -> Result<(), String> {
+    let (_, url_info) =
+        layout::read_mirror(output_directory).ok_or("Could not read mirror manifest")?;
+    let representations = select_representations(&url_info, options)?;
+    let mut tracks = Vec::new();
+    for (idx, representation) in representations.into_iter().enumerate() {
+        println!(
+            "track {} : rep {} {} bandwidth {}",
+            idx + 1,
+            representation.id,
+            representation.mime_type,
+            representation.bandwidth
+        );
+        tracks.push(load_track(
+            output_directory,
+            &url_info.base_url,
+            representation,
+            idx as u32 + 1,
+        )?);
+    }
+    rebase_tracks(&mut tracks);
+    match options.progressive {
+        true => write_progressive(output_directory, &url_info.base_url, &tracks, output),
+        false => write_fragmented(output_directory, &url_info.base_url, &tracks, output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mux_mirror, MuxOptions};
+    use crate::isobmff::{self, tests::init_segment, tests::media_segment};
+
+    fn write_mirror(name: &str) -> std::path::PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("dash-mirror-mux-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(directory.join("video")).unwrap();
+        std::fs::create_dir_all(directory.join("audio")).unwrap();
+        std::fs::write(
+            directory.join("manifest.mpd"),
+            r#"<MPD type="static" mediaPresentationDuration="PT4S">
+            <Period duration="PT4S">
+            <AdaptationSet mimeType="video/mp4">
+            <SegmentTemplate timescale="90000" duration="180000" media="video/$RepresentationID$_$Number$.m4s" initialization="video/$RepresentationID$_init.mp4"/>
+            <Representation id="v1" bandwidth="1000"/>
+            <Representation id="v2" bandwidth="2000"/>
+            </AdaptationSet>
+            <AdaptationSet mimeType="audio/mp4" lang="en">
+            <SegmentTemplate timescale="48000" duration="96000" media="audio/$Number$.m4s" initialization="audio/init.mp4"/>
+            <Representation id="a1" bandwidth="128"/>
+            </AdaptationSet>
+            </Period>
+            </MPD>"#,
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("video/v2_init.mp4"),
+            init_segment(1, 90000, b"vide"),
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("video/v2_1.m4s"),
+            media_segment(1, 900000, 90000, &[b"V1V1", b"V2"]),
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("video/v2_2.m4s"),
+            media_segment(1, 1080000, 90000, &[b"V3", b"V4"]),
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("audio/init.mp4"),
+            init_segment(1, 48000, b"soun"),
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("audio/1.m4s"),
+            media_segment(1, 480000, 48000, &[b"A1", b"A2"]),
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("audio/2.m4s"),
+            media_segment(1, 576000, 48000, &[b"A3", b"A4A4"]),
+        )
+        .unwrap();
+        directory
+    }
+
+    #[test]
+    fn mux_fragmented() {
+        let directory = write_mirror("fragmented");
+        let output = directory.join("out.mp4");
+        mux_mirror(
+            directory.to_str().unwrap(),
+            output.to_str().unwrap(),
+            &MuxOptions::default(),
+        )
+        .unwrap();
+        let data = std::fs::read(&output).unwrap();
+        let tracks = isobmff::parse_init_tracks(&data);
+        let ids: Vec<(u32, u32)> = tracks
+            .iter()
+            .map(|track| (track.track_id, track.trex.unwrap().track_id))
+            .collect();
+        assert_eq!(ids, [(1, 1), (2, 2)]);
+        let fragments = isobmff::parse_track_fragments(&data, &tracks);
+        let timing: Vec<(u32, Option<u64>)> = fragments
+            .iter()
+            .map(|fragment| (fragment.track_id, fragment.base_media_decode_time))
+            .collect();
+        assert_eq!(
+            timing,
+            [
+                (1, Some(0)),
+                (2, Some(0)),
+                (1, Some(180000)),
+                (2, Some(96000))
+            ]
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn mux_progressive() {
+        let directory = write_mirror("progressive");
+        let output = directory.join("out.mp4");
+        let options = MuxOptions {
+            video: Some("v2".to_string()),
+            audio: vec!["a1".to_string()],
+            progressive: true,
+            ..Default::default()
+        };
+        mux_mirror(
+            directory.to_str().unwrap(),
+            output.to_str().unwrap(),
+            &options,
+        )
+        .unwrap();
+        let data = std::fs::read(&output).unwrap();
+        let moov = isobmff::find_path(&data, &[b"moov"]).unwrap();
+        assert!(isobmff::find_child(&data, &moov, b"mvex").is_none());
+        let traks: Vec<isobmff::BoxHeader> = isobmff::children(&data, &moov)
+            .into_iter()
+            .filter(|child| &child.box_type == b"trak")
+            .collect();
+        assert_eq!(traks.len(), 2);
+        let mut payloads = Vec::new();
+        for trak in &traks {
+            let mdia = isobmff::find_child(&data, trak, b"mdia").unwrap();
+            let minf = isobmff::find_child(&data, &mdia, b"minf").unwrap();
+            let stbl = isobmff::find_child(&data, &minf, b"stbl").unwrap();
+            let stsz = isobmff::find_child(&data, &stbl, b"stsz").unwrap();
+            let stco = isobmff::find_child(&data, &stbl, b"stco").unwrap();
+            let stsz = stsz.payload(&data);
+            let stco = stco.payload(&data);
+            assert_eq!(isobmff::read_u32(stsz, 8), Some(4));
+            assert_eq!(isobmff::read_u32(stco, 4), Some(2));
+            let first_chunk = isobmff::read_u32(stco, 8).unwrap() as usize;
+            let first_size = isobmff::read_u32(stsz, 12).unwrap() as usize;
+            payloads.push(data[first_chunk..first_chunk + first_size].to_vec());
+        }
+        assert_eq!(payloads, [b"V1V1".to_vec(), b"A1".to_vec()]);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+use crate::mpd::{RepresentationSegments, RepresentationUrls, Segments};
+
+/// Order in which the segments of a mirror are downloaded.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Order {
+    /// Every segment of one representation after another, as listed in the manifest
+    #[default]
+    Manifest,
+    /// Init segments first, then media segments of all representations by presentation time
+    Interleaved,
+    /// Init segments first, then the lowest bitrate representation of every adaptation
+    /// set by presentation time, then the next higher ones
+    LowestBitrate,
+}
+
+/// Start of `time` on the presentation timeline of its period, in seconds.
+fn presentation_time(representation: &RepresentationUrls, time: u64) -> f64 {
+    time.saturating_sub(representation.presentation_time_offset) as f64
+        / representation.timescale.max(1) as f64
+}
+
+/// Rank of each representation by bandwidth within its adaptation set, 0 for the lowest.
+fn bandwidth_ranks(representations: &[RepresentationSegments]) -> Vec<usize> {
+    representations
+        .iter()
+        .map(|representation| {
+            let representation = &representation.info;
+            representations
+                .iter()
+                .map(|other| &other.info)
+                .filter(|other| {
+                    (other.period_idx, other.adaptation_set_idx)
+                        == (representation.period_idx, representation.adaptation_set_idx)
+                })
+                .filter(|other| other.bandwidth < representation.bandwidth)
+                .map(|other| other.bandwidth)
+                .collect::<std::collections::BTreeSet<_>>()
+                .len()
+        })
+        .collect()
+}
+
+/// A url of a mirror, in download order.
+pub struct OrderedUrl {
+    /// Index of the representation in the slice given to `ordered_urls`.
+    pub representation_idx: usize,
+    /// Segment number, `None` for the initialization segment.
+    pub number: Option<u64>,
+    pub url: String,
+}
+
+/// Iterator over the urls of some representations in download order. Only the next
+/// segment of every representation is expanded, so memory does not grow with the length
+/// of the timelines.
+pub struct OrderedUrls<'a> {
+    representations: &'a [RepresentationSegments<'a>],
+    order: Order,
+    ranks: Vec<usize>,
+    segments: Vec<std::iter::Peekable<Segments<'a>>>,
+    /// Representation whose initialization segment or, in manifest order, whose
+    /// segments come next.
+    current: usize,
+    initialization_done: bool,
+}
+
+/// Urls of `representations` in download order. Stopping at any point leaves a prefix of
+/// the presentation which plays from the start, apart from `Order::Manifest`.
+pub fn ordered_urls<'a>(
+    representations: &'a [RepresentationSegments<'a>],
+    order: Order,
+) -> OrderedUrls<'a> {
+    OrderedUrls {
+        representations,
+        order,
+        ranks: match order {
+            Order::LowestBitrate => bandwidth_ranks(representations),
+            _ => vec![0; representations.len()],
+        },
+        segments: representations
+            .iter()
+            .map(|representation| representation.segments().peekable())
+            .collect(),
+        current: 0,
+        initialization_done: false,
+    }
+}
+
+impl OrderedUrls<'_> {
+    fn initialization(&self, representation_idx: usize) -> Option<OrderedUrl> {
+        let url = self.representations[representation_idx]
+            .info
+            .initialization
+            .clone()?;
+        Some(OrderedUrl {
+            representation_idx,
+            number: None,
+            url,
+        })
+    }
+
+    fn segment(&mut self, representation_idx: usize) -> Option<OrderedUrl> {
+        let segment = self.segments[representation_idx].next()?;
+        Some(OrderedUrl {
+            representation_idx,
+            number: Some(segment.number),
+            url: segment.url,
+        })
+    }
+
+    /// Every segment of one representation after the other.
+    fn next_in_manifest_order(&mut self) -> Option<OrderedUrl> {
+        while self.current < self.representations.len() {
+            if !self.initialization_done {
+                self.initialization_done = true;
+                if let Some(initialization) = self.initialization(self.current) {
+                    return Some(initialization);
+                }
+            }
+            if let Some(segment) = self.segment(self.current) {
+                return Some(segment);
+            }
+            self.current += 1;
+            self.initialization_done = false;
+        }
+        None
+    }
+
+    /// All initialization segments, then the earliest next segment of any
+    /// representation, the first one in the manifest for equal times.
+    fn next_by_time(&mut self) -> Option<OrderedUrl> {
+        while self.current < self.representations.len() {
+            self.current += 1;
+            if let Some(initialization) = self.initialization(self.current - 1) {
+                return Some(initialization);
+            }
+        }
+        let (representation_idx, _) = self
+            .segments
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(representation_idx, segments)| {
+                let info = &self.representations[representation_idx].info;
+                let segment = segments.peek()?;
+                Some((
+                    representation_idx,
+                    (
+                        self.ranks[representation_idx],
+                        info.period_idx,
+                        presentation_time(info, segment.time),
+                    ),
+                ))
+            })
+            .min_by(|(_, a), (_, b)| (a.0, a.1).cmp(&(b.0, b.1)).then(a.2.total_cmp(&b.2)))?;
+        self.segment(representation_idx)
+    }
+}
+
+impl Iterator for OrderedUrls<'_> {
+    type Item = OrderedUrl;
+
+    fn next(&mut self) -> Option<OrderedUrl> {
+        match self.order {
+            Order::Manifest => self.next_in_manifest_order(),
+            Order::Interleaved | Order::LowestBitrate => self.next_by_time(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ordered_urls, Order};
+    use crate::mpd::parse_manifest;
+
+    #[test]
+    fn download_orders() {
+        let xml_text = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <MPD type="static" mediaPresentationDuration="PT6S">
+        <Period duration="PT6S">
+            <AdaptationSet mimeType="video/mp4">
+                <SegmentTemplate timescale="1000" duration="2000" media="$RepresentationID$/$Number$.m4s" initialization="$RepresentationID$/init.mp4"/>
+                <Representation id="v2" bandwidth="2000000"/>
+                <Representation id="v1" bandwidth="1000000"/>
+            </AdaptationSet>
+            <AdaptationSet mimeType="audio/mp4">
+                <SegmentTemplate timescale="48000" presentationTimeOffset="48000" media="a/$Time$.m4s" initialization="a/init.mp4">
+                <SegmentTimeline>
+                    <S t="48000" d="144000" r="1"/>
+                </SegmentTimeline>
+                </SegmentTemplate>
+                <Representation id="a" bandwidth="128000"/>
+            </AdaptationSet>
+        </Period>
+        </MPD>"#
+            .to_owned();
+        let manifest = parse_manifest(
+            xml_text,
+            "http://test.com/manifest.mpd",
+            &Default::default(),
+        )
+        .unwrap();
+        let representations: Vec<_> = manifest.representations().collect();
+        let ordered = |order| -> Vec<String> {
+            ordered_urls(&representations, order)
+                .map(|ordered| {
+                    let url = ordered.url.strip_prefix("http://test.com/").unwrap();
+                    let info = &representations[ordered.representation_idx].info;
+                    assert!(url.starts_with(&info.id[..1]));
+                    url.to_string()
+                })
+                .collect()
+        };
+        assert_eq!(
+            ordered(Order::Manifest),
+            [
+                "v2/init.mp4",
+                "v2/1.m4s",
+                "v2/2.m4s",
+                "v2/3.m4s",
+                "v1/init.mp4",
+                "v1/1.m4s",
+                "v1/2.m4s",
+                "v1/3.m4s",
+                "a/init.mp4",
+                "a/48000.m4s",
+                "a/192000.m4s",
+            ]
+        );
+        assert_eq!(
+            ordered(Order::Interleaved),
+            [
+                "v2/init.mp4",
+                "v1/init.mp4",
+                "a/init.mp4",
+                "v2/1.m4s",
+                "v1/1.m4s",
+                "a/48000.m4s",
+                "v2/2.m4s",
+                "v1/2.m4s",
+                "a/192000.m4s",
+                "v2/3.m4s",
+                "v1/3.m4s",
+            ]
+        );
+        assert_eq!(
+            ordered(Order::LowestBitrate),
+            [
+                "v2/init.mp4",
+                "v1/init.mp4",
+                "a/init.mp4",
+                "v1/1.m4s",
+                "a/48000.m4s",
+                "v1/2.m4s",
+                "a/192000.m4s",
+                "v1/3.m4s",
+                "v2/1.m4s",
+                "v2/2.m4s",
+                "v2/3.m4s",
+            ]
+        );
+    }
+}
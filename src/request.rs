@@ -0,0 +1,559 @@
+use std::sync::Mutex;
+
+use crate::fetch::{FetchRequest, FetchResponse, Fetcher};
+use crate::util;
+
+pub enum Auth {
+    Basic { user: String, password: String },
+    Bearer(String),
+}
+
+impl Auth {
+    /// Parses `user:password` as for curl's `--user`.
+    pub fn basic(credentials: &str) -> Auth {
+        let (user, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+        Auth::Basic {
+            user: user.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    fn header_value(&self) -> String {
+        use base64::Engine;
+        match self {
+            Auth::Basic { user, password } => format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password))
+            ),
+            Auth::Bearer(token) => format!("Bearer {}", token),
+        }
+    }
+}
+
+/// Parses a `Name: value` header argument.
+pub fn parse_header(header: &str) -> Result<(String, String), String> {
+    match header.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("invalid header {}, expected Name: value", header)),
+    }
+}
+
+fn host(url: &str) -> Option<String> {
+    reqwest::Url::parse(url)
+        .ok()?
+        .host_str()
+        .map(|host| host.to_ascii_lowercase())
+}
+
+/// Credentials from a netrc file, by machine name.
+#[derive(Default)]
+pub struct Netrc {
+    machines: Vec<(String, String, String)>,
+    default: Option<(String, String)>,
+}
+
+impl Netrc {
+    pub fn parse(text: &str) -> Netrc {
+        let mut netrc = Netrc::default();
+        let mut tokens = text.split_whitespace();
+        // machine name, None for the default entry
+        let mut entry: Option<Option<String>> = None;
+        let (mut login, mut password) = (String::new(), String::new());
+        while let Some(token) = tokens.next() {
+            match token {
+                "machine" | "default" => {
+                    netrc.finish(entry.take(), &login, &password);
+                    login.clear();
+                    password.clear();
+                    entry = Some(match token {
+                        "machine" => Some(tokens.next().unwrap_or_default().to_ascii_lowercase()),
+                        _ => None,
+                    });
+                }
+                "login" => login = tokens.next().unwrap_or_default().to_string(),
+                "password" => password = tokens.next().unwrap_or_default().to_string(),
+                "account" => {
+                    tokens.next();
+                }
+                "macdef" => {
+                    // macro bodies end with an empty line, which whitespace splitting loses,
+                    // so everything after a macro definition is ignored
+                    netrc.finish(entry.take(), &login, &password);
+                    return netrc;
+                }
+                _ => (),
+            }
+        }
+        netrc.finish(entry, &login, &password);
+        netrc
+    }
+
+    fn finish(&mut self, entry: Option<Option<String>>, login: &str, password: &str) {
+        match entry {
+            Some(Some(machine)) => {
+                self.machines
+                    .push((machine, login.to_string(), password.to_string()))
+            }
+            Some(None) => self.default = Some((login.to_string(), password.to_string())),
+            None => (),
+        }
+    }
+
+    pub fn load(path: &std::path::Path) -> Result<Netrc, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{} : {}", path.display(), e))?;
+        Ok(Netrc::parse(&text))
+    }
+
+    /// `~/.netrc`, or `$NETRC` if set.
+    pub fn default_path() -> Option<std::path::PathBuf> {
+        if let Some(path) = std::env::var_os("NETRC") {
+            return Some(path.into());
+        }
+        std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".netrc"))
+    }
+
+    pub fn auth(&self, host: &str) -> Option<Auth> {
+        let (user, password) = self
+            .machines
+            .iter()
+            .find(|(machine, _, _)| machine == host)
+            .map(|(_, login, password)| (login, password))
+            .or(self
+                .default
+                .as_ref()
+                .map(|(login, password)| (login, password)))?;
+        Some(Auth::Basic {
+            user: user.clone(),
+            password: password.clone(),
+        })
+    }
+}
+
+struct Cookie {
+    domain: String,
+    include_subdomains: bool,
+    path: String,
+    secure: bool,
+    /// Unix time, 0 for session cookies.
+    expires: u64,
+    name: String,
+    value: String,
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Cookies in the Netscape cookie file format used by curl and wget, updated from the
+/// `Set-Cookie` headers of responses.
+#[derive(Default)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+impl CookieJar {
+    pub fn parse(text: &str) -> CookieJar {
+        let mut jar = CookieJar::default();
+        for line in text.lines() {
+            let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 7 {
+                eprintln!("Ignoring invalid cookie line {}", line);
+                continue;
+            }
+            jar.cookies.push(Cookie {
+                domain: fields[0].trim_start_matches('.').to_ascii_lowercase(),
+                include_subdomains: fields[1] == "TRUE",
+                path: fields[2].to_string(),
+                secure: fields[3] == "TRUE",
+                expires: fields[4].parse().unwrap_or_default(),
+                name: fields[5].to_string(),
+                value: fields[6].to_string(),
+            });
+        }
+        jar
+    }
+
+    /// Loads `path`, an empty jar if it does not exist yet.
+    pub fn load(path: &std::path::Path) -> Result<CookieJar, String> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Ok(CookieJar::parse(&text)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(CookieJar::default()),
+            Err(e) => Err(format!("{} : {}", path.display(), e)),
+        }
+    }
+
+    pub fn save(&self, path: &std::path::Path) -> Result<(), String> {
+        let mut text = String::from("# Netscape HTTP Cookie File\n");
+        for cookie in &self.cookies {
+            text.push_str(&format!(
+                "{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                if cookie.include_subdomains { "." } else { "" },
+                cookie.domain,
+                if cookie.include_subdomains {
+                    "TRUE"
+                } else {
+                    "FALSE"
+                },
+                cookie.path,
+                if cookie.secure { "TRUE" } else { "FALSE" },
+                cookie.expires,
+                cookie.name,
+                cookie.value
+            ));
+        }
+        std::fs::write(path, text).map_err(|e| format!("{} : {}", path.display(), e))
+    }
+
+    /// `Cookie` header value for a request to `url`.
+    pub fn header(&self, url: &str) -> Option<String> {
+        let url = reqwest::Url::parse(url).ok()?;
+        let host = url.host_str()?.to_ascii_lowercase();
+        let now = now();
+        let cookies: Vec<String> = self
+            .cookies
+            .iter()
+            .filter(|cookie| {
+                (cookie.domain == host
+                    || (cookie.include_subdomains
+                        && host.ends_with(&format!(".{}", cookie.domain))))
+                    && url.path().starts_with(&cookie.path)
+                    && (!cookie.secure || url.scheme() == "https")
+                    && (cookie.expires == 0 || cookie.expires > now)
+            })
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect();
+        (!cookies.is_empty()).then(|| cookies.join("; "))
+    }
+
+    /// Stores a `Set-Cookie` header received from `url`.
+    pub fn store(&mut self, url: &str, set_cookie: &str) {
+        let Ok(url) = reqwest::Url::parse(url) else {
+            return;
+        };
+        let mut attributes = set_cookie.split(';').map(str::trim);
+        let Some((name, value)) = attributes.next().and_then(|pair| pair.split_once('=')) else {
+            return;
+        };
+        let default_path = match url.path().rfind('/') {
+            Some(0) | None => "/".to_string(),
+            Some(pos) => url.path()[..pos].to_string(),
+        };
+        let mut cookie = Cookie {
+            domain: url.host_str().unwrap_or_default().to_ascii_lowercase(),
+            include_subdomains: false,
+            path: default_path,
+            secure: false,
+            expires: 0,
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+        };
+        let mut max_age = false;
+        for attribute in attributes {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            match key.to_ascii_lowercase().as_str() {
+                "domain" if !value.is_empty() => {
+                    let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                    // only the host itself or a parent domain of it may be set
+                    if cookie.domain == domain || cookie.domain.ends_with(&format!(".{}", domain)) {
+                        cookie.domain = domain;
+                        cookie.include_subdomains = true;
+                    } else {
+                        return;
+                    }
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_string(),
+                "secure" => cookie.secure = true,
+                // a max age of 0 or less removes the cookie, expiry 1 is in the past
+                "max-age" => {
+                    if let Ok(age) = value.parse::<i64>() {
+                        cookie.expires = match age > 0 {
+                            true => now() + age as u64,
+                            false => 1,
+                        };
+                        max_age = true;
+                    }
+                }
+                // Max-Age takes precedence, whichever comes first
+                "expires" if !max_age => {
+                    if let Some(time) = util::parse_http_date(value) {
+                        cookie.expires = time
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .map(|since_epoch| since_epoch.as_secs().max(1))
+                            .unwrap_or(1);
+                    }
+                }
+                _ => (),
+            }
+        }
+        self.cookies.retain(|existing| {
+            (&existing.domain, &existing.path, &existing.name)
+                != (&cookie.domain, &cookie.path, &cookie.name)
+        });
+        self.cookies.push(cookie);
+    }
+}
+
+/// Headers, authentication and cookies added to every request, manifest and segments
+/// alike.
+#[derive(Default)]
+pub struct RequestOptions {
+    pub headers: Vec<(String, String)>,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    /// Sent to `auth_host` only, so credentials do not leak through redirects.
+    pub auth: Option<Auth>,
+    pub auth_host: Option<String>,
+    /// Credentials for hosts without `auth`.
+    pub netrc: Option<Netrc>,
+    pub cookies: Option<Mutex<CookieJar>>,
+}
+
+impl RequestOptions {
+    /// Headers for a request to `url`, in addition to the ones of the request itself.
+    pub fn headers(&self, url: &str) -> Vec<(String, String)> {
+        let mut headers = self.headers.clone();
+        if let Some(user_agent) = &self.user_agent {
+            headers.push(("User-Agent".to_string(), user_agent.clone()));
+        }
+        if let Some(referer) = &self.referer {
+            headers.push(("Referer".to_string(), referer.clone()));
+        }
+        let host = host(url);
+        let auth = match (&self.auth, &self.auth_host) {
+            (Some(auth), Some(auth_host)) if host.as_ref() == Some(auth_host) => {
+                Some(auth.header_value())
+            }
+            (Some(auth), None) => Some(auth.header_value()),
+            _ => None,
+        };
+        let auth = auth.or_else(|| {
+            let netrc_auth = self.netrc.as_ref()?.auth(host.as_deref()?)?;
+            Some(netrc_auth.header_value())
+        });
+        if let Some(auth) = auth {
+            headers.push(("Authorization".to_string(), auth));
+        }
+        if let Some(cookies) = &self.cookies {
+            if let Some(cookie) = cookies.lock().unwrap().header(url) {
+                headers.push(("Cookie".to_string(), cookie));
+            }
+        }
+        headers
+    }
+
+    pub fn save_cookies(&self, path: &std::path::Path) -> Result<(), String> {
+        match &self.cookies {
+            Some(cookies) => cookies.lock().unwrap().save(path),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Fetcher adding the headers of `RequestOptions` to every request and keeping the cookie
+/// jar up to date. Headers of the request itself take precedence.
+pub struct RequestFetcher<'a> {
+    pub fetcher: &'a dyn Fetcher,
+    pub options: &'a RequestOptions,
+}
+
+impl RequestFetcher<'_> {
+    /// `request` with the configured headers it does not set itself.
+    fn prepare(&self, request: &FetchRequest) -> FetchRequest {
+        let mut headers = request.headers.clone();
+        for (name, value) in self.options.headers(&request.url) {
+            if !headers
+                .iter()
+                .any(|(key, _)| key.eq_ignore_ascii_case(&name))
+            {
+                headers.push((name, value));
+            }
+        }
+        FetchRequest {
+            url: request.url.clone(),
+            headers,
+        }
+    }
+
+    fn store_cookies(&self, url: &str, response: &FetchResponse) {
+        if let Some(cookies) = &self.options.cookies {
+            let mut cookies = cookies.lock().unwrap();
+            for (name, value) in &response.headers {
+                if name.eq_ignore_ascii_case("Set-Cookie") {
+                    cookies.store(url, value);
+                }
+            }
+        }
+    }
+}
+
+impl Fetcher for RequestFetcher<'_> {
+    fn fetch(&self, request: &FetchRequest) -> Result<FetchResponse, String> {
+        let request = self.prepare(request);
+        let response = self.fetcher.fetch(&request)?;
+        self.store_cookies(&request.url, &response);
+        Ok(response)
+    }
+
+    fn fetch_to(
+        &self,
+        request: &FetchRequest,
+        sink: &mut dyn std::io::Write,
+    ) -> Result<FetchResponse, String> {
+        let request = self.prepare(request);
+        let response = self.fetcher.fetch_to(&request, sink)?;
+        self.store_cookies(&request.url, &response);
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_header, Auth, CookieJar, Netrc, RequestFetcher, RequestOptions};
+    use crate::fetch::{FetchRequest, Fetcher};
+    use crate::mock::{MockOrigin, Route};
+    use std::sync::Mutex;
+
+    #[test]
+    fn netrc_and_headers() {
+        assert_eq!(
+            parse_header("X-Api-Key: a:b").unwrap(),
+            ("X-Api-Key".to_string(), "a:b".to_string())
+        );
+        assert!(parse_header("no header").is_err());
+
+        let netrc = Netrc::parse(
+            "machine origin.test login joe password s3cret\ndefault login anon password none\n",
+        );
+        let options = RequestOptions {
+            user_agent: Some("Player/1.0".to_string()),
+            auth: Some(Auth::Bearer("token".to_string())),
+            auth_host: Some("cdn.test".to_string()),
+            netrc: Some(netrc),
+            ..Default::default()
+        };
+        let headers = options.headers("https://origin.test/a.mpd");
+        assert!(headers.contains(&("User-Agent".to_string(), "Player/1.0".to_string())));
+        assert!(headers.contains(&(
+            "Authorization".to_string(),
+            "Basic am9lOnMzY3JldA==".to_string()
+        )));
+        let headers = options.headers("https://cdn.test/1.m4s");
+        assert!(headers.contains(&("Authorization".to_string(), "Bearer token".to_string())));
+        let headers = options.headers("https://other.test/1.m4s");
+        assert!(headers.contains(&(
+            "Authorization".to_string(),
+            "Basic YW5vbjpub25l".to_string()
+        )));
+        assert_eq!(
+            Auth::basic("user:pa:ss").header_value(),
+            "Basic dXNlcjpwYTpzcw=="
+        );
+    }
+
+    #[test]
+    fn cookie_jar() {
+        let mut jar = CookieJar::parse(
+            "# Netscape HTTP Cookie File\n\
+             .origin.test\tTRUE\t/\tFALSE\t0\tsession\tabc\n\
+             #HttpOnly_origin.test\tFALSE\t/vod\tTRUE\t0\tsecure\t1\n\
+             origin.test\tFALSE\t/\tFALSE\t1\texpired\t1\n",
+        );
+        assert_eq!(
+            jar.header("http://cdn.origin.test/vod/a.m4s").as_deref(),
+            Some("session=abc")
+        );
+        assert_eq!(
+            jar.header("https://origin.test/vod/a.m4s").as_deref(),
+            Some("session=abc; secure=1")
+        );
+        jar.store(
+            "https://origin.test/vod/manifest.mpd",
+            "session=def; Path=/",
+        );
+        jar.store("https://origin.test/vod/manifest.mpd", "token=t");
+        jar.store("https://origin.test/", "evil=1; Domain=other.test");
+        assert_eq!(
+            jar.header("https://origin.test/vod/a.m4s").as_deref(),
+            Some("secure=1; session=def; token=t")
+        );
+        assert_eq!(
+            jar.header("https://origin.test/live/a.m4s").as_deref(),
+            Some("session=def")
+        );
+        jar.store("https://origin.test/vod/manifest.mpd", "token=t; Max-Age=0");
+        assert!(!jar
+            .header("https://origin.test/vod/a.m4s")
+            .unwrap()
+            .contains("token"));
+        jar.store(
+            "https://origin.test/vod/manifest.mpd",
+            "past=1; Expires=Sun, 06 Nov 1994 08:49:37 GMT",
+        );
+        jar.store(
+            "https://origin.test/vod/manifest.mpd",
+            "future=1; Expires=Fri, 01-Jan-2100 00:00:00 GMT",
+        );
+        jar.store(
+            "https://origin.test/vod/manifest.mpd",
+            "aged=1; Expires=Fri, 01 Jan 2100 00:00:00 GMT; Max-Age=0",
+        );
+        jar.store(
+            "https://origin.test/vod/manifest.mpd",
+            "kept=1; Max-Age=60; Expires=Sun, 06 Nov 1994 08:49:37 GMT",
+        );
+        assert_eq!(
+            jar.header("https://origin.test/vod/a.m4s").as_deref(),
+            Some("secure=1; session=def; future=1; kept=1")
+        );
+
+        let path = std::env::temp_dir().join(format!("dash-mirror-cookies-{}", std::process::id()));
+        jar.save(&path).unwrap();
+        let loaded = CookieJar::load(&path).unwrap();
+        assert_eq!(
+            loaded.header("https://origin.test/vod/a.m4s"),
+            jar.header("https://origin.test/vod/a.m4s")
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn request_fetcher() {
+        let origin = MockOrigin::new();
+        origin.add("http://origin.test/a", Route::Body(b"a".to_vec()));
+        let options = RequestOptions {
+            headers: vec![("X-Custom".to_string(), "1".to_string())],
+            referer: Some("http://player.test/".to_string()),
+            cookies: Some(Mutex::new(CookieJar::parse(
+                "origin.test\tFALSE\t/\tFALSE\t0\tid\t7\n",
+            ))),
+            ..Default::default()
+        };
+        let fetcher = RequestFetcher {
+            fetcher: &origin,
+            options: &options,
+        };
+        let mut request = FetchRequest::new("http://origin.test/a");
+        request
+            .headers
+            .push(("x-custom".to_string(), "2".to_string()));
+        assert_eq!(fetcher.fetch(&request).unwrap().body, b"a");
+        assert_eq!(
+            origin.request_headers("http://origin.test/a").unwrap(),
+            vec![
+                ("x-custom".to_string(), "2".to_string()),
+                ("Referer".to_string(), "http://player.test/".to_string()),
+                ("Cookie".to_string(), "id=7".to_string()),
+            ]
+        );
+    }
+}
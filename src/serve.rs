@@ -0,0 +1,602 @@
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::impair::{Impairments, Target};
+use crate::layout;
+use crate::live::{self, LiveOptions, LiveStream};
+use crate::util::reason_phrase;
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Part of a file which is sent as the body of a response, positioned at its start.
+pub struct FileBody {
+    pub file: std::fs::File,
+    pub length: u64,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Sent in place of `body` when set.
+    pub file: Option<FileBody>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Response {
+        Response {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body,
+            file: None,
+        }
+    }
+
+    pub fn error(status: u16) -> Response {
+        let body = format!("{} {}\n", status, reason_phrase(status));
+        Response::new(status, "text/plain; charset=utf-8", body.into_bytes())
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn content_length(&self) -> u64 {
+        match &self.file {
+            Some(file) => file.length,
+            None => self.body.len() as u64,
+        }
+    }
+
+    /// Reader of the body, which streams it from the file if there is one.
+    pub fn body_reader(&self) -> Box<dyn Read + '_> {
+        match &self.file {
+            Some(file) => Box::new((&file.file).take(file.length)),
+            None => Box::new(&self.body[..]),
+        }
+    }
+}
+
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "mpd" => "application/dash+xml",
+        "m4s" => "video/iso.segment",
+        "mp4" | "m4v" => "video/mp4",
+        "m4a" => "audio/mp4",
+        "vtt" => "text/vtt",
+        "ttml" => "application/ttml+xml",
+        "m3u8" => "application/vnd.apple.mpegurl",
+        "json" => "application/json",
+        "html" => "text/html; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(pos + 1..pos + 3)?).ok()?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                pos += 3;
+            }
+            byte => {
+                out.push(byte);
+                pos += 1;
+            }
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Percent-encodes everything but unreserved characters and `/`, for use in an href.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Maps a request path to a file below `root`, `None` if it would leave `root`.
+pub fn resolve_path(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(request_path)?;
+    let mut path = root.to_path_buf();
+    for component in decoded.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => return None,
+            component if component.contains('\\') => return None,
+            component => path.push(component),
+        }
+    }
+    Some(path)
+}
+
+/// Parses a single `bytes=` range against a resource of `length` bytes. `Err` means the
+/// range can not be satisfied, `Ok(None)` that the whole resource should be sent.
+fn parse_range(value: &str, length: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(range) = value.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if range.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = range.split_once('-').ok_or(())?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().map_err(|_| ())?;
+            if suffix == 0 {
+                return Err(());
+            }
+            (length.saturating_sub(suffix), length.saturating_sub(1))
+        }
+        (start, "") => (start.parse().map_err(|_| ())?, length.saturating_sub(1)),
+        (start, end) => {
+            let end: u64 = end.parse().map_err(|_| ())?;
+            (
+                start.parse().map_err(|_| ())?,
+                end.min(length.saturating_sub(1)),
+            )
+        }
+    };
+    if start > end || start >= length {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+fn directory_listing(request_path: &str, directory: &Path) -> Response {
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return Response::error(404);
+    };
+    let mut names: Vec<String> = entries
+        .flatten()
+        .map(|entry| {
+            let mut name = entry.file_name().to_string_lossy().to_string();
+            if entry.path().is_dir() {
+                name.push('/');
+            }
+            name
+        })
+        .collect();
+    names.sort();
+    let title = html_escape(request_path);
+    let mut body = format!(
+        "<!DOCTYPE html>\n<html><head><title>Index of {0}</title></head>\n<body><h1>Index of {0}</h1>\n<ul>\n",
+        title
+    );
+    if request_path != "/" {
+        body.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for name in names {
+        body.push_str(&format!(
+            "<li><a href=\"{}\">{}</a></li>\n",
+            percent_encode(&name),
+            html_escape(&name)
+        ));
+    }
+    body.push_str("</ul></body></html>\n");
+    Response::new(200, "text/html; charset=utf-8", body.into_bytes())
+}
+
+/// Status and byte range `start..end` of the body of `length` bytes which `request`
+/// asks for, or the response to an unsatisfiable range.
+fn requested_range(request: &Request, length: u64) -> Result<(u16, u64, u64), Response> {
+    match request
+        .header("Range")
+        .map(|range| parse_range(range, length))
+    {
+        Some(Ok(Some((start, end)))) => Ok((206, start, end + 1)),
+        Some(Err(_)) => {
+            Err(Response::error(416).header("Content-Range", &format!("bytes */{}", length)))
+        }
+        _ => Ok((200, 0, length)),
+    }
+}
+
+fn ranged(response: Response, start: u64, end: u64, length: u64) -> Response {
+    match response.status {
+        206 => response.header(
+            "Content-Range",
+            &format!("bytes {}-{}/{}", start, end - 1, length),
+        ),
+        _ => response,
+    }
+}
+
+/// Serves a file, honouring a single byte range. Only the requested range is read, as
+/// the response is sent.
+pub fn file_response(request: &Request, path: &Path) -> Response {
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Response::error(404),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => return Response::error(403),
+        Err(_) => return Response::error(500),
+    };
+    let Ok(length) = file.metadata().map(|metadata| metadata.len()) else {
+        return Response::error(500);
+    };
+    let (status, start, end) = match requested_range(request, length) {
+        Ok(range) => range,
+        Err(response) => return response,
+    };
+    if request.method != "HEAD" && file.seek(SeekFrom::Start(start)).is_err() {
+        return Response::error(500);
+    }
+    let mut response = Response::new(status, mime_type(path), Vec::new());
+    response.file = Some(FileBody {
+        file,
+        length: end - start,
+    });
+    ranged(response, start, end, length)
+}
+
+/// Serves `data` held in memory, honouring a single byte range.
+pub fn bytes_response(request: &Request, data: Vec<u8>, content_type: &str) -> Response {
+    let length = data.len() as u64;
+    match requested_range(request, length) {
+        Ok((200, _, _)) => Response::new(200, content_type, data),
+        Ok((status, start, end)) => {
+            let body = data[start as usize..end as usize].to_vec();
+            ranged(
+                Response::new(status, content_type, body),
+                start,
+                end,
+                length,
+            )
+        }
+        Err(response) => response,
+    }
+}
+
+/// Answers a request for the mirror rooted at `root`.
+pub fn handle(root: &Path, request: &Request) -> Response {
+    match request.method.as_str() {
+        "GET" | "HEAD" => {}
+        "OPTIONS" => return Response::new(204, "text/plain", Vec::new()),
+        _ => return Response::error(405).header("Allow", "GET, HEAD, OPTIONS"),
+    }
+    let Some(mut path) = resolve_path(root, &request.path) else {
+        return Response::error(403);
+    };
+    // segments mirrored from urls with a query are stored under a name derived from it
+    if let (Some(query), Some(file_name)) = (&request.query, path.file_name()) {
+        let queried =
+            path.with_file_name(layout::query_file_name(&file_name.to_string_lossy(), query));
+        if queried.is_file() {
+            path = queried;
+        }
+    }
+    if path.is_dir() {
+        if !request.path.ends_with('/') {
+            let location = format!("{}/", request.path);
+            return Response::error(301).header("Location", &location);
+        }
+        let index = path.join("index.html");
+        return match index.is_file() {
+            true => file_response(request, &index),
+            false => directory_listing(&request.path, &path),
+        };
+    }
+    file_response(request, &path)
+}
+
+pub fn read_request(reader: &mut impl BufRead) -> Option<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?;
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    };
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    Some(Request {
+        method,
+        path,
+        query,
+        headers,
+    })
+}
+
+/// Status line and headers of a response, including the blank line.
+pub fn response_head(response: &Response) -> String {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason_phrase(response.status)
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!(
+        "Content-Length: {}\r\n",
+        response.content_length()
+    ));
+    head.push_str("Accept-Ranges: bytes\r\n");
+    head.push_str("Access-Control-Allow-Origin: *\r\n");
+    head.push_str("Access-Control-Allow-Methods: GET, HEAD, OPTIONS\r\n");
+    head.push_str("Access-Control-Allow-Headers: Range, Origin, Accept\r\n");
+    head.push_str(
+        "Access-Control-Expose-Headers: Content-Length, Content-Range, Accept-Ranges\r\n",
+    );
+    head.push_str("Connection: close\r\n\r\n");
+    head
+}
+
+pub fn write_response(
+    writer: &mut impl Write,
+    request: &Request,
+    response: &Response,
+) -> std::io::Result<()> {
+    writer.write_all(response_head(response).as_bytes())?;
+    if request.method != "HEAD" {
+        std::io::copy(&mut response.body_reader(), writer)?;
+    }
+    writer.flush()
+}
+
+struct Server {
+    root: PathBuf,
+    live: Option<LiveStream>,
+    impairments: Option<Impairments>,
+}
+
+impl Server {
+    fn target(&self, path: &str) -> Target {
+        let live_target = self.live.as_ref().and_then(|live| live.target(path));
+        match (&self.impairments, live_target) {
+            (_, Some(target)) => target,
+            (Some(impairments), None) => impairments.target(path),
+            (None, None) => Target::default(),
+        }
+    }
+}
+
+fn handle_connection(server: &Server, stream: std::net::TcpStream) {
+    let peer = stream
+        .peer_addr()
+        .map(|address| address.to_string())
+        .unwrap_or_default();
+    let Ok(read_stream) = stream.try_clone() else {
+        return;
+    };
+    let mut reader = BufReader::new(read_stream);
+    let Some(request) = read_request(&mut reader) else {
+        return;
+    };
+    let live_response = server
+        .live
+        .as_ref()
+        .and_then(|live| live.handle(&request, SystemTime::now()));
+    let response = live_response.unwrap_or_else(|| handle(&server.root, &request));
+    let mut writer = std::io::BufWriter::new(stream);
+    let rule = server.impairments.as_ref().and_then(|impairments| {
+        let target = server.target(&request.path);
+        impairments
+            .rule(&request.path, &target)
+            .map(|rule| (impairments, rule))
+    });
+    let result = match rule {
+        Some((impairments, rule)) => impairments.send(&mut writer, &request, response, rule),
+        None => write_response(&mut writer, &request, &response).map(|_| (response.status, None)),
+    };
+    match result {
+        Ok((status, Some(fault))) => println!(
+            "{} {} {} {} {}",
+            peer, request.method, request.path, status, fault
+        ),
+        Ok((status, None)) => println!("{} {} {} {}", peer, request.method, request.path, status),
+        Err(e) => eprintln!("{} : could not send response {}", peer, e),
+    }
+}
+
+/// Serves `root` over HTTP on `address` until the process is stopped. With `live`
+/// options the mirror is also published as a simulated live stream below `/live/`.
+/// `impairments` is a JSON rule file of faults injected into the responses.
+pub fn serve(
+    root: &str,
+    address: &str,
+    live: Option<LiveOptions>,
+    impairments: Option<&str>,
+) -> Result<(), String> {
+    let root_path = PathBuf::from(root);
+    if !root_path.is_dir() {
+        return Err(format!("{} is not a directory", root_path.display()));
+    }
+    let live = match live {
+        Some(options) => Some(LiveStream::new(&root_path, options, SystemTime::now())?),
+        None => None,
+    };
+    let impairments = match impairments {
+        Some(path) => Some(Impairments::load(path, root)?),
+        None => None,
+    };
+    let listener =
+        std::net::TcpListener::bind(address).map_err(|e| format!("{} : {}", address, e))?;
+    println!("serving {} on http://{}/", root_path.display(), address);
+    if live.is_some() {
+        println!(
+            "live stream on http://{}/{}",
+            address,
+            live::LIVE_MANIFEST_PATH.trim_start_matches('/')
+        );
+    }
+    let server = Arc::new(Server {
+        root: root_path,
+        live,
+        impairments,
+    });
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let server = server.clone();
+                std::thread::spawn(move || handle_connection(&server, stream));
+            }
+            Err(e) => eprintln!("Could not accept connection {}", e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle, read_request, write_response, Request};
+
+    fn request(path: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            query: None,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    fn body(response: &super::Response) -> Vec<u8> {
+        let mut body = Vec::new();
+        std::io::Read::read_to_end(&mut response.body_reader(), &mut body).unwrap();
+        body
+    }
+
+    fn header<'a>(response: &'a super::Response, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn handle_requests() {
+        let root = std::env::temp_dir().join(format!("dash-mirror-serve-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("video")).unwrap();
+        std::fs::write(root.join("manifest.mpd"), "<MPD/>").unwrap();
+        std::fs::write(root.join("video/1.m4s"), b"0123456789").unwrap();
+
+        let response = handle(&root, &request("/manifest.mpd", &[]));
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            header(&response, "Content-Type"),
+            Some("application/dash+xml")
+        );
+
+        let response = handle(&root, &request("/video/1.m4s", &[("range", "bytes=2-5")]));
+        assert_eq!(response.status, 206);
+        assert_eq!(body(&response), b"2345");
+        assert_eq!(header(&response, "Content-Range"), Some("bytes 2-5/10"));
+        assert_eq!(header(&response, "Content-Type"), Some("video/iso.segment"));
+
+        let response = handle(&root, &request("/video/1.m4s", &[("Range", "bytes=-3")]));
+        assert_eq!(body(&response), b"789");
+        let response = handle(&root, &request("/video/1.m4s", &[("Range", "bytes=10-")]));
+        assert_eq!(response.status, 416);
+        let mut head = request("/video/1.m4s", &[]);
+        head.method = "HEAD".to_string();
+        let mut out = Vec::new();
+        write_response(&mut out, &head, &handle(&root, &head)).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Content-Length: 10\r\n") && out.ends_with("\r\n\r\n"));
+
+        let response = handle(&root, &request("/video", &[]));
+        assert_eq!(response.status, 301);
+        assert_eq!(header(&response, "Location"), Some("/video/"));
+        let response = handle(&root, &request("/video/", &[]));
+        assert!(String::from_utf8(response.body)
+            .unwrap()
+            .contains("<a href=\"1.m4s\">1.m4s</a>"));
+        std::fs::write(root.join("video/a b#?%&.m4s"), b"").unwrap();
+        let response = handle(&root, &request("/video/", &[]));
+        assert!(String::from_utf8(response.body)
+            .unwrap()
+            .contains("<a href=\"a%20b%23%3F%25%26.m4s\">a b#?%&amp;.m4s</a>"));
+        let response = handle(&root, &request("/video/a%20b%23%3F%25%26.m4s", &[]));
+        assert_eq!(response.status, 200);
+
+        assert_eq!(handle(&root, &request("/../etc/passwd", &[])).status, 403);
+        assert_eq!(handle(&root, &request("/%2e%2e/x", &[])).status, 403);
+        assert_eq!(handle(&root, &request("/missing.m4s", &[])).status, 404);
+
+        let name = crate::layout::query_file_name("2.m4s", "sig=2");
+        std::fs::write(root.join("video").join(name), b"signed").unwrap();
+        let mut queried = request("/video/2.m4s", &[]);
+        queried.query = Some("sig=2".to_string());
+        assert_eq!(body(&handle(&root, &queried)), b"signed");
+        queried.path = "/video/1.m4s".to_string();
+        assert_eq!(body(&handle(&root, &queried)), b"0123456789");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let raw = b"HEAD /a%20b.mp4?x=1 HTTP/1.1\r\nHost: localhost\r\nRange: bytes=0-1\r\n\r\n";
+        let request = read_request(&mut &raw[..]).unwrap();
+        assert_eq!(request.method, "HEAD");
+        assert_eq!(request.path, "/a%20b.mp4");
+        assert_eq!(request.query.as_deref(), Some("x=1"));
+        assert_eq!(request.header("range"), Some("bytes=0-1"));
+
+        let response = super::Response::new(200, "video/mp4", b"abc".to_vec());
+        let mut out = Vec::new();
+        write_response(&mut out, &request, &response).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.contains("Access-Control-Allow-Origin: *\r\n"));
+        assert!(out.contains("Content-Length: 3\r\n"));
+        assert!(out.ends_with("\r\n\r\n"));
+    }
+}
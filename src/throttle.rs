@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::fetch::{FetchRequest, FetchResponse, Fetcher};
+use crate::util::Rng;
+
+/// Parses a rate in bytes per second, with an optional `k`, `m` or `g` suffix for
+/// multiples of 1024 as for curl's `--limit-rate`.
+pub fn parse_rate(rate: &str) -> Result<u64, String> {
+    let rate = rate.trim();
+    let (number, multiplier) = match rate.char_indices().last() {
+        Some((pos, 'k' | 'K')) => (&rate[..pos], 1 << 10),
+        Some((pos, 'm' | 'M')) => (&rate[..pos], 1 << 20),
+        Some((pos, 'g' | 'G')) => (&rate[..pos], 1 << 30),
+        _ => (rate, 1),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid rate {}", rate))?;
+    if number.is_nan() || number < 0.0 {
+        return Err(format!("invalid rate {}", rate));
+    }
+    Ok((number * multiplier as f64) as u64)
+}
+
+fn parse_time_of_day(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+struct Window {
+    start: u32,
+    end: u32,
+    /// Bytes per second, `None` for no limit.
+    rate: Option<u64>,
+}
+
+impl Window {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Parses an offset from UTC as `+HH:MM`, `-HH:MM` or `Z`, in minutes.
+pub fn parse_utc_offset(offset: &str) -> Result<i32, String> {
+    let offset = offset.trim();
+    if offset == "Z" {
+        return Ok(0);
+    }
+    let invalid = || format!("invalid UTC offset {}, expected +HH:MM or -HH:MM", offset);
+    let (sign, time) = match offset.split_at_checked(1) {
+        Some(("+", time)) => (1, time),
+        Some(("-", time)) => (-1, time),
+        _ => return Err(invalid()),
+    };
+    let minutes = parse_time_of_day(time).ok_or_else(invalid)?;
+    Ok(sign * minutes as i32)
+}
+
+/// Download rate by time of day.
+#[derive(Default)]
+pub struct RateSchedule {
+    /// Rate outside of all windows, `None` for no limit.
+    pub default: Option<u64>,
+    /// Offset from UTC of the window times, in minutes.
+    pub utc_offset: i32,
+    windows: Vec<Window>,
+}
+
+impl RateSchedule {
+    pub fn new(default: Option<u64>) -> RateSchedule {
+        RateSchedule {
+            default,
+            utc_offset: 0,
+            windows: Vec::new(),
+        }
+    }
+
+    /// Adds a `HH:MM-HH:MM=rate` window, a rate of `0` or `unlimited` removes the limit.
+    /// Windows may wrap around midnight, the first matching one applies.
+    pub fn add_window(&mut self, window: &str) -> Result<(), String> {
+        let invalid = || format!("invalid schedule {}, expected HH:MM-HH:MM=rate", window);
+        let (times, rate) = window.split_once('=').ok_or_else(invalid)?;
+        let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+        let start = parse_time_of_day(start.trim()).ok_or_else(invalid)?;
+        let end = parse_time_of_day(end.trim()).ok_or_else(invalid)?;
+        let rate = match rate.trim() {
+            "unlimited" => None,
+            rate => Some(parse_rate(rate)?).filter(|rate| *rate > 0),
+        };
+        self.windows.push(Window { start, end, rate });
+        Ok(())
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.default.is_none() && self.windows.iter().all(|window| window.rate.is_none())
+    }
+
+    /// Minutes since midnight at `time`, in the time zone of the window times.
+    pub fn minute_of_day(&self, time: SystemTime) -> u32 {
+        let seconds = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        (seconds / 60 + self.utc_offset as i64).rem_euclid(24 * 60) as u32
+    }
+
+    /// Rate at `minute` after midnight.
+    pub fn rate_at(&self, minute: u32) -> Option<u64> {
+        match self.windows.iter().find(|window| window.contains(minute)) {
+            Some(window) => window.rate,
+            None => self.default,
+        }
+    }
+}
+
+/// Limits the combined rate of all downloads sharing it.
+pub struct RateLimiter {
+    schedule: RateSchedule,
+    /// Time at which the bytes consumed so far have been paid for.
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(schedule: RateSchedule) -> RateLimiter {
+        RateLimiter {
+            schedule,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Bytes to read before calling `consume`, about 50 ms worth at the current rate.
+    pub fn chunk_size(&self) -> usize {
+        match self.current_rate() {
+            Some(rate) => ((rate / 20) as usize).clamp(512, 64 * 1024),
+            None => 64 * 1024,
+        }
+    }
+
+    /// Accounts for `bytes` received and sleeps as long as the rate is exceeded.
+    pub fn consume(&self, bytes: usize) {
+        self.consume_at(bytes, self.schedule.minute_of_day(SystemTime::now()));
+    }
+
+    fn current_rate(&self) -> Option<u64> {
+        self.schedule
+            .rate_at(self.schedule.minute_of_day(SystemTime::now()))
+    }
+
+    fn consume_at(&self, bytes: usize, minute: u32) {
+        let now = Instant::now();
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let Some(rate) = self.schedule.rate_at(minute) else {
+                *next = now;
+                return;
+            };
+            // idle time does not build up credit for a later burst
+            *next = (*next).max(now) + Duration::from_secs_f64(bytes as f64 / rate as f64);
+            *next - now
+        };
+        std::thread::sleep(wait);
+    }
+}
+
+/// Request pacing of `PoliteFetcher`.
+#[derive(Default)]
+pub struct PoliteOptions {
+    /// Maximum requests per second to each host.
+    pub host_rate: Option<f64>,
+    /// Pause between the end of a request and the start of the next one.
+    pub delay: Duration,
+    /// Random time of up to this much added to `delay`.
+    pub jitter: Duration,
+}
+
+/// Fetcher spacing out requests by `PoliteOptions`.
+pub struct PoliteFetcher<'a> {
+    pub fetcher: &'a dyn Fetcher,
+    pub options: PoliteOptions,
+    /// End of the last request.
+    last_request: Mutex<Option<Instant>>,
+    host_requests: Mutex<HashMap<String, Instant>>,
+    rng: Mutex<Rng>,
+}
+
+impl PoliteFetcher<'_> {
+    pub fn new(fetcher: &dyn Fetcher, options: PoliteOptions) -> PoliteFetcher<'_> {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        PoliteFetcher {
+            fetcher,
+            options,
+            last_request: Mutex::new(None),
+            host_requests: Mutex::new(HashMap::new()),
+            rng: Mutex::new(Rng(seed)),
+        }
+    }
+
+    /// Time to wait before a request to `host`, reserving the slot.
+    fn wait(&self, host: &str) -> Duration {
+        let now = Instant::now();
+        let mut start = now;
+        if let Some(last) = *self.last_request.lock().unwrap() {
+            let jitter = self
+                .options
+                .jitter
+                .mul_f64(self.rng.lock().unwrap().next_f64());
+            start = start.max(last + self.options.delay + jitter);
+        }
+        let mut host_requests = self.host_requests.lock().unwrap();
+        if let (Some(rate), Some(last)) = (self.options.host_rate, host_requests.get(host)) {
+            start = start.max(*last + Duration::from_secs_f64(1.0 / rate));
+        }
+        host_requests.insert(host.to_string(), start);
+        start - now
+    }
+}
+
+impl PoliteFetcher<'_> {
+    /// Runs `fetch` once it is the turn of `request`.
+    fn paced(
+        &self,
+        request: &FetchRequest,
+        fetch: impl FnOnce() -> Result<FetchResponse, String>,
+    ) -> Result<FetchResponse, String> {
+        let host = reqwest::Url::parse(&request.url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        std::thread::sleep(self.wait(&host));
+        let result = fetch();
+        *self.last_request.lock().unwrap() = Some(Instant::now());
+        result
+    }
+}
+
+impl Fetcher for PoliteFetcher<'_> {
+    fn fetch(&self, request: &FetchRequest) -> Result<FetchResponse, String> {
+        self.paced(request, || self.fetcher.fetch(request))
+    }
+
+    fn fetch_to(
+        &self,
+        request: &FetchRequest,
+        sink: &mut dyn std::io::Write,
+    ) -> Result<FetchResponse, String> {
+        self.paced(request, || self.fetcher.fetch_to(request, sink))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_rate, parse_utc_offset, PoliteFetcher, PoliteOptions, RateLimiter, RateSchedule,
+    };
+    use crate::fetch::{FetchRequest, Fetcher};
+    use crate::mock::{MockOrigin, Route};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    #[test]
+    fn rate_schedule() {
+        assert_eq!(parse_rate("1500").unwrap(), 1500);
+        assert_eq!(parse_rate("200k").unwrap(), 200 * 1024);
+        assert_eq!(parse_rate("1.5M").unwrap(), 3 * 512 * 1024);
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("-1k").is_err());
+
+        let mut schedule = RateSchedule::new(Some(100 * 1024));
+        schedule.add_window("22:00-06:30=0").unwrap();
+        schedule.add_window("12:00-13:00=1m").unwrap();
+        assert!(schedule.add_window("25:00-06:00=0").is_err());
+        assert!(schedule.add_window("22:00=0").is_err());
+        assert_eq!(schedule.rate_at(23 * 60), None);
+        assert_eq!(schedule.rate_at(6 * 60 + 29), None);
+        assert_eq!(schedule.rate_at(6 * 60 + 30), Some(100 * 1024));
+        assert_eq!(schedule.rate_at(12 * 60 + 59), Some(1024 * 1024));
+        assert!(!schedule.is_unlimited());
+        assert!(RateSchedule::default().is_unlimited());
+
+        assert_eq!(parse_utc_offset("+05:30"), Ok(330));
+        assert_eq!(parse_utc_offset("-08:00"), Ok(-480));
+        assert_eq!(parse_utc_offset("Z"), Ok(0));
+        assert!(parse_utc_offset("0530").is_err());
+        let time = UNIX_EPOCH + Duration::from_secs(86_400 * 3 + 23 * 3600 + 50 * 60);
+        assert_eq!(schedule.minute_of_day(time), 23 * 60 + 50);
+        schedule.utc_offset = 30;
+        assert_eq!(schedule.minute_of_day(time), 20);
+        schedule.utc_offset = -24 * 60 + 1;
+        assert_eq!(schedule.minute_of_day(time), 23 * 60 + 51);
+    }
+
+    #[test]
+    fn rate_limiter() {
+        let limiter = RateLimiter::new(RateSchedule::new(Some(100_000)));
+        let started = Instant::now();
+        for _ in 0..4 {
+            limiter.consume_at(5_000, 0);
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+
+        let mut schedule = RateSchedule::new(Some(1));
+        schedule.add_window("00:00-01:00=unlimited").unwrap();
+        let limiter = RateLimiter::new(schedule);
+        let started = Instant::now();
+        limiter.consume_at(1_000_000, 30);
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn polite_fetcher() {
+        let origin = MockOrigin::new();
+        origin.add("http://a.test/1", Route::Body(Vec::new()));
+        origin.add("http://b.test/1", Route::Body(Vec::new()));
+        let fetcher = PoliteFetcher::new(
+            &origin,
+            PoliteOptions {
+                host_rate: Some(10.0),
+                ..Default::default()
+            },
+        );
+        let started = Instant::now();
+        fetcher
+            .fetch(&FetchRequest::new("http://a.test/1"))
+            .unwrap();
+        fetcher
+            .fetch(&FetchRequest::new("http://b.test/1"))
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(80));
+        fetcher
+            .fetch(&FetchRequest::new("http://a.test/1"))
+            .unwrap();
+        fetcher
+            .fetch(&FetchRequest::new("http://a.test/1"))
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        let fetcher = PoliteFetcher::new(
+            &origin,
+            PoliteOptions {
+                delay: Duration::from_millis(30),
+                jitter: Duration::from_millis(20),
+                ..Default::default()
+            },
+        );
+        let started = Instant::now();
+        for _ in 0..3 {
+            fetcher
+                .fetch(&FetchRequest::new("http://b.test/1"))
+                .unwrap();
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(60), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(300), "{:?}", elapsed);
+    }
+}
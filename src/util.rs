@@ -0,0 +1,164 @@
+//! Small helpers shared by modules which do not otherwise depend on each other.
+
+use std::time::SystemTime;
+
+/// Formats a time as `YYYY-MM-DDThh:mm:ss.sssZ`.
+pub fn format_time(time: SystemTime) -> String {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let days = (seconds / 86400) as i64;
+    let seconds_of_day = seconds % 86400;
+    // civil_from_days, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Days from 1970-01-01 to a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // days_from_civil, http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Parses an HTTP date such as `Sun, 06 Nov 1994 08:49:37 GMT`. As for cookie expiry
+/// dates the fields are found by their form, so `Sun, 06-Nov-94 08:49:37 GMT` and
+/// `Sun Nov  6 08:49:37 1994` are accepted too.
+pub fn parse_http_date(text: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let (mut time, mut day, mut month, mut year) = (None, None, None, None);
+    let is_number = |token: &str, digits: std::ops::RangeInclusive<usize>| {
+        digits.contains(&token.len()) && token.chars().all(|c| c.is_ascii_digit())
+    };
+    for token in text
+        .split([' ', ',', '-'])
+        .filter(|token| !token.is_empty())
+    {
+        let fields: Vec<&str> = token.split(':').collect();
+        if time.is_none() && fields.len() == 3 {
+            if fields.iter().all(|field| is_number(field, 1..=2)) {
+                let fields: Vec<i64> = fields.iter().filter_map(|f| f.parse().ok()).collect();
+                time = Some((fields[0], fields[1], fields[2]));
+            }
+        } else if day.is_none() && is_number(token, 1..=2) {
+            day = token.parse::<i64>().ok();
+        } else if month.is_none() && token.len() >= 3 {
+            let name = token[..3].to_ascii_lowercase();
+            month = MONTHS
+                .iter()
+                .position(|month| *month == name)
+                .map(|idx| idx as i64 + 1);
+        } else if year.is_none() && is_number(token, 2..=4) {
+            year = token.parse::<i64>().ok();
+        }
+    }
+    let (hours, minutes, seconds) = time?;
+    let (day, month) = (day?, month?);
+    let year = match year? {
+        year @ 0..=69 => year + 2000,
+        year @ 70..=99 => year + 1900,
+        year => year,
+    };
+    if !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 59 {
+        return None;
+    }
+    let seconds = days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds;
+    let since_epoch = std::time::Duration::from_secs(seconds.unsigned_abs());
+    match seconds >= 0 {
+        true => SystemTime::UNIX_EPOCH.checked_add(since_epoch),
+        false => SystemTime::UNIX_EPOCH.checked_sub(since_epoch),
+    }
+}
+
+/// Reason phrase of an HTTP status code, empty for codes without one here.
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        416 => "Range Not Satisfiable",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// splitmix64, deterministic for a given seed so failures can be replayed.
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_time, parse_http_date};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn format_time_test() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1_709_251_199_250);
+        assert_eq!(format_time(time), "2024-02-29T23:59:59.250Z");
+    }
+
+    #[test]
+    fn http_dates() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(784111777);
+        for text in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            assert_eq!(parse_http_date(text), Some(time), "{}", text);
+        }
+        let leap_day = parse_http_date("Thu, 29 Feb 2024 23:59:59 GMT").unwrap();
+        assert_eq!(format_time(leap_day), "2024-02-29T23:59:59.000Z");
+        assert_eq!(
+            parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"),
+            Some(SystemTime::UNIX_EPOCH)
+        );
+        assert!(parse_http_date("Wed, 31 Dec 1969 23:59:59 GMT").unwrap() < SystemTime::UNIX_EPOCH);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:00:00 GMT"), None);
+    }
+}
@@ -0,0 +1,457 @@
+use crate::checksum::{self, CHECKSUM_FILE_NAME};
+use crate::concat::CONCAT_DIRECTORY_NAME;
+use crate::isobmff::{self, BoxHeader, TrackInit};
+use crate::journal::{self, JOURNAL_FILE_NAME};
+use crate::layout;
+use crate::mpd::{RepresentationUrls, UrlInfo};
+
+/// A problem found in one mirrored file.
+pub struct Issue {
+    pub url: String,
+    pub message: String,
+}
+
+/// Walks the top level boxes of a whole file, reporting a box which runs past the
+/// end of the file as truncated.
+fn top_level_boxes(data: &[u8]) -> Result<Vec<BoxHeader>, String> {
+    let mut boxes = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        match isobmff::read_box_header(data, pos) {
+            Some(header) => {
+                pos = header.end();
+                boxes.push(header);
+            }
+            None => {
+                let declared = match data.get(pos + 4..pos + 8) {
+                    Some(box_type) => format!(
+                        "box {} of size {}",
+                        String::from_utf8_lossy(box_type),
+                        isobmff::read_u32(data, pos).unwrap_or_default()
+                    ),
+                    None => "box header".to_string(),
+                };
+                return Err(format!(
+                    "truncated at offset {}, {} but {} bytes left",
+                    pos,
+                    declared,
+                    data.len() - pos
+                ));
+            }
+        }
+    }
+    Ok(boxes)
+}
+
+fn box_types(boxes: &[BoxHeader]) -> Vec<[u8; 4]> {
+    boxes.iter().map(|header| header.box_type).collect()
+}
+
+/// Checks the structure of an init segment and returns its tracks.
+pub fn verify_init(data: &[u8]) -> Result<Vec<TrackInit>, String> {
+    let types = box_types(&top_level_boxes(data)?);
+    if types.first() != Some(b"ftyp") {
+        return Err("init segment does not start with ftyp".to_string());
+    }
+    if !types.contains(b"moov") {
+        return Err("moov box not found".to_string());
+    }
+    let moov = isobmff::find_path(data, &[b"moov"]).ok_or("moov box not found")?;
+    if isobmff::find_child(data, &moov, b"mvex").is_none() {
+        return Err("moov has no mvex, segments can not be fragments".to_string());
+    }
+    let tracks = isobmff::parse_init_tracks(data);
+    if tracks.is_empty() {
+        return Err("moov has no trak".to_string());
+    }
+    Ok(tracks)
+}
+
+/// Checks the structure of a media segment: `moof` and `mdat` pairs whose sample runs
+/// point into the following `mdat`.
+pub fn verify_media_segment(data: &[u8], tracks: &[TrackInit]) -> Result<(), String> {
+    let boxes = top_level_boxes(data)?;
+    let mut moof_count = 0;
+    for (idx, header) in boxes.iter().enumerate() {
+        if &header.box_type != b"moof" {
+            continue;
+        }
+        moof_count += 1;
+        let mdat = boxes[idx + 1..]
+            .iter()
+            .find(|header| matches!(&header.box_type, b"mdat" | b"moof"))
+            .filter(|header| &header.box_type == b"mdat")
+            .ok_or(format!(
+                "moof at offset {} is not followed by mdat",
+                header.offset
+            ))?;
+        for traf in isobmff::children(data, header) {
+            if &traf.box_type != b"traf" {
+                continue;
+            }
+            let fragment = isobmff::parse_traf(data, header, &traf, tracks)
+                .ok_or(format!("traf at offset {} has no tfhd", traf.offset))?;
+            if fragment.base_media_decode_time.is_none() {
+                return Err(format!("track {} fragment has no tfdt", fragment.track_id));
+            }
+            for run in &fragment.runs {
+                if run.data_offset < mdat.payload_offset()
+                    || run.data_offset + run.data_size() > mdat.end()
+                {
+                    return Err(format!(
+                        "track {} samples at {}..{} outside of mdat {}..{}",
+                        fragment.track_id,
+                        run.data_offset,
+                        run.data_offset + run.data_size(),
+                        mdat.payload_offset(),
+                        mdat.end()
+                    ));
+                }
+            }
+        }
+    }
+    match moof_count {
+        0 => Err("moof box not found".to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Converts manifest ticks to track ticks.
+fn to_track_time(value: u64, manifest_timescale: u64, track_timescale: u32) -> f64 {
+    value as f64 * track_timescale as f64 / manifest_timescale.max(1) as f64
+}
+
+/// Compares the decode time and duration of the representation's track in a segment
+/// with the manifest.
+fn verify_timing(
+    data: &[u8],
+    track: &TrackInit,
+    tracks: &[TrackInit],
+    representation: &RepresentationUrls,
+    segment_idx: usize,
+) -> Result<(), String> {
+    let segment = &representation.segments[segment_idx];
+    let fragments: Vec<isobmff::TrackFragment> = isobmff::parse_track_fragments(data, tracks)
+        .into_iter()
+        .filter(|fragment| fragment.track_id == track.track_id)
+        .collect();
+    let first = fragments
+        .first()
+        .ok_or(format!("no fragment of track {}", track.track_id))?;
+    let decode_time = first.base_media_decode_time.unwrap_or_default();
+    let duration: u64 = fragments.iter().map(|fragment| fragment.duration).sum();
+    // Durations computed from SegmentTemplate@duration are nominal, the media can only
+    // be cut at sample boundaries.
+    let tolerance = match representation.segment_timeline {
+        true if track.timescale as u64 == representation.timescale => 0.0,
+        true => 1.0,
+        false => fragments
+            .iter()
+            .flat_map(|fragment| fragment.runs.iter())
+            .flat_map(|run| run.samples.iter())
+            .map(|sample| sample.duration as f64)
+            .fold(1.0, f64::max),
+    };
+    let expected_time = to_track_time(segment.time, representation.timescale, track.timescale);
+    if (decode_time as f64 - expected_time).abs() > tolerance {
+        return Err(format!(
+            "tfdt {} does not match expected time {} (segment number {})",
+            decode_time, expected_time, segment.number
+        ));
+    }
+    if let Some(expected_duration) = segment.duration {
+        let expected = to_track_time(expected_duration, representation.timescale, track.timescale);
+        let last = segment_idx + 1 == representation.segments.len();
+        let shorter_last = last && !representation.segment_timeline && (duration as f64) < expected;
+        if (duration as f64 - expected).abs() > tolerance && !shorter_last {
+            return Err(format!(
+                "trun sample durations add up to {}, expected segment duration {}",
+                duration, expected
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Verifies the init segment and every media segment of a representation.
+pub fn verify_representation(
+    output_directory: &str,
+    url_info: &UrlInfo,
+    representation: &RepresentationUrls,
+) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let base_url = &url_info.base_url;
+    let mut tracks = Vec::new();
+    if let Some(initialization) = &representation.initialization {
+        let result = layout::read_segment(output_directory, base_url, initialization)
+            .and_then(|data| verify_init(&data));
+        match result {
+            Ok(init_tracks) => tracks = init_tracks,
+            Err(message) => issues.push(Issue {
+                url: initialization.clone(),
+                message,
+            }),
+        }
+    }
+    let track = tracks.first().cloned().map(|mut track| {
+        if track.timescale == 0 {
+            track.timescale = representation.timescale as u32;
+        }
+        track
+    });
+    for (segment_idx, segment) in representation.segments.iter().enumerate() {
+        let result =
+            layout::read_segment(output_directory, base_url, &segment.url).and_then(|data| {
+                verify_media_segment(&data, &tracks)?;
+                match &track {
+                    Some(track) => {
+                        verify_timing(&data, track, &tracks, representation, segment_idx)
+                    }
+                    None => Ok(()),
+                }
+            });
+        if let Err(message) = result {
+            issues.push(Issue {
+                url: segment.url.clone(),
+                message,
+            });
+        }
+    }
+    issues
+}
+
+/// Files the tool writes next to a mirror, which are not part of the checksum file.
+fn is_generated(path: &str) -> bool {
+    path == CHECKSUM_FILE_NAME
+        || path == JOURNAL_FILE_NAME
+        || path.starts_with(&format!("{}/", CONCAT_DIRECTORY_NAME))
+        || (!path.contains('/') && path.ends_with(".m3u8"))
+}
+
+/// Re-hashes the files listed in the checksum file, reporting missing and corrupted
+/// ones, and files which are not listed. The `url` of an issue is the relative path.
+pub fn verify_files(output_directory: &str) -> Result<Vec<Issue>, String> {
+    let checksums = checksum::read_checksums(output_directory)?;
+    let mut issues = Vec::new();
+    for checksum in &checksums {
+        let path = std::path::Path::new(output_directory).join(&checksum.path);
+        // the size comes from the metadata, so a truncated file is not hashed at all
+        let opened = std::fs::File::open(&path).and_then(|file| Ok((file.metadata()?.len(), file)));
+        let message = match opened {
+            Ok((size, _)) if size != checksum.size => Some(format!(
+                "corrupted, size {} expected {}",
+                size, checksum.size
+            )),
+            Ok((_, mut file)) => match journal::hash_reader(&mut file) {
+                Ok((_, sha256)) if sha256 != checksum.sha256 => {
+                    Some("corrupted, SHA-256 mismatch".to_string())
+                }
+                Ok(_) => None,
+                Err(e) => Some(e.to_string()),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some("missing".to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(message) = message {
+            issues.push(Issue {
+                url: checksum.path.clone(),
+                message,
+            });
+        }
+    }
+    let listed: std::collections::HashSet<&str> = checksums
+        .iter()
+        .map(|checksum| checksum.path.as_str())
+        .collect();
+    for path in checksum::list_files(output_directory)? {
+        if !listed.contains(path.as_str()) && !is_generated(&path) {
+            issues.push(Issue {
+                url: path,
+                message: "extra, not in checksum file".to_string(),
+            });
+        }
+    }
+    Ok(issues)
+}
+
+/// Verifies every representation of the mirror in `output_directory`, printing the
+/// issues per representation. Returns the number of issues.
+pub fn verify_mirror(output_directory: &str) -> usize {
+    let mut issue_count = 0;
+    if checksum::checksum_path(output_directory).exists() {
+        match verify_files(output_directory) {
+            Ok(issues) => {
+                for issue in &issues {
+                    eprintln!("{} : {}", issue.url, issue.message);
+                }
+                println!("{} : {} file issues", CHECKSUM_FILE_NAME, issues.len());
+                issue_count += issues.len();
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                issue_count += 1;
+            }
+        }
+    } else {
+        println!(
+            "{} not found, file checksums not verified",
+            CHECKSUM_FILE_NAME
+        );
+    }
+    let Some((_, url_info)) = layout::read_mirror(output_directory) else {
+        return issue_count + 1;
+    };
+    for representation in &url_info.representations {
+        let issues = verify_representation(output_directory, &url_info, representation);
+        for issue in &issues {
+            let path =
+                layout::relative_path(&url_info.base_url, &issue.url).unwrap_or(issue.url.clone());
+            eprintln!("rep {} : {} : {}", representation.id, path, issue.message);
+        }
+        println!(
+            "rep {} : {} segments verified, {} issues",
+            representation.id,
+            representation.segments.len(),
+            issues.len()
+        );
+        issue_count += issues.len();
+    }
+    issue_count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_files, verify_representation};
+    use crate::checksum::{write_checksums, Checksum};
+    use crate::isobmff::tests::{init_segment, media_segment};
+    use crate::layout;
+
+    #[test]
+    fn verify_representation_test() {
+        let directory =
+            std::env::temp_dir().join(format!("dash-mirror-verify-{}", std::process::id()));
+        let output_directory = directory.to_str().unwrap();
+        std::fs::create_dir_all(directory.join("audio")).unwrap();
+        std::fs::write(
+            directory.join("manifest.mpd"),
+            r#"<MPD type="static" mediaPresentationDuration="PT8S">
+            <Period duration="PT8S">
+            <AdaptationSet mimeType="audio/mp4">
+            <SegmentTemplate timescale="1000" media="audio/$Time$.m4s" initialization="audio/init.mp4">
+            <SegmentTimeline><S t="0" d="2000" r="3"/></SegmentTimeline>
+            </SegmentTemplate>
+            <Representation id="a1" bandwidth="128000"/>
+            </AdaptationSet>
+            </Period>
+            </MPD>"#,
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("audio/init.mp4"),
+            init_segment(1, 48000, b"soun"),
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("audio/0.m4s"),
+            media_segment(1, 0, 48000, &[b"a", b"b"]),
+        )
+        .unwrap();
+        // segment 4000 was stored under the name of segment 2000
+        std::fs::write(
+            directory.join("audio/2000.m4s"),
+            media_segment(1, 192000, 48000, &[b"c", b"d"]),
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("audio/4000.m4s"),
+            media_segment(1, 192000, 48000, &[b"e"]),
+        )
+        .unwrap();
+        let mut truncated = media_segment(1, 288000, 48000, &[b"g", b"h"]);
+        truncated.pop();
+        std::fs::write(directory.join("audio/6000.m4s"), truncated).unwrap();
+
+        let (_, url_info) = layout::read_mirror(output_directory).unwrap();
+        let issues =
+            verify_representation(output_directory, &url_info, &url_info.representations[0]);
+        let issues: Vec<(String, String)> = issues
+            .iter()
+            .map(|issue| {
+                let path = layout::relative_path(&url_info.base_url, &issue.url).unwrap();
+                (path, issue.message.clone())
+            })
+            .collect();
+        assert_eq!(issues.len(), 3);
+        assert_eq!(issues[0].0, "audio/2000.m4s");
+        assert!(issues[0]
+            .1
+            .starts_with("tfdt 192000 does not match expected time 96000"));
+        assert_eq!(issues[1].0, "audio/4000.m4s");
+        assert!(issues[1]
+            .1
+            .starts_with("trun sample durations add up to 48000"));
+        assert_eq!(issues[2].0, "audio/6000.m4s");
+        assert!(issues[2].1.starts_with("truncated at offset"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn verify_files_test() {
+        let directory =
+            std::env::temp_dir().join(format!("dash-mirror-verify-files-{}", std::process::id()));
+        let output_directory = directory.to_str().unwrap();
+        std::fs::create_dir_all(directory.join("video")).unwrap();
+        std::fs::create_dir_all(directory.join("concat")).unwrap();
+        let files: [(&str, &[u8]); 4] = [
+            ("manifest.mpd", b"<MPD/>"),
+            ("video/1.m4s", b"one"),
+            ("video/2.m4s", b"two"),
+            ("video/3.m4s", b"three"),
+        ];
+        let checksums: Vec<Checksum> = files
+            .iter()
+            .map(|(path, data)| Checksum::new(path, data))
+            .collect();
+        write_checksums(
+            &crate::storage::FileStorage::new(output_directory),
+            &checksums,
+        )
+        .unwrap();
+        for (path, data) in &files {
+            std::fs::write(directory.join(path), data).unwrap();
+        }
+        for path in ["journal.jsonl", "master.m3u8", "concat/v.mp4"] {
+            std::fs::write(directory.join(path), b"").unwrap();
+        }
+        assert!(verify_files(output_directory).unwrap().is_empty());
+
+        std::fs::write(directory.join("video/1.m4s"), b"One").unwrap();
+        std::fs::write(directory.join("video/2.m4s"), b"two!").unwrap();
+        std::fs::remove_file(directory.join("video/3.m4s")).unwrap();
+        std::fs::write(directory.join("video/4.m4s.part"), b"").unwrap();
+        let issues: Vec<(String, String)> = verify_files(output_directory)
+            .unwrap()
+            .into_iter()
+            .map(|issue| (issue.url, issue.message))
+            .collect();
+        assert_eq!(
+            issues,
+            vec![
+                (
+                    "video/1.m4s".to_string(),
+                    "corrupted, SHA-256 mismatch".to_string()
+                ),
+                (
+                    "video/2.m4s".to_string(),
+                    "corrupted, size 4 expected 3".to_string()
+                ),
+                ("video/3.m4s".to_string(), "missing".to_string()),
+                (
+                    "video/4.m4s.part".to_string(),
+                    "extra, not in checksum file".to_string()
+                ),
+            ]
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
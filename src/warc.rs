@@ -0,0 +1,630 @@
+use std::io::{Read, Seek, Write};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+use crate::fetch::{FetchRequest, FetchResponse, Fetcher};
+use crate::storage::Spool;
+use crate::util::{self, Rng};
+
+/// `sha256:` followed by the padded base32 digest, as in `WARC-Block-Digest`.
+pub fn digest(data: &[u8]) -> String {
+    format_digest(Sha256::new_with_prefix(data))
+}
+
+fn format_digest(hasher: Sha256) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let hash = hasher.finalize();
+    let mut text = String::from("sha256:");
+    for chunk in hash.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer
+            .iter()
+            .fold(0u64, |bits, byte| (bits << 8) | *byte as u64);
+        let characters = (chunk.len() * 8).div_ceil(5);
+        for i in 0..8 {
+            match i < characters {
+                true => text.push(ALPHABET[((bits >> (35 - i * 5)) & 31) as usize] as char),
+                false => text.push('='),
+            }
+        }
+    }
+    text
+}
+
+/// Sort-friendly URI Reordering Transform key of `url` as used by CDXJ indexes, such as
+/// `com,example)/video/1.m4s?a=1&b=2`.
+pub fn surt(url: &str) -> String {
+    let Ok(parsed) = reqwest::Url::parse(url) else {
+        return url.to_ascii_lowercase();
+    };
+    let host = parsed.host_str().unwrap_or_default().to_ascii_lowercase();
+    let host = host.strip_prefix("www.").unwrap_or(&host);
+    let mut key = host.split('.').rev().collect::<Vec<_>>().join(",");
+    if let Some(port) = parsed.port() {
+        key.push_str(&format!(":{}", port));
+    }
+    key.push(')');
+    key.push_str(&parsed.path().to_ascii_lowercase());
+    if let Some(query) = parsed.query() {
+        let mut parameters: Vec<&str> = query.split('&').collect();
+        parameters.sort();
+        key.push('?');
+        key.push_str(&parameters.join("&").to_ascii_lowercase());
+    }
+    key
+}
+
+/// Body of a response being recorded, read once for its digests and once more into the
+/// record.
+pub trait Body: Read + Seek {}
+
+impl<T: Read + Seek> Body for T {}
+
+/// Counts the bytes written through it.
+struct CountingWriter<'a> {
+    inner: &'a mut dyn Write,
+    count: u64,
+}
+
+impl Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes a record with a block of `length` bytes read from `block`.
+fn write_record_to(
+    out: &mut dyn Write,
+    head: &[u8],
+    block: &mut dyn Read,
+    length: u64,
+) -> std::io::Result<()> {
+    out.write_all(head)?;
+    let copied = std::io::copy(&mut block.take(length), out)?;
+    if copied != length {
+        return Err(std::io::Error::other(format!(
+            "block is shorter than {} bytes",
+            length
+        )));
+    }
+    out.write_all(b"\r\n\r\n")
+}
+
+/// Path of the CDXJ index of a WARC file, `out.warc.gz` is indexed in `out.cdxj`.
+pub fn index_path(warc_path: &str) -> String {
+    let stem = warc_path.strip_suffix(".gz").unwrap_or(warc_path);
+    let stem = stem.strip_suffix(".warc").unwrap_or(stem);
+    format!("{}.cdxj", stem)
+}
+
+/// Writes WARC 1.1 records, each record a separate gzip member if the file name ends in
+/// `.gz`, and collects a CDXJ line for every response.
+pub struct WarcWriter {
+    pub path: String,
+    file: std::io::BufWriter<std::fs::File>,
+    gzip: bool,
+    offset: u64,
+    index: Vec<String>,
+    rng: Rng,
+    pub records: usize,
+}
+
+impl WarcWriter {
+    /// Creates the file and writes the `warcinfo` record.
+    pub fn create(path: &str) -> Result<WarcWriter, String> {
+        let file = std::fs::File::create(path).map_err(|e| format!("{} : {}", path, e))?;
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let mut writer = WarcWriter {
+            path: path.to_string(),
+            file: std::io::BufWriter::new(file),
+            gzip: path.ends_with(".gz"),
+            offset: 0,
+            index: Vec::new(),
+            rng: Rng(seed ^ std::process::id() as u64),
+            records: 0,
+        };
+        let file_name = std::path::Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let info = format!(
+            "software: dash-mirror/{}\r\nformat: WARC File Format 1.1\r\nconformsTo: http://iipc.github.io/warc-specifications/specifications/warc-format/warc-1.1/\r\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        let id = writer.record_id();
+        writer.write_record(
+            &[
+                ("WARC-Type", "warcinfo".to_string()),
+                ("WARC-Record-ID", id),
+                ("WARC-Date", util::format_time(SystemTime::now())),
+                ("WARC-Filename", file_name),
+                ("Content-Type", "application/warc-fields".to_string()),
+                ("WARC-Block-Digest", digest(info.as_bytes())),
+            ],
+            &mut info.as_bytes(),
+            info.len() as u64,
+        )?;
+        Ok(writer)
+    }
+
+    /// Random version 4 UUID in angle brackets.
+    fn record_id(&mut self) -> String {
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&self.rng.next_u64().to_be_bytes());
+        bytes[8..].copy_from_slice(&self.rng.next_u64().to_be_bytes());
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!(
+            "<urn:uuid:{}-{}-{}-{}-{}>",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+
+    /// Writes one record with a block of `length` bytes read from `block`, returning its
+    /// offset and length in the file.
+    fn write_record(
+        &mut self,
+        headers: &[(&str, String)],
+        block: &mut dyn Read,
+        length: u64,
+    ) -> Result<(u64, u64), String> {
+        let mut head = b"WARC/1.1\r\n".to_vec();
+        for (name, value) in headers {
+            head.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        head.extend_from_slice(format!("Content-Length: {}\r\n\r\n", length).as_bytes());
+        let mut file = CountingWriter {
+            inner: &mut self.file,
+            count: 0,
+        };
+        let written = match self.gzip {
+            true => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(&mut file, flate2::Compression::default());
+                write_record_to(&mut encoder, &head, block, length)
+                    .and_then(|_| encoder.finish().map(|_| ()))
+            }
+            false => write_record_to(&mut file, &head, block, length),
+        };
+        written.map_err(|e| format!("{} : {}", self.path, e))?;
+        let offset = self.offset;
+        self.offset += file.count;
+        self.records += 1;
+        Ok((offset, file.count))
+    }
+
+    /// Writes a `request` record and, if there was one, the `response` record of an
+    /// exchange which started at `time`, with the body of `response`.
+    pub fn write_exchange(
+        &mut self,
+        request: &FetchRequest,
+        response: Option<&FetchResponse>,
+        time: SystemTime,
+    ) -> Result<(), String> {
+        let body = response.map(|response| std::io::Cursor::new(&response.body[..]));
+        match body {
+            Some(mut body) => self.write_streamed_exchange(request, response, &mut body, time),
+            None => self.write_streamed_exchange(request, None, &mut std::io::empty(), time),
+        }
+    }
+
+    /// Like `write_exchange`, with the response body read from `body` instead.
+    pub fn write_streamed_exchange(
+        &mut self,
+        request: &FetchRequest,
+        response: Option<&FetchResponse>,
+        body: &mut dyn Body,
+        time: SystemTime,
+    ) -> Result<(), String> {
+        let parsed =
+            reqwest::Url::parse(&request.url).map_err(|e| format!("{} : {}", request.url, e))?;
+        let mut target = parsed.path().to_string();
+        if let Some(query) = parsed.query() {
+            target.push('?');
+            target.push_str(query);
+        }
+        let mut host = parsed.host_str().unwrap_or_default().to_string();
+        if let Some(port) = parsed.port() {
+            host.push_str(&format!(":{}", port));
+        }
+        let mut request_block = format!("GET {} HTTP/1.1\r\nHost: {}\r\n", target, host);
+        for (name, value) in &request.headers {
+            request_block.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request_block.push_str("\r\n");
+
+        let date = util::format_time(time);
+        let request_id = self.record_id();
+        let response_id = self.record_id();
+        let mut headers = vec![
+            ("WARC-Type", "request".to_string()),
+            ("WARC-Record-ID", request_id),
+            ("WARC-Date", date.clone()),
+            ("WARC-Target-URI", request.url.clone()),
+            (
+                "Content-Type",
+                "application/http;msgtype=request".to_string(),
+            ),
+            ("WARC-Block-Digest", digest(request_block.as_bytes())),
+        ];
+        if response.is_some() {
+            headers.push(("WARC-Concurrent-To", response_id.clone()));
+        }
+        self.write_record(
+            &headers,
+            &mut request_block.as_bytes(),
+            request_block.len() as u64,
+        )?;
+        let Some(response) = response else {
+            return Ok(());
+        };
+
+        let mut http_head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            response.status,
+            util::reason_phrase(response.status)
+        )
+        .into_bytes();
+        for (name, value) in &response.headers {
+            http_head.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        http_head.extend_from_slice(b"\r\n");
+        // the digests go into the record header, so the body is read twice
+        let error = |e: std::io::Error| format!("{} : {}", self.path, e);
+        let mut block_hasher = Sha256::new_with_prefix(&http_head);
+        let mut payload_hasher = Sha256::new();
+        let mut body_length = 0;
+        let mut buffer = vec![0; 64 * 1024];
+        body.rewind().map_err(error)?;
+        loop {
+            let length = body.read(&mut buffer).map_err(error)?;
+            if length == 0 {
+                break;
+            }
+            block_hasher.update(&buffer[..length]);
+            payload_hasher.update(&buffer[..length]);
+            body_length += length as u64;
+        }
+        body.rewind().map_err(error)?;
+        let payload_digest = format_digest(payload_hasher);
+        let (offset, length) = self.write_record(
+            &[
+                ("WARC-Type", "response".to_string()),
+                ("WARC-Record-ID", response_id),
+                ("WARC-Date", date.clone()),
+                ("WARC-Target-URI", request.url.clone()),
+                (
+                    "Content-Type",
+                    "application/http;msgtype=response".to_string(),
+                ),
+                ("WARC-Block-Digest", format_digest(block_hasher)),
+                ("WARC-Payload-Digest", payload_digest.clone()),
+            ],
+            &mut (&http_head[..]).chain(body),
+            http_head.len() as u64 + body_length,
+        )?;
+
+        let mime = response
+            .header("Content-Type")
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|mime| mime.trim().to_string())
+            .unwrap_or("unk".to_string());
+        let file_name = std::path::Path::new(&self.path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let fields = serde_json::json!({
+            "url": request.url,
+            "mime": mime,
+            "status": response.status.to_string(),
+            "digest": payload_digest,
+            "length": length.to_string(),
+            "offset": offset.to_string(),
+            "filename": file_name,
+        });
+        // CDXJ timestamps are YYYYMMDDhhmmss
+        let timestamp: String = date.chars().filter(char::is_ascii_digit).take(14).collect();
+        self.index
+            .push(format!("{} {} {}", surt(&request.url), timestamp, fields));
+        Ok(())
+    }
+
+    /// Flushes the WARC file and writes the sorted CDXJ index next to it.
+    pub fn finish(&mut self) -> Result<String, String> {
+        self.file
+            .flush()
+            .map_err(|e| format!("{} : {}", self.path, e))?;
+        let mut index = self.index.clone();
+        index.sort();
+        let text: String = index.iter().map(|line| format!("{}\n", line)).collect();
+        let path = index_path(&self.path);
+        std::fs::write(&path, text).map_err(|e| format!("{} : {}", path, e))?;
+        Ok(path)
+    }
+}
+
+/// Fetcher recording every request and response it passes on in a WARC file. Placed
+/// below the fetcher adding request headers, and above redirects and retries, so each
+/// attempt is archived as sent and received.
+pub struct WarcFetcher<'a> {
+    pub fetcher: &'a dyn Fetcher,
+    pub writer: Mutex<WarcWriter>,
+}
+
+impl WarcFetcher<'_> {
+    pub fn new(fetcher: &dyn Fetcher, writer: WarcWriter) -> WarcFetcher<'_> {
+        WarcFetcher {
+            fetcher,
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+/// Writes to `sink` and keeps a copy in `copy`.
+struct Tee<'a> {
+    sink: &'a mut dyn Write,
+    copy: &'a mut Spool,
+}
+
+impl Write for Tee<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.sink.write(buf)?;
+        self.copy.write_all(&buf[..written])?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+/// Local files have no HTTP exchange to preserve.
+fn is_http(request: &FetchRequest) -> bool {
+    request.url.starts_with("http://") || request.url.starts_with("https://")
+}
+
+impl Fetcher for WarcFetcher<'_> {
+    fn fetch(&self, request: &FetchRequest) -> Result<FetchResponse, String> {
+        let time = SystemTime::now();
+        let result = self.fetcher.fetch(request);
+        if is_http(request) {
+            self.writer
+                .lock()
+                .unwrap()
+                .write_exchange(request, result.as_ref().ok(), time)
+                .map_err(|e| format!("WARC {}", e))?;
+        }
+        result
+    }
+
+    /// Copies the body into a temporary file on its way to `sink`, and writes the
+    /// record from there once the response is complete.
+    fn fetch_to(
+        &self,
+        request: &FetchRequest,
+        sink: &mut dyn Write,
+    ) -> Result<FetchResponse, String> {
+        if !is_http(request) {
+            return self.fetcher.fetch_to(request, sink);
+        }
+        let time = SystemTime::now();
+        let mut copy = Spool::new()?;
+        let result = self.fetcher.fetch_to(
+            request,
+            &mut Tee {
+                sink,
+                copy: &mut copy,
+            },
+        );
+        let mut writer = self.writer.lock().unwrap();
+        let written = match &result {
+            // the body went to the sink, the response only has the one of an error
+            Ok(response) if response.is_success() => {
+                let (_, _, body) = copy.finish()?;
+                writer.write_streamed_exchange(request, Some(response), body, time)
+            }
+            Ok(response) => writer.write_exchange(request, Some(response), time),
+            Err(_) => writer.write_exchange(request, None, time),
+        };
+        written.map_err(|e| format!("WARC {}", e))?;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{digest, index_path, surt, WarcFetcher, WarcWriter};
+    use crate::fetch::{FetchRequest, Fetcher};
+    use crate::mirror::{mirror, MirrorOptions};
+    use crate::mock::{MockOrigin, Route};
+    use std::io::Read;
+
+    type Record = (Vec<(String, String)>, Vec<u8>);
+
+    /// Splits WARC records into their headers and block.
+    fn parse_records(data: &[u8]) -> Vec<Record> {
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let end = pos
+                + data[pos..]
+                    .windows(4)
+                    .position(|window| window == b"\r\n\r\n")
+                    .unwrap();
+            let head = String::from_utf8(data[pos..end].to_vec()).unwrap();
+            let mut lines = head.split("\r\n");
+            assert_eq!(lines.next(), Some("WARC/1.1"));
+            let headers: Vec<(String, String)> = lines
+                .map(|line| {
+                    let (name, value) = line.split_once(": ").unwrap();
+                    (name.to_string(), value.to_string())
+                })
+                .collect();
+            let length: usize = headers
+                .iter()
+                .find(|(name, _)| name == "Content-Length")
+                .unwrap()
+                .1
+                .parse()
+                .unwrap();
+            let block = data[end + 4..end + 4 + length].to_vec();
+            assert_eq!(&data[end + 4 + length..end + 8 + length], b"\r\n\r\n");
+            pos = end + 8 + length;
+            records.push((headers, block));
+        }
+        records
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> &'a str {
+        &headers.iter().find(|(key, _)| key == name).unwrap().1
+    }
+
+    #[test]
+    fn warc_digest_and_surt() {
+        // base32 of the SHA-256 of an empty input
+        assert_eq!(
+            digest(b""),
+            "sha256:4OYMIQUY7QOBJGX36TEJS35ZEQT24QPEMSNZGTFESWMRW6CSXBKQ===="
+        );
+        assert_eq!(
+            surt("https://www.Example.com:8443/Video/1.m4s?b=2&a=1"),
+            "com,example:8443)/video/1.m4s?a=1&b=2"
+        );
+        assert_eq!(index_path("out/vod.warc.gz"), "out/vod.cdxj");
+    }
+
+    #[test]
+    fn warc_fetcher() {
+        let origin = MockOrigin::synthetic_vod("http://origin.test/vod/", 1);
+        origin.add("http://origin.test/vod/audio/1.m4s", Route::Unreachable);
+        let directory =
+            std::env::temp_dir().join(format!("dash-mirror-warc-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        for name in ["vod.warc", "vod.warc.gz"] {
+            let path = directory.join(name);
+            let writer = WarcWriter::create(path.to_str().unwrap()).unwrap();
+            let fetcher = WarcFetcher::new(&origin, writer);
+            let mut options = MirrorOptions::new(
+                "http://origin.test/vod/manifest.mpd",
+                directory.join("mirror").to_str().unwrap(),
+            );
+            options.retries = 0;
+            mirror(&fetcher, &options).unwrap();
+            let index_path = fetcher.writer.lock().unwrap().finish().unwrap();
+            std::fs::remove_dir_all(directory.join("mirror")).unwrap();
+
+            let compressed = std::fs::read(&path).unwrap();
+            let mut data = Vec::new();
+            match name.ends_with(".gz") {
+                true => {
+                    flate2::read::MultiGzDecoder::new(&compressed[..])
+                        .read_to_end(&mut data)
+                        .unwrap();
+                }
+                false => data = compressed.clone(),
+            }
+            let records = parse_records(&data);
+            let types: Vec<&str> = records
+                .iter()
+                .map(|(headers, _)| header(headers, "WARC-Type"))
+                .collect();
+            // the unreachable audio segment has a request but no response
+            assert_eq!(types[0], "warcinfo");
+            assert_eq!(types.iter().filter(|kind| **kind == "request").count(), 5);
+            assert_eq!(types.iter().filter(|kind| **kind == "response").count(), 4);
+            for (headers, block) in &records {
+                assert_eq!(header(headers, "WARC-Block-Digest"), digest(block));
+            }
+            let (headers, block) = records
+                .iter()
+                .find(|(headers, _)| {
+                    header(headers, "WARC-Type") == "response"
+                        && header(headers, "WARC-Target-URI")
+                            == "http://origin.test/vod/video/1.m4s"
+                })
+                .unwrap();
+            let body = origin.body("http://origin.test/vod/video/1.m4s").unwrap();
+            assert!(block.starts_with(b"HTTP/1.1 200 OK\r\n"));
+            assert!(block.ends_with(&body));
+            assert_eq!(header(headers, "WARC-Payload-Digest"), digest(&body));
+
+            let index = std::fs::read_to_string(&index_path).unwrap();
+            let lines: Vec<&str> = index.lines().collect();
+            assert_eq!(lines.len(), 4);
+            assert!(lines.windows(2).all(|pair| pair[0] <= pair[1]));
+            assert!(lines[0].starts_with("test,origin)/vod/audio/init.mp4 "));
+            for line in lines {
+                let fields: serde_json::Value =
+                    serde_json::from_str(line.splitn(3, ' ').nth(2).unwrap()).unwrap();
+                let offset: usize = fields["offset"].as_str().unwrap().parse().unwrap();
+                let length: usize = fields["length"].as_str().unwrap().parse().unwrap();
+                let mut record = compressed[offset..offset + length].to_vec();
+                if name.ends_with(".gz") {
+                    let mut decoded = Vec::new();
+                    flate2::read::GzDecoder::new(&record[..])
+                        .read_to_end(&mut decoded)
+                        .unwrap();
+                    record = decoded;
+                }
+                let (headers, _) = &parse_records(&record)[0];
+                assert_eq!(header(headers, "WARC-Target-URI"), fields["url"]);
+                assert_eq!(fields["status"], "200");
+            }
+        }
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn warc_fetch_to() {
+        let origin = MockOrigin::synthetic_vod("http://origin.test/vod/", 1);
+        let path = std::env::temp_dir().join(format!(
+            "dash-mirror-warc-fetch-to-{}.warc",
+            std::process::id()
+        ));
+        let writer = WarcWriter::create(path.to_str().unwrap()).unwrap();
+        let fetcher = WarcFetcher::new(&origin, writer);
+        let url = "http://origin.test/vod/video/1.m4s";
+        let mut sink = Vec::new();
+        let response = fetcher
+            .fetch_to(&FetchRequest::new(url), &mut sink)
+            .unwrap();
+        let missing = "http://origin.test/vod/video/9.m4s";
+        let not_found = fetcher
+            .fetch_to(&FetchRequest::new(missing), &mut Vec::new())
+            .unwrap();
+        fetcher.writer.lock().unwrap().finish().unwrap();
+
+        let body = origin.body(url).unwrap();
+        assert_eq!(sink, body);
+        assert!(response.body.is_empty());
+        let records = parse_records(&std::fs::read(&path).unwrap());
+        let (headers, block) = &records[2];
+        assert_eq!(header(headers, "WARC-Type"), "response");
+        assert!(block.ends_with(&body));
+        assert_eq!(header(headers, "WARC-Block-Digest"), digest(block));
+        assert_eq!(header(headers, "WARC-Payload-Digest"), digest(&body));
+        let (headers, block) = &records[4];
+        assert_eq!(header(headers, "WARC-Target-URI"), missing);
+        assert!(block.starts_with(format!("HTTP/1.1 {}", not_found.status).as_bytes()));
+        assert!(block.ends_with(&not_found.body));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(index_path(path.to_str().unwrap())).unwrap();
+    }
+}